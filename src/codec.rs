@@ -1,5 +1,7 @@
 use std::convert::TryFrom;
+use std::io;
 use std::mem;
+use std::ptr;
 use std::slice;
 
 use enumflags2::BitFlags;
@@ -19,6 +21,7 @@ pub enum StreamError {
 /// If numbered reports are used for a type, all messages from the kernel already have the report-number as prefix. Otherwise, no prefix is added by the kernel.
 /// For messages sent by user-space to the kernel, you must adjust the prefixes according to these flags.
 #[derive(BitFlags, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u64)]
 pub enum DevFlags {
     FeatureReportsNumbered = 0b0000_0001,
@@ -28,6 +31,7 @@ pub enum DevFlags {
 
 /// See https://www.kernel.org/doc/html/latest/hid/uhid.html#read
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ReportType {
     Feature = 0,
     Output = 1,
@@ -62,6 +66,38 @@ pub enum Bus {
     INTEL_ISHTP = 31,
 }
 
+/// Converts a `BUS_*` value from the kernel, giving back unknown values as the error
+impl TryFrom<u32> for Bus {
+    type Error = u32;
+    fn try_from(bus: u32) -> Result<Self, Self::Error> {
+        Ok(match bus {
+            1 => Bus::PCI,
+            2 => Bus::ISAPNP,
+            3 => Bus::USB,
+            4 => Bus::HIL,
+            5 => Bus::BLUETOOTH,
+            6 => Bus::VIRTUAL,
+            16 => Bus::ISA,
+            17 => Bus::I8042,
+            18 => Bus::XTKBD,
+            19 => Bus::RS232,
+            20 => Bus::GAMEPORT,
+            21 => Bus::PARPORT,
+            22 => Bus::AMIGA,
+            23 => Bus::ADB,
+            24 => Bus::I2C,
+            25 => Bus::HOST,
+            26 => Bus::GSC,
+            27 => Bus::ATARI,
+            28 => Bus::SPI,
+            29 => Bus::RMI,
+            30 => Bus::CEC,
+            31 => Bus::INTEL_ISHTP,
+            _ => return Err(bus),
+        })
+    }
+}
+
 pub const UHID_EVENT_SIZE: usize = mem::size_of::<sys::uhid_event>();
 
 /// See https://www.kernel.org/doc/html/latest/hid/uhid.html#write
@@ -125,18 +161,20 @@ impl<'a> Into<sys::uhid_event> for InputEvent<'a> {
                     .for_each(|(i, x)| payload.data[i] = *x);
                 payload.size = data.len() as u16;
             }
-            InputEvent::GetReportReply { err, data, .. } => {
+            InputEvent::GetReportReply { id, err, data } => {
                 event.type_ = sys::uhid_event_type_UHID_GET_REPORT_REPLY as u32;
                 let payload = unsafe { &mut event.u.get_report_reply };
+                payload.id = id;
                 payload.err = err;
                 data.iter()
                     .enumerate()
                     .for_each(|(i, x)| payload.data[i] = *x);
                 payload.size = data.len() as u16;
             }
-            InputEvent::SetReportReply { err, .. } => {
+            InputEvent::SetReportReply { id, err } => {
                 event.type_ = sys::uhid_event_type_UHID_SET_REPORT_REPLY as u32;
                 let payload = unsafe { &mut event.u.set_report_reply };
+                payload.id = id;
                 payload.err = err;
             }
         };
@@ -145,7 +183,72 @@ impl<'a> Into<sys::uhid_event> for InputEvent<'a> {
     }
 }
 
+/// An `InputEvent` owning its input report, for keeping events around or deserializing them
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
+pub enum OwnedInputEvent {
+    Create(CreateParams),
+    Destroy,
+    Input {
+        #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hex"))]
+        data: Vec<u8>,
+    },
+    GetReportReply {
+        id: u32,
+        err: u16,
+        #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hex"))]
+        data: Vec<u8>,
+    },
+    SetReportReply {
+        id: u32,
+        err: u16,
+    },
+}
+
+impl OwnedInputEvent {
+    pub fn as_input_event(&self) -> InputEvent<'_> {
+        match self {
+            OwnedInputEvent::Create(params) => InputEvent::Create(params.clone()),
+            OwnedInputEvent::Destroy => InputEvent::Destroy,
+            OwnedInputEvent::Input { data } => InputEvent::Input { data },
+            OwnedInputEvent::GetReportReply { id, err, data } => InputEvent::GetReportReply {
+                id: *id,
+                err: *err,
+                data: data.clone(),
+            },
+            OwnedInputEvent::SetReportReply { id, err } => {
+                InputEvent::SetReportReply { id: *id, err: *err }
+            }
+        }
+    }
+}
+
+impl<'a> From<InputEvent<'a>> for OwnedInputEvent {
+    fn from(event: InputEvent<'a>) -> Self {
+        match event {
+            InputEvent::Create(params) => OwnedInputEvent::Create(params),
+            InputEvent::Destroy => OwnedInputEvent::Destroy,
+            InputEvent::Input { data } => OwnedInputEvent::Input {
+                data: data.to_vec(),
+            },
+            InputEvent::GetReportReply { id, err, data } => {
+                OwnedInputEvent::GetReportReply { id, err, data }
+            }
+            InputEvent::SetReportReply { id, err } => OwnedInputEvent::SetReportReply { id, err },
+        }
+    }
+}
+
 /// See https://www.kernel.org/doc/html/latest/hid/uhid.html#read
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
 pub enum OutputEvent {
     Start {
         dev_flags: Vec<DevFlags>,
@@ -154,6 +257,7 @@ pub enum OutputEvent {
     Open,
     Close,
     Output {
+        #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hex"))]
         data: Vec<u8>,
     },
     GetReport {
@@ -165,6 +269,7 @@ pub enum OutputEvent {
         id: u32,
         report_number: u8,
         report_type: ReportType,
+        #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hex"))]
         data: Vec<u8>,
     },
 }
@@ -244,6 +349,71 @@ impl TryFrom<[u8; UHID_EVENT_SIZE]> for OutputEvent {
     }
 }
 
+/// Text of a nul-terminated field of a create request
+fn nul_terminated(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// Decodes an event as written to /dev/uhid, the data of input reports borrowed from `src`
+impl<'a> TryFrom<&'a [u8; UHID_EVENT_SIZE]> for InputEvent<'a> {
+    type Error = StreamError;
+    fn try_from(src: &'a [u8; UHID_EVENT_SIZE]) -> Result<Self, Self::Error> {
+        let base = src.as_ptr() as *const sys::uhid_event;
+        let event = unsafe { ptr::read_unaligned(base) };
+        match to_uhid_event_type(event.type_) {
+            Some(sys::uhid_event_type_UHID_CREATE2) => {
+                let payload = unsafe { event.u.create2 };
+                let bus = Bus::try_from(payload.bus as u32).map_err(|bus| {
+                    StreamError::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown bus type {}", bus),
+                    ))
+                })?;
+                let rd_size = (payload.rd_size as usize).min(payload.rd_data.len());
+                Ok(InputEvent::Create(CreateParams {
+                    name: nul_terminated(&payload.name),
+                    phys: nul_terminated(&payload.phys),
+                    uniq: nul_terminated(&payload.uniq),
+                    bus,
+                    vendor: payload.vendor,
+                    product: payload.product,
+                    version: payload.version,
+                    country: payload.country,
+                    rd_data: payload.rd_data[..rd_size].to_vec(),
+                }))
+            }
+            Some(sys::uhid_event_type_UHID_DESTROY) => Ok(InputEvent::Destroy),
+            Some(sys::uhid_event_type_UHID_INPUT2) => {
+                let data = unsafe { ptr::addr_of!((*base).u.input2.data) } as usize;
+                let offset = data - src.as_ptr() as usize;
+                let size = unsafe { event.u.input2.size } as usize;
+                let end = (offset + size).min(UHID_EVENT_SIZE);
+                Ok(InputEvent::Input {
+                    data: &src[offset..end],
+                })
+            }
+            Some(sys::uhid_event_type_UHID_GET_REPORT_REPLY) => {
+                let payload = unsafe { event.u.get_report_reply };
+                let size = (payload.size as usize).min(payload.data.len());
+                Ok(InputEvent::GetReportReply {
+                    id: payload.id,
+                    err: payload.err,
+                    data: payload.data[..size].to_vec(),
+                })
+            }
+            Some(sys::uhid_event_type_UHID_SET_REPORT_REPLY) => {
+                let payload = unsafe { event.u.set_report_reply };
+                Ok(InputEvent::SetReportReply {
+                    id: payload.id,
+                    err: payload.err,
+                })
+            }
+            _ => Err(StreamError::UnknownEventType(event.type_)),
+        }
+    }
+}
+
 impl<'a> Into<[u8; UHID_EVENT_SIZE]> for InputEvent<'a> {
     fn into(self) -> [u8; UHID_EVENT_SIZE] {
         let event: sys::uhid_event = self.into();
@@ -446,4 +616,22 @@ mod tests {
         let result: [u8; UHID_EVENT_SIZE] = InputEvent::Destroy.into();
         assert_bytes_eq(&result[..], &expected);
     }
+
+    #[test]
+    fn encode_get_report_reply() {
+        let mut expected = vec![0; mem::size_of::<sys::uhid_event>()];
+        expected[0] = 0x0a;
+        expected[4] = 0x2a;
+        expected[10] = 0x02;
+        expected[12] = 0x03;
+        expected[13] = 0x01;
+
+        let result: [u8; UHID_EVENT_SIZE] = InputEvent::GetReportReply {
+            id: 42,
+            err: 0,
+            data: vec![0x03, 0x01],
+        }
+        .into();
+        assert_bytes_eq(&result[..], &expected);
+    }
 }
@@ -0,0 +1,207 @@
+use enumflags2::BitFlags;
+
+/// Data bits of an Input, Output or Feature main item. An empty set means Data,Array,Abs.
+/// See section 6.2.2.5 of the HID 1.11 specification
+#[derive(BitFlags, Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u16)]
+pub enum MainFlags {
+    Constant = 0b0_0000_0001,
+    Variable = 0b0_0000_0010,
+    Relative = 0b0_0000_0100,
+    Wrap = 0b0_0000_1000,
+    NonLinear = 0b0_0001_0000,
+    NoPreferred = 0b0_0010_0000,
+    NullState = 0b0_0100_0000,
+    Volatile = 0b0_1000_0000,
+    BufferedBytes = 0b1_0000_0000,
+}
+
+/// See section 6.2.2.6 of the HID 1.11 specification
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Collection {
+    Physical = 0,
+    Application = 1,
+    Logical = 2,
+    Report = 3,
+    NamedArray = 4,
+    UsageSwitch = 5,
+    UsageModifier = 6,
+}
+
+/// Builds a HID report descriptor out of short items, picking the smallest encoding for each value.
+/// The result is meant for `CreateParams::rd_data`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReportDescriptorBuilder {
+    data: Vec<u8>,
+}
+
+impl ReportDescriptorBuilder {
+    pub fn new() -> ReportDescriptorBuilder {
+        ReportDescriptorBuilder::default()
+    }
+
+    fn item_unsigned(&mut self, prefix: u8, value: u32) -> &mut Self {
+        if value <= 0xff {
+            self.data.extend_from_slice(&[prefix | 1, value as u8]);
+        } else if value <= 0xffff {
+            self.data.push(prefix | 2);
+            self.data.extend_from_slice(&(value as u16).to_le_bytes());
+        } else {
+            self.data.push(prefix | 3);
+            self.data.extend_from_slice(&value.to_le_bytes());
+        }
+        self
+    }
+
+    fn item_signed(&mut self, prefix: u8, value: i32) -> &mut Self {
+        if value >= i8::MIN as i32 && value <= i8::MAX as i32 {
+            self.data.extend_from_slice(&[prefix | 1, value as u8]);
+        } else if value >= i16::MIN as i32 && value <= i16::MAX as i32 {
+            self.data.push(prefix | 2);
+            self.data.extend_from_slice(&(value as i16).to_le_bytes());
+        } else {
+            self.data.push(prefix | 3);
+            self.data.extend_from_slice(&value.to_le_bytes());
+        }
+        self
+    }
+
+    fn main_item(&mut self, prefix: u8, flags: BitFlags<MainFlags>) -> &mut Self {
+        self.item_unsigned(prefix, flags.bits() as u32)
+    }
+
+    pub fn usage_page(&mut self, page: u16) -> &mut Self {
+        self.item_unsigned(0x04, page as u32)
+    }
+
+    /// Values above 0xffff are extended usages that carry their own usage page in the high 16 bits
+    pub fn usage(&mut self, usage: u32) -> &mut Self {
+        self.item_unsigned(0x08, usage)
+    }
+
+    pub fn usage_minimum(&mut self, usage: u32) -> &mut Self {
+        self.item_unsigned(0x18, usage)
+    }
+
+    pub fn usage_maximum(&mut self, usage: u32) -> &mut Self {
+        self.item_unsigned(0x28, usage)
+    }
+
+    pub fn logical_minimum(&mut self, value: i32) -> &mut Self {
+        self.item_signed(0x14, value)
+    }
+
+    pub fn logical_maximum(&mut self, value: i32) -> &mut Self {
+        self.item_signed(0x24, value)
+    }
+
+    pub fn physical_minimum(&mut self, value: i32) -> &mut Self {
+        self.item_signed(0x34, value)
+    }
+
+    pub fn physical_maximum(&mut self, value: i32) -> &mut Self {
+        self.item_signed(0x44, value)
+    }
+
+    /// The exponent is a 4-bit two's complement nibble, so only -8..=7 are meaningful
+    pub fn unit_exponent(&mut self, exponent: i8) -> &mut Self {
+        self.item_unsigned(0x54, (exponent as u32) & 0x0f)
+    }
+
+    pub fn unit(&mut self, unit: u32) -> &mut Self {
+        self.item_unsigned(0x64, unit)
+    }
+
+    /// Size of each field in bits
+    pub fn report_size(&mut self, bits: u32) -> &mut Self {
+        self.item_unsigned(0x74, bits)
+    }
+
+    pub fn report_id(&mut self, id: u8) -> &mut Self {
+        self.item_unsigned(0x84, id as u32)
+    }
+
+    pub fn report_count(&mut self, count: u32) -> &mut Self {
+        self.item_unsigned(0x94, count)
+    }
+
+    pub fn push(&mut self) -> &mut Self {
+        self.data.push(0xa4);
+        self
+    }
+
+    pub fn pop(&mut self) -> &mut Self {
+        self.data.push(0xb4);
+        self
+    }
+
+    pub fn input(&mut self, flags: BitFlags<MainFlags>) -> &mut Self {
+        self.main_item(0x80, flags)
+    }
+
+    pub fn output(&mut self, flags: BitFlags<MainFlags>) -> &mut Self {
+        self.main_item(0x90, flags)
+    }
+
+    pub fn feature(&mut self, flags: BitFlags<MainFlags>) -> &mut Self {
+        self.main_item(0xb0, flags)
+    }
+
+    pub fn collection(&mut self, kind: Collection) -> &mut Self {
+        self.item_unsigned(0xa0, kind as u32)
+    }
+
+    pub fn end_collection(&mut self) -> &mut Self {
+        self.data.push(0xc0);
+        self
+    }
+
+    /// Appends already encoded items, e.g. a descriptor fragment copied from a real device
+    pub fn raw(&mut self, items: &[u8]) -> &mut Self {
+        self.data.extend_from_slice(items);
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_smallest_item_sizes() {
+        let rd = ReportDescriptorBuilder::new()
+            .usage_page(0x01)
+            .usage(0x02)
+            .collection(Collection::Application)
+            .logical_minimum(-127)
+            .logical_maximum(255)
+            .physical_maximum(100_000)
+            .usage(0xff00_0001)
+            .input(MainFlags::Variable | MainFlags::Relative)
+            .output(BitFlags::empty())
+            .end_collection()
+            .build();
+
+        assert_eq!(
+            rd,
+            vec![
+                0x05, 0x01, /* USAGE_PAGE (Generic Desktop) */
+                0x09, 0x02, /* USAGE (Mouse) */
+                0xa1, 0x01, /* COLLECTION (Application) */
+                0x15, 0x81, /* LOGICAL_MINIMUM (-127) */
+                0x26, 0xff, 0x00, /* LOGICAL_MAXIMUM (255) */
+                0x47, 0xa0, 0x86, 0x01, 0x00, /* PHYSICAL_MAXIMUM (100000) */
+                0x0b, 0x01, 0x00, 0x00, 0xff, /* USAGE (Vendor 0xff00:0x0001) */
+                0x81, 0x06, /* INPUT (Data,Var,Rel) */
+                0x91, 0x00, /* OUTPUT (Data,Arr,Abs) */
+                0xc0, /* END_COLLECTION */
+            ]
+        );
+    }
+}
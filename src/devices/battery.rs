@@ -0,0 +1,176 @@
+use std::io::{self, prelude::*};
+
+use crate::codec::{OutputEvent, ReportType};
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::Preset;
+use crate::uhid_device::UHIDDevice;
+
+const BATTERY_STRENGTH: u32 = 0x06_0020;
+const CHARGING: u32 = 0x85_0044;
+
+/// Battery strength of a wireless peripheral, on the Generic Device Controls page. hid-input
+/// turns it into a power supply under `/sys/class/power_supply`, reads the level through
+/// GET_REPORT for the feature report and follows the input report afterwards. Both reports use
+/// `report_id`.
+///
+/// Being a preset of its own, it is added to the descriptor of any other preset sharing the
+/// device, or bundled with one through `WithBattery`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatteryStrength {
+    report_id: u8,
+    level: u8,
+    charging: bool,
+}
+
+impl BatteryStrength {
+    /// A battery at `level` percent, clamped to 100
+    pub fn new(report_id: u8, level: u8) -> BatteryStrength {
+        BatteryStrength {
+            report_id,
+            level: level.min(100),
+            charging: false,
+        }
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    pub fn is_charging(&self) -> bool {
+        self.charging
+    }
+
+    fn report(&self) -> [u8; 3] {
+        [self.report_id, self.level, self.charging as u8]
+    }
+
+    /// Sets the level in percent, clamped to 100
+    pub fn set_level<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        level: u8,
+    ) -> io::Result<usize> {
+        self.level = level.min(100);
+        device.write(&self.report())
+    }
+
+    pub fn set_charging<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        charging: bool,
+    ) -> io::Result<usize> {
+        self.charging = charging;
+        device.write(&self.report())
+    }
+
+    /// Appends the level and charging fields, for use inside another preset's collection
+    pub fn describe_fields(&self, rd: &mut ReportDescriptorBuilder) {
+        let fields = |rd: &mut ReportDescriptorBuilder, feature: bool| {
+            let flags = MainFlags::Variable.into();
+            rd.usage(BATTERY_STRENGTH)
+                .logical_minimum(0)
+                .logical_maximum(100)
+                .report_size(8)
+                .report_count(1);
+            if feature {
+                rd.feature(flags);
+            } else {
+                rd.input(flags);
+            }
+            rd.usage(CHARGING)
+                .logical_maximum(1)
+                .report_size(1)
+                .report_count(1);
+            if feature {
+                rd.feature(flags)
+                    .report_count(7)
+                    .feature(MainFlags::Constant.into());
+            } else {
+                rd.input(flags)
+                    .report_count(7)
+                    .input(MainFlags::Constant.into());
+            }
+        };
+        rd.report_id(self.report_id);
+        fields(rd, false);
+        fields(rd, true);
+    }
+}
+
+impl Preset for BatteryStrength {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.usage_page(0x06) /* Generic Device Controls */
+            .usage(BATTERY_STRENGTH)
+            .collection(Collection::Application);
+        self.describe_fields(rd);
+        rd.end_collection();
+    }
+
+    fn handle<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        event: &OutputEvent,
+    ) -> io::Result<bool> {
+        match event {
+            OutputEvent::GetReport {
+                id,
+                report_number,
+                report_type: ReportType::Feature | ReportType::Input,
+            } if *report_number == self.report_id => {
+                device.write_get_report_reply(*id, 0, self.report().to_vec())?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// A preset bundled with a battery, described one after the other. Events go to the battery
+/// first and to the preset if the battery did not take them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WithBattery<P: Preset> {
+    pub preset: P,
+    pub battery: BatteryStrength,
+}
+
+impl<P: Preset> WithBattery<P> {
+    pub fn new(preset: P, battery: BatteryStrength) -> WithBattery<P> {
+        WithBattery { preset, battery }
+    }
+}
+
+impl<P: Preset> Preset for WithBattery<P> {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        self.preset.describe(rd);
+        self.battery.describe(rd);
+    }
+
+    fn handle<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        event: &OutputEvent,
+    ) -> io::Result<bool> {
+        if self.battery.handle(device, event)? {
+            return Ok(true);
+        }
+        self.preset.handle(device, event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::Mouse;
+
+    #[test]
+    fn bundles_battery_after_preset() {
+        let mouse = Mouse::new(1);
+        let battery = BatteryStrength::new(2, 150);
+        assert_eq!(battery.report(), [2, 100, 0]);
+
+        let bundle = WithBattery::new(mouse.clone(), battery.clone());
+        let mut expected = mouse.descriptor();
+        expected.extend(battery.descriptor());
+        assert_eq!(bundle.descriptor(), expected);
+    }
+}
@@ -0,0 +1,272 @@
+use std::io::{self, prelude::*};
+
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::Preset;
+use crate::uhid_device::UHIDDevice;
+
+const CONSUMER_SLOTS: usize = 4;
+const CONSUMER_MAX_USAGE: u16 = 0x0fff;
+const SYSTEM_SLOTS: usize = 2;
+
+/// A usage of the Consumer page (0x0C). Any value of the page can be sent, the constants only
+/// name common media keys.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ConsumerUsage(pub u16);
+
+impl ConsumerUsage {
+    pub const BRIGHTNESS_UP: ConsumerUsage = ConsumerUsage(0x006f);
+    pub const BRIGHTNESS_DOWN: ConsumerUsage = ConsumerUsage(0x0070);
+    pub const PLAY: ConsumerUsage = ConsumerUsage(0x00b0);
+    pub const PAUSE: ConsumerUsage = ConsumerUsage(0x00b1);
+    pub const RECORD: ConsumerUsage = ConsumerUsage(0x00b2);
+    pub const FAST_FORWARD: ConsumerUsage = ConsumerUsage(0x00b3);
+    pub const REWIND: ConsumerUsage = ConsumerUsage(0x00b4);
+    pub const NEXT_TRACK: ConsumerUsage = ConsumerUsage(0x00b5);
+    pub const PREVIOUS_TRACK: ConsumerUsage = ConsumerUsage(0x00b6);
+    pub const STOP: ConsumerUsage = ConsumerUsage(0x00b7);
+    pub const EJECT: ConsumerUsage = ConsumerUsage(0x00b8);
+    pub const PLAY_PAUSE: ConsumerUsage = ConsumerUsage(0x00cd);
+    pub const MUTE: ConsumerUsage = ConsumerUsage(0x00e2);
+    pub const VOLUME_UP: ConsumerUsage = ConsumerUsage(0x00e9);
+    pub const VOLUME_DOWN: ConsumerUsage = ConsumerUsage(0x00ea);
+    pub const MEDIA_SELECT: ConsumerUsage = ConsumerUsage(0x0183);
+    pub const MAIL: ConsumerUsage = ConsumerUsage(0x018a);
+    pub const CALCULATOR: ConsumerUsage = ConsumerUsage(0x0192);
+    pub const FILE_BROWSER: ConsumerUsage = ConsumerUsage(0x0194);
+    pub const SEARCH: ConsumerUsage = ConsumerUsage(0x0221);
+    pub const HOME: ConsumerUsage = ConsumerUsage(0x0223);
+    pub const BACK: ConsumerUsage = ConsumerUsage(0x0224);
+    pub const FORWARD: ConsumerUsage = ConsumerUsage(0x0225);
+    pub const REFRESH: ConsumerUsage = ConsumerUsage(0x0227);
+    pub const BOOKMARKS: ConsumerUsage = ConsumerUsage(0x022a);
+}
+
+/// A System Control usage of the Generic Desktop page (0x01)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SystemUsage(pub u8);
+
+impl SystemUsage {
+    pub const POWER_DOWN: SystemUsage = SystemUsage(0x81);
+    pub const SLEEP: SystemUsage = SystemUsage(0x82);
+    pub const WAKE_UP: SystemUsage = SystemUsage(0x83);
+    pub const CONTEXT_MENU: SystemUsage = SystemUsage(0x84);
+    pub const MAIN_MENU: SystemUsage = SystemUsage(0x85);
+    pub const APP_MENU: SystemUsage = SystemUsage(0x86);
+}
+
+/// Held usages of an array input report, where every slot carries the usage value itself and
+/// zero marks an empty slot
+#[derive(Debug, Clone, PartialEq)]
+struct UsageArray {
+    report_id: u8,
+    slots: usize,
+    pressed: Vec<u16>,
+}
+
+impl UsageArray {
+    fn new(report_id: u8, slots: usize) -> UsageArray {
+        UsageArray {
+            report_id,
+            slots,
+            pressed: Vec::new(),
+        }
+    }
+
+    /// Returns false when all slots are taken
+    fn press(&mut self, usage: u16) -> bool {
+        if self.pressed.contains(&usage) {
+            return true;
+        }
+        if self.pressed.len() == self.slots {
+            return false;
+        }
+        self.pressed.push(usage);
+        true
+    }
+
+    fn release(&mut self, usage: u16) {
+        self.pressed.retain(|held| *held != usage);
+    }
+
+    fn report(&self, wide: bool) -> Vec<u8> {
+        let mut report = vec![self.report_id];
+        for slot in 0..self.slots {
+            let usage = self.pressed.get(slot).copied().unwrap_or(0);
+            if wide {
+                report.extend_from_slice(&usage.to_le_bytes());
+            } else {
+                report.push(usage as u8);
+            }
+        }
+        report
+    }
+
+    fn describe(&self, rd: &mut ReportDescriptorBuilder, max_usage: u16, bits: u32) {
+        rd.report_id(self.report_id)
+            .usage_minimum(0)
+            .usage_maximum(max_usage as u32)
+            .logical_minimum(0)
+            .logical_maximum(max_usage as i32)
+            .report_size(bits)
+            .report_count(self.slots as u32)
+            .input(MainFlags::NoPreferred.into());
+    }
+}
+
+fn slots_full() -> io::Error {
+    io::Error::other("all usage slots of the report are held")
+}
+
+/// Media and application keys from the whole Consumer page, up to four at once
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumerControl {
+    array: UsageArray,
+}
+
+impl ConsumerControl {
+    pub fn new(report_id: u8) -> ConsumerControl {
+        ConsumerControl {
+            array: UsageArray::new(report_id, CONSUMER_SLOTS),
+        }
+    }
+
+    fn send<T: Read + Write>(&self, device: &mut UHIDDevice<T>) -> io::Result<usize> {
+        device.write(&self.array.report(true))
+    }
+
+    /// Holds a usage. Fails without sending if four usages are already held.
+    pub fn press<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        usage: ConsumerUsage,
+    ) -> io::Result<usize> {
+        if !self.array.press(usage.0) {
+            return Err(slots_full());
+        }
+        self.send(device)
+    }
+
+    pub fn release<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        usage: ConsumerUsage,
+    ) -> io::Result<usize> {
+        self.array.release(usage.0);
+        self.send(device)
+    }
+
+    /// Presses and releases a usage in two consecutive reports
+    pub fn tap<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        usage: ConsumerUsage,
+    ) -> io::Result<usize> {
+        Ok(self.press(device, usage)? + self.release(device, usage)?)
+    }
+}
+
+impl Preset for ConsumerControl {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.usage_page(0x0c) /* Consumer */
+            .usage(0x01) /* Consumer Control */
+            .collection(Collection::Application);
+        self.array.describe(rd, CONSUMER_MAX_USAGE, 16);
+        rd.end_collection();
+    }
+}
+
+/// Power, sleep and wake keys of the Generic Desktop System Control collection
+#[derive(Debug, Clone, PartialEq)]
+pub struct SystemControl {
+    array: UsageArray,
+}
+
+impl SystemControl {
+    pub fn new(report_id: u8) -> SystemControl {
+        SystemControl {
+            array: UsageArray::new(report_id, SYSTEM_SLOTS),
+        }
+    }
+
+    fn send<T: Read + Write>(&self, device: &mut UHIDDevice<T>) -> io::Result<usize> {
+        device.write(&self.array.report(false))
+    }
+
+    /// Holds a usage. Fails without sending if two usages are already held.
+    pub fn press<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        usage: SystemUsage,
+    ) -> io::Result<usize> {
+        if !self.array.press(usage.0 as u16) {
+            return Err(slots_full());
+        }
+        self.send(device)
+    }
+
+    pub fn release<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        usage: SystemUsage,
+    ) -> io::Result<usize> {
+        self.array.release(usage.0 as u16);
+        self.send(device)
+    }
+
+    /// Presses and releases a usage in two consecutive reports
+    pub fn tap<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        usage: SystemUsage,
+    ) -> io::Result<usize> {
+        Ok(self.press(device, usage)? + self.release(device, usage)?)
+    }
+}
+
+impl Preset for SystemControl {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.usage_page(0x01) /* Generic Desktop */
+            .usage(0x80) /* System Control */
+            .collection(Collection::Application);
+        self.array.describe(rd, u8::MAX as u16, 8);
+        rd.end_collection();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReportDescriptor;
+
+    #[test]
+    fn presets_share_a_descriptor() {
+        let consumer = ConsumerControl::new(3);
+        let system = SystemControl::new(4);
+        let mut rd = ReportDescriptorBuilder::new();
+        consumer.describe(&mut rd);
+        system.describe(&mut rd);
+        let fields = ReportDescriptor::parse(&rd.build())
+            .unwrap()
+            .fields()
+            .unwrap();
+        let usage_range = |report_id| {
+            fields
+                .iter()
+                .find(|field| field.report_id == report_id)
+                .and_then(|field| field.usage_range)
+        };
+        assert_eq!(fields.len(), 2);
+        assert_eq!(
+            usage_range(3),
+            Some((0x0c_0000, 0x0c_0000 | CONSUMER_MAX_USAGE as u32))
+        );
+        assert_eq!(usage_range(4), Some((0x01_0000, 0x01_00ff)));
+
+        let mut array = UsageArray::new(3, 2);
+        assert!(array.press(ConsumerUsage::VOLUME_UP.0));
+        assert!(array.press(ConsumerUsage::MUTE.0));
+        assert!(!array.press(ConsumerUsage::PLAY_PAUSE.0));
+        array.release(ConsumerUsage::VOLUME_UP.0);
+        assert_eq!(array.report(true), vec![3, 0xe2, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,553 @@
+use std::collections::VecDeque;
+use std::io::{self, prelude::*};
+use std::time::Instant;
+
+use enumflags2::BitFlags;
+
+use crate::codec::{Bus, OutputEvent, ReportType};
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::{Hat, Preset};
+use crate::uhid_device::{CreateParams, UHIDDevice};
+
+const VENDOR_SONY: u32 = 0x054c;
+const PRODUCT_DUALSENSE: u32 = 0x0ce6;
+
+const INPUT_REPORT_USB: u8 = 0x01;
+const INPUT_REPORT_BT: u8 = 0x31;
+const OUTPUT_REPORT_USB: u8 = 0x02;
+const OUTPUT_REPORT_BT: u8 = 0x31;
+const FEATURE_CALIBRATION: u8 = 0x05;
+const FEATURE_PAIRING_INFO: u8 = 0x09;
+const FEATURE_FIRMWARE_INFO: u8 = 0x20;
+
+/// Report sizes including the report ID, as checked by hid-playstation
+const INPUT_SIZE_USB: usize = 64;
+const INPUT_SIZE_BT: usize = 78;
+const OUTPUT_SIZE_USB: usize = 63;
+const OUTPUT_SIZE_BT: usize = 78;
+const CALIBRATION_SIZE: usize = 41;
+const PAIRING_INFO_SIZE: usize = 20;
+const FIRMWARE_INFO_SIZE: usize = 64;
+/// Input and output data shared by both buses
+const INPUT_COMMON_SIZE: usize = 63;
+const OUTPUT_COMMON_SIZE: usize = 47;
+
+/// Bytes prepended to a Bluetooth report when computing its CRC32
+const CRC_SEED_INPUT: u8 = 0xa1;
+const CRC_SEED_OUTPUT: u8 = 0xa2;
+const CRC_SEED_FEATURE: u8 = 0xa3;
+
+/// Resolution of the raw motion values with the calibration this preset reports
+pub const GYRO_RES_PER_DEG_S: i32 = 1024;
+pub const ACC_RES_PER_G: i32 = 8192;
+const TOUCHPAD_WIDTH: u16 = 1920;
+const TOUCHPAD_HEIGHT: u16 = 1080;
+
+const VALID_FLAG0_COMPATIBLE_VIBRATION: u8 = 0x01;
+const VALID_FLAG0_RIGHT_TRIGGER: u8 = 0x04;
+const VALID_FLAG0_LEFT_TRIGGER: u8 = 0x08;
+const VALID_FLAG1_MIC_MUTE_LED: u8 = 0x01;
+const VALID_FLAG1_LIGHTBAR: u8 = 0x04;
+const VALID_FLAG1_PLAYER_INDICATOR: u8 = 0x10;
+const VALID_FLAG2_COMPATIBLE_VIBRATION2: u8 = 0x04;
+
+/// Buttons of the DualSense, at their bit position in the input report. The D-pad is a `Hat`.
+#[derive(BitFlags, Copy, Clone, Debug, PartialEq)]
+#[repr(u32)]
+pub enum DualSenseButton {
+    Square = 1 << 4,
+    Cross = 1 << 5,
+    Circle = 1 << 6,
+    Triangle = 1 << 7,
+    L1 = 1 << 8,
+    R1 = 1 << 9,
+    L2 = 1 << 10,
+    R2 = 1 << 11,
+    Create = 1 << 12,
+    Options = 1 << 13,
+    L3 = 1 << 14,
+    R3 = 1 << 15,
+    Ps = 1 << 16,
+    Touchpad = 1 << 17,
+    Mute = 1 << 18,
+}
+
+/// Controls and sensors sent by `DualSense::send_state`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DualSenseState {
+    /// X and Y, centered at 0x80
+    pub left_stick: (u8, u8),
+    pub right_stick: (u8, u8),
+    pub l2: u8,
+    pub r2: u8,
+    pub buttons: BitFlags<DualSenseButton>,
+    pub dpad: Hat,
+    /// Pitch, yaw and roll in 1/`GYRO_RES_PER_DEG_S` degrees per second
+    pub gyro: [i16; 3],
+    /// X, Y and Z in 1/`ACC_RES_PER_G` G
+    pub accel: [i16; 3],
+    /// Up to two fingers on the 1920x1080 touchpad
+    pub touch: [Option<(u16, u16)>; 2],
+    /// Battery level in percent
+    pub battery: u8,
+    pub charging: bool,
+}
+
+impl Default for DualSenseState {
+    fn default() -> DualSenseState {
+        DualSenseState {
+            left_stick: (0x80, 0x80),
+            right_stick: (0x80, 0x80),
+            l2: 0,
+            r2: 0,
+            buttons: BitFlags::empty(),
+            dpad: Hat::Centered,
+            gyro: [0; 3],
+            accel: [0, ACC_RES_PER_G as i16, 0],
+            touch: [None; 2],
+            battery: 100,
+            charging: false,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Trigger {
+    Left,
+    Right,
+}
+
+/// An adaptive trigger effect. Common modes are 0x05 for off, 0x01 for continuous resistance,
+/// 0x02 for section resistance and 0x06 for vibration; their parameters differ per mode.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TriggerEffect {
+    pub mode: u8,
+    pub parameters: [u8; 10],
+}
+
+/// A setting decoded from an output report. One report can carry several of them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DualSenseEvent {
+    /// Speed of the low-frequency left and high-frequency right motor
+    Rumble {
+        left: u8,
+        right: u8,
+    },
+    Lightbar {
+        red: u8,
+        green: u8,
+        blue: u8,
+    },
+    /// Bit mask of the five player LEDs under the touchpad
+    PlayerLeds(u8),
+    MicLed(bool),
+    TriggerEffect(Trigger, TriggerEffect),
+}
+
+fn crc32(seed: u8, data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in std::iter::once(&seed).chain(data) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Writes the CRC32 of all but the last four bytes into the last four bytes
+fn seal(seed: u8, report: &mut [u8]) {
+    let end = report.len() - 4;
+    let crc = crc32(seed, &report[..end]);
+    report[end..].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn put_i16(report: &mut [u8], offset: usize, value: i16) {
+    report[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+/// A Sony DualSense controller as bound by the kernel's hid-playstation driver, over USB or
+/// Bluetooth. Its report IDs are fixed, so it is meant to be the only preset of its device,
+/// created from `create_params`. Bluetooth reports carry a CRC32, which is added to input and
+/// feature reports and checked on output reports.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DualSense {
+    bus: Bus,
+    mac: [u8; 6],
+    state: DualSenseState,
+    sequence: u8,
+    epoch: Instant,
+    events: VecDeque<DualSenseEvent>,
+}
+
+impl DualSense {
+    fn new(bus: Bus, mac: [u8; 6]) -> DualSense {
+        DualSense {
+            bus,
+            mac,
+            state: DualSenseState::default(),
+            sequence: 0,
+            epoch: Instant::now(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// A controller connected by cable, with the MAC address reported as pairing info
+    pub fn usb(mac: [u8; 6]) -> DualSense {
+        DualSense::new(Bus::USB, mac)
+    }
+
+    /// A controller connected over Bluetooth with the given MAC address
+    pub fn bluetooth(mac: [u8; 6]) -> DualSense {
+        DualSense::new(Bus::BLUETOOTH, mac)
+    }
+
+    fn is_bluetooth(&self) -> bool {
+        self.bus == Bus::BLUETOOTH
+    }
+
+    /// Parameters matching a real controller on the chosen bus
+    pub fn create_params(&self, name: &str) -> CreateParams {
+        let mac = self
+            .mac
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(":");
+        CreateParams {
+            name: name.to_string(),
+            phys: "".to_string(),
+            uniq: mac,
+            bus: self.bus,
+            vendor: VENDOR_SONY,
+            product: PRODUCT_DUALSENSE,
+            version: 0x0100,
+            country: 0,
+            rd_data: self.descriptor(),
+        }
+    }
+
+    pub fn state(&self) -> &DualSenseState {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut DualSenseState {
+        &mut self.state
+    }
+
+    /// Takes the oldest setting received from the host
+    pub fn next_event(&mut self) -> Option<DualSenseEvent> {
+        self.events.pop_front()
+    }
+
+    fn input_common(&self, sequence: u8, timestamp: u32) -> [u8; INPUT_COMMON_SIZE] {
+        let state = &self.state;
+        let mut common = [0; INPUT_COMMON_SIZE];
+        common[..6].copy_from_slice(&[
+            state.left_stick.0,
+            state.left_stick.1,
+            state.right_stick.0,
+            state.right_stick.1,
+            state.l2,
+            state.r2,
+        ]);
+        common[6] = sequence;
+        let buttons = state.buttons.bits() | state.dpad as u32;
+        common[7..11].copy_from_slice(&buttons.to_le_bytes());
+        for axis in 0..3 {
+            put_i16(&mut common, 15 + axis * 2, state.gyro[axis]);
+            put_i16(&mut common, 21 + axis * 2, state.accel[axis]);
+        }
+        common[27..31].copy_from_slice(&timestamp.to_le_bytes());
+        for (slot, touch) in state.touch.iter().enumerate() {
+            let point = &mut common[32 + slot * 4..36 + slot * 4];
+            match touch {
+                Some((x, y)) => {
+                    let x = (*x).min(TOUCHPAD_WIDTH - 1);
+                    let y = (*y).min(TOUCHPAD_HEIGHT - 1);
+                    point[0] = slot as u8;
+                    point[1] = x as u8;
+                    point[2] = (x >> 8) as u8 | (y as u8 & 0x0f) << 4;
+                    point[3] = (y >> 4) as u8;
+                }
+                /* Bit 7 marks an inactive contact */
+                None => point[0] = 0x80 | slot as u8,
+            }
+        }
+        /* Battery level in tenths, charging status in the high nibble */
+        let level = (state.battery.min(100) / 10).min(9);
+        let status = match (state.charging, state.battery >= 100) {
+            (true, true) => 2,
+            (true, false) => 1,
+            (false, _) => 0,
+        };
+        common[52] = level | status << 4;
+        common
+    }
+
+    fn input_report(&self, sequence: u8, timestamp: u32) -> Vec<u8> {
+        let common = self.input_common(sequence, timestamp);
+        if self.is_bluetooth() {
+            let mut report = vec![0; INPUT_SIZE_BT];
+            report[0] = INPUT_REPORT_BT;
+            report[1] = sequence << 4;
+            report[2..2 + INPUT_COMMON_SIZE].copy_from_slice(&common);
+            seal(CRC_SEED_INPUT, &mut report);
+            report
+        } else {
+            let mut report = vec![INPUT_REPORT_USB];
+            report.extend_from_slice(&common);
+            report
+        }
+    }
+
+    /// Sends the current state. Real controllers do so every few milliseconds.
+    pub fn send_state<T: Read + Write>(&mut self, device: &mut UHIDDevice<T>) -> io::Result<usize> {
+        /* The sensor timestamp counts in units of 0.33 µs */
+        let timestamp = (self.epoch.elapsed().as_micros() * 3) as u32;
+        let report = self.input_report(self.sequence, timestamp);
+        self.sequence = self.sequence.wrapping_add(1);
+        device.write(&report)
+    }
+
+    fn feature_report(&self, report_number: u8) -> Option<Vec<u8>> {
+        let mut report = match report_number {
+            FEATURE_CALIBRATION => {
+                let mut report = vec![0; CALIBRATION_SIZE];
+                let gyro = GYRO_RES_PER_DEG_S as i16;
+                let acc = ACC_RES_PER_G as i16;
+                /* Biases stay zero; plus and minus span twice the resolution over a speed of 2 */
+                for (i, value) in [gyro, -gyro, gyro, -gyro, gyro, -gyro, 1, 1]
+                    .iter()
+                    .enumerate()
+                {
+                    put_i16(&mut report, 7 + i * 2, *value);
+                }
+                for (i, value) in [acc, -acc, acc, -acc, acc, -acc].iter().enumerate() {
+                    put_i16(&mut report, 23 + i * 2, *value);
+                }
+                report
+            }
+            FEATURE_PAIRING_INFO => {
+                let mut report = vec![0; PAIRING_INFO_SIZE];
+                /* Least significant byte first */
+                for (i, byte) in self.mac.iter().rev().enumerate() {
+                    report[1 + i] = *byte;
+                }
+                report
+            }
+            FEATURE_FIRMWARE_INFO => {
+                let mut report = vec![0; FIRMWARE_INFO_SIZE];
+                report[1..12].copy_from_slice(b"Jan 01 2024");
+                report[12..20].copy_from_slice(b"00:00:00");
+                report[24..28].copy_from_slice(&0x0000_0617u32.to_le_bytes());
+                report[28..32].copy_from_slice(&0x0110_002au32.to_le_bytes());
+                report[44..46].copy_from_slice(&0x0630u16.to_le_bytes());
+                report
+            }
+            _ => return None,
+        };
+        report[0] = report_number;
+        if self.is_bluetooth() {
+            seal(CRC_SEED_FEATURE, &mut report);
+        }
+        Some(report)
+    }
+
+    /// Decodes the settings an output report marks as valid. Reports with another ID, size or a
+    /// wrong CRC are ignored.
+    fn parse_output(&self, data: &[u8]) -> Option<Vec<DualSenseEvent>> {
+        let common = if self.is_bluetooth() {
+            if data.len() != OUTPUT_SIZE_BT || data[0] != OUTPUT_REPORT_BT {
+                return None;
+            }
+            let end = data.len() - 4;
+            let crc = u32::from_le_bytes([data[end], data[end + 1], data[end + 2], data[end + 3]]);
+            if crc != crc32(CRC_SEED_OUTPUT, &data[..end]) {
+                return None;
+            }
+            &data[3..3 + OUTPUT_COMMON_SIZE]
+        } else {
+            if data.len() != OUTPUT_SIZE_USB || data[0] != OUTPUT_REPORT_USB {
+                return None;
+            }
+            &data[1..1 + OUTPUT_COMMON_SIZE]
+        };
+
+        let mut events = Vec::new();
+        let (flag0, flag1, flag2) = (common[0], common[1], common[38]);
+        if flag0 & VALID_FLAG0_COMPATIBLE_VIBRATION != 0
+            || flag2 & VALID_FLAG2_COMPATIBLE_VIBRATION2 != 0
+        {
+            events.push(DualSenseEvent::Rumble {
+                left: common[3],
+                right: common[2],
+            });
+        }
+        for (flag, trigger, offset) in [
+            (VALID_FLAG0_RIGHT_TRIGGER, Trigger::Right, 10),
+            (VALID_FLAG0_LEFT_TRIGGER, Trigger::Left, 21),
+        ] {
+            if flag0 & flag != 0 {
+                let mut parameters = [0; 10];
+                parameters.copy_from_slice(&common[offset + 1..offset + 11]);
+                events.push(DualSenseEvent::TriggerEffect(
+                    trigger,
+                    TriggerEffect {
+                        mode: common[offset],
+                        parameters,
+                    },
+                ));
+            }
+        }
+        if flag1 & VALID_FLAG1_MIC_MUTE_LED != 0 {
+            events.push(DualSenseEvent::MicLed(common[8] != 0));
+        }
+        if flag1 & VALID_FLAG1_PLAYER_INDICATOR != 0 {
+            events.push(DualSenseEvent::PlayerLeds(common[43]));
+        }
+        if flag1 & VALID_FLAG1_LIGHTBAR != 0 {
+            events.push(DualSenseEvent::Lightbar {
+                red: common[44],
+                green: common[45],
+                blue: common[46],
+            });
+        }
+        Some(events)
+    }
+}
+
+impl Preset for DualSense {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        let vendor = |rd: &mut ReportDescriptorBuilder, report_id: u8, usage: u32, size: usize| {
+            rd.report_id(report_id)
+                .usage(usage)
+                .report_count(size as u32 - 1);
+        };
+
+        rd.usage_page(0x01) /* Generic Desktop */
+            .usage(0x05) /* Gamepad */
+            .collection(Collection::Application)
+            .logical_minimum(0)
+            .logical_maximum(u8::MAX as i32)
+            .report_size(8);
+        if self.is_bluetooth() {
+            rd.usage_page(0xff00); /* Vendor-defined */
+            vendor(rd, INPUT_REPORT_BT, 0x31, INPUT_SIZE_BT);
+            rd.input(MainFlags::Variable.into());
+            vendor(rd, OUTPUT_REPORT_BT, 0x32, OUTPUT_SIZE_BT);
+            rd.output(MainFlags::Variable.into());
+        } else {
+            rd.report_id(INPUT_REPORT_USB)
+                .usage(0x30) /* X */
+                .usage(0x31) /* Y */
+                .usage(0x33) /* Rx */
+                .usage(0x34) /* Ry */
+                .usage(0x32) /* Z */
+                .usage(0x35) /* Rz */
+                .report_count(6)
+                .input(MainFlags::Variable.into())
+                .usage_page(0xff00) /* Vendor-defined */
+                .usage(0x20)
+                .report_count((INPUT_SIZE_USB - 7) as u32)
+                .input(MainFlags::Variable.into());
+            vendor(rd, OUTPUT_REPORT_USB, 0x21, OUTPUT_SIZE_USB);
+            rd.output(MainFlags::Variable.into());
+        }
+        vendor(rd, FEATURE_CALIBRATION, 0x22, CALIBRATION_SIZE);
+        rd.feature(MainFlags::Variable.into());
+        vendor(rd, FEATURE_PAIRING_INFO, 0x23, PAIRING_INFO_SIZE);
+        rd.feature(MainFlags::Variable.into());
+        vendor(rd, FEATURE_FIRMWARE_INFO, 0x24, FIRMWARE_INFO_SIZE);
+        rd.feature(MainFlags::Variable.into()).end_collection();
+    }
+
+    fn handle<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        event: &OutputEvent,
+    ) -> io::Result<bool> {
+        match event {
+            OutputEvent::Output { data }
+            | OutputEvent::SetReport {
+                report_type: ReportType::Output,
+                data,
+                ..
+            } => {
+                let events = self.parse_output(data);
+                if let OutputEvent::SetReport { id, .. } = event {
+                    let err = match events {
+                        Some(_) => 0,
+                        None => libc::EINVAL as u16,
+                    };
+                    device.write_set_report_reply(*id, err)?;
+                }
+                match events {
+                    Some(events) => {
+                        self.events.extend(events);
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            }
+            OutputEvent::GetReport {
+                id,
+                report_number,
+                report_type: ReportType::Feature,
+            } => match self.feature_report(*report_number) {
+                Some(report) => {
+                    device.write_get_report_reply(*id, 0, report)?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bluetooth_reports_carry_crc() {
+        assert_eq!(crc32(0xa1, b"123456789"), 0x88ed_2411);
+
+        let mut controller = DualSense::bluetooth([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+        controller.state_mut().dpad = Hat::Left;
+        controller.state_mut().buttons = DualSenseButton::Cross | DualSenseButton::Ps;
+        controller.state_mut().touch[1] = Some((0x123, 0x345));
+        let report = controller.input_report(3, 0);
+        assert_eq!(report.len(), INPUT_SIZE_BT);
+        assert_eq!(&report[9..13], &[0x26, 0, 0x01, 0]);
+        assert_eq!(&report[34..42], &[0x80, 0, 0, 0, 1, 0x23, 0x51, 0x34]);
+        let end = report.len() - 4;
+        assert_eq!(
+            report[end..],
+            crc32(CRC_SEED_INPUT, &report[..end]).to_le_bytes()
+        );
+
+        let pairing = controller.feature_report(FEATURE_PAIRING_INFO).unwrap();
+        assert_eq!(&pairing[..7], &[0x09, 0xff, 0xee, 0xdd, 0xcc, 0xbb, 0xaa]);
+
+        let mut output = vec![0; OUTPUT_SIZE_BT];
+        output[0] = OUTPUT_REPORT_BT;
+        output[3 + 1] = VALID_FLAG1_LIGHTBAR;
+        output[3 + 44..3 + 47].copy_from_slice(&[1, 2, 3]);
+        assert_eq!(controller.parse_output(&output), None);
+        seal(CRC_SEED_OUTPUT, &mut output);
+        assert_eq!(
+            controller.parse_output(&output),
+            Some(vec![DualSenseEvent::Lightbar {
+                red: 1,
+                green: 2,
+                blue: 3
+            }])
+        );
+    }
+}
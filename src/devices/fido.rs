@@ -0,0 +1,460 @@
+//! A FIDO security key speaking CTAPHID, the USB HID transport of the Client to Authenticator
+//! Protocol. This module handles framing, channels and the transport commands; CTAP2 and U2F
+//! requests are answered by an `Authenticator` implementation.
+
+use std::io::{self, prelude::*};
+use std::time::{Duration, Instant};
+
+use crate::codec::{Bus, OutputEvent};
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::Preset;
+use crate::uhid_device::{CreateParams, UHIDDevice};
+
+pub const PACKET_SIZE: usize = 64;
+const INIT_DATA_SIZE: usize = PACKET_SIZE - 7;
+const CONT_DATA_SIZE: usize = PACKET_SIZE - 5;
+/// Longest message that fits an initialization packet and 128 continuation packets
+pub const MAX_MESSAGE_SIZE: usize = INIT_DATA_SIZE + 128 * CONT_DATA_SIZE;
+const BROADCAST_CHANNEL: u32 = 0xffff_ffff;
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(3);
+const PROTOCOL_VERSION: u8 = 2;
+/// Allocated channels kept before the oldest one is dropped
+const MAX_CHANNELS: usize = 64;
+
+const CMD_PING: u8 = 0x81;
+const CMD_MSG: u8 = 0x83;
+const CMD_INIT: u8 = 0x86;
+const CMD_WINK: u8 = 0x88;
+const CMD_CBOR: u8 = 0x90;
+const CMD_CANCEL: u8 = 0x91;
+const CMD_KEEPALIVE: u8 = 0xbb;
+const CMD_ERROR: u8 = 0xbf;
+
+const CAPABILITY_WINK: u8 = 0x01;
+const CAPABILITY_CBOR: u8 = 0x04;
+const CAPABILITY_NMSG: u8 = 0x08;
+
+/// Error codes of the CTAPHID ERROR response
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum CtapHidError {
+    InvalidCommand = 0x01,
+    InvalidParameter = 0x02,
+    InvalidLength = 0x03,
+    InvalidSequence = 0x04,
+    MessageTimeout = 0x05,
+    ChannelBusy = 0x06,
+    InvalidChannel = 0x0b,
+    Other = 0x7f,
+}
+
+/// Status sent in KEEPALIVE messages while a request is processed
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum KeepaliveStatus {
+    Processing = 1,
+    UserPresenceNeeded = 2,
+}
+
+/// The authenticator behind the transport
+pub trait Authenticator {
+    /// Answers a CTAP2 request, made of a command byte and CBOR parameters, with a status byte
+    /// followed by the CBOR response. `keepalive` sends a KEEPALIVE message to the client and
+    /// should be called at least every 100 ms while waiting for the user.
+    fn cbor(
+        &mut self,
+        request: &[u8],
+        keepalive: &mut dyn FnMut(KeepaliveStatus) -> io::Result<()>,
+    ) -> Vec<u8>;
+
+    /// Whether `msg` implements CTAP1/U2F
+    fn supports_msg(&self) -> bool {
+        false
+    }
+
+    /// Answers a U2F request APDU with a response APDU
+    fn msg(&mut self, _apdu: &[u8]) -> Vec<u8> {
+        vec![0x6d, 0x00] /* SW_INS_NOT_SUPPORTED */
+    }
+
+    /// Asks for a visual or audible identification of the key
+    fn wink(&mut self) {}
+
+    /// The client cancelled its outstanding request. Requests are answered as they are read,
+    /// so this only matters to authenticators that wait for the user on another thread.
+    fn cancel(&mut self) {}
+}
+
+/// A message being reassembled from packets
+#[derive(Debug, Clone, PartialEq)]
+struct Pending {
+    channel: u32,
+    command: u8,
+    length: usize,
+    data: Vec<u8>,
+    sequence: u8,
+    started: Instant,
+}
+
+#[derive(Debug, PartialEq)]
+enum Received {
+    Incomplete,
+    Error(u32, CtapHidError),
+    Message(u32, u8, Vec<u8>),
+}
+
+/// Splits a message into padded initialization and continuation packets
+fn packets(channel: u32, command: u8, data: &[u8]) -> Vec<[u8; PACKET_SIZE]> {
+    let mut packet = [0; PACKET_SIZE];
+    packet[..4].copy_from_slice(&channel.to_be_bytes());
+    packet[4] = command;
+    packet[5..7].copy_from_slice(&(data.len() as u16).to_be_bytes());
+    let first = data.len().min(INIT_DATA_SIZE);
+    packet[7..7 + first].copy_from_slice(&data[..first]);
+
+    let mut packets = vec![packet];
+    for (sequence, chunk) in data[first..].chunks(CONT_DATA_SIZE).enumerate() {
+        let mut packet = [0; PACKET_SIZE];
+        packet[..4].copy_from_slice(&channel.to_be_bytes());
+        packet[4] = sequence as u8;
+        packet[5..5 + chunk.len()].copy_from_slice(chunk);
+        packets.push(packet);
+    }
+    packets
+}
+
+fn write_message<T: Read + Write>(
+    device: &mut UHIDDevice<T>,
+    channel: u32,
+    command: u8,
+    data: &[u8],
+) -> io::Result<usize> {
+    let mut written = 0;
+    for packet in packets(channel, command, data) {
+        written += device.write(&packet)?;
+    }
+    Ok(written)
+}
+
+/// A security key on the FIDO Alliance page (0xF1D0) with 64-byte input and output reports and
+/// no report IDs, so it is meant to be the only preset of its device
+pub struct SecurityKey<A: Authenticator> {
+    authenticator: A,
+    version: (u8, u8, u8),
+    channels: Vec<u32>,
+    next_channel: u32,
+    pending: Option<Pending>,
+}
+
+impl<A: Authenticator> SecurityKey<A> {
+    pub fn new(authenticator: A) -> SecurityKey<A> {
+        SecurityKey {
+            authenticator,
+            version: (1, 0, 0),
+            channels: Vec::new(),
+            next_channel: 1,
+            pending: None,
+        }
+    }
+
+    /// Sets the major, minor and build device version returned by INIT
+    pub fn with_version(mut self, major: u8, minor: u8, build: u8) -> SecurityKey<A> {
+        self.version = (major, minor, build);
+        self
+    }
+
+    pub fn authenticator(&self) -> &A {
+        &self.authenticator
+    }
+
+    pub fn authenticator_mut(&mut self) -> &mut A {
+        &mut self.authenticator
+    }
+
+    /// Parameters for a USB security key with this descriptor. The pid.codes test IDs are used
+    /// unless changed by the caller.
+    pub fn create_params(&self, name: &str) -> CreateParams {
+        CreateParams {
+            name: name.to_string(),
+            phys: "".to_string(),
+            uniq: "".to_string(),
+            bus: Bus::USB,
+            vendor: 0x1209,
+            product: 0x0001,
+            version: 0,
+            country: 0,
+            rd_data: self.descriptor(),
+        }
+    }
+
+    fn capabilities(&self) -> u8 {
+        let mut capabilities = CAPABILITY_WINK | CAPABILITY_CBOR;
+        if !self.authenticator.supports_msg() {
+            capabilities |= CAPABILITY_NMSG;
+        }
+        capabilities
+    }
+
+    fn allocate_channel(&mut self) -> u32 {
+        let channel = self.next_channel;
+        self.next_channel = match self.next_channel.wrapping_add(1) {
+            0 | BROADCAST_CHANNEL => 1,
+            next => next,
+        };
+        self.channels.retain(|allocated| *allocated != channel);
+        if self.channels.len() == MAX_CHANNELS {
+            self.channels.remove(0);
+        }
+        self.channels.push(channel);
+        channel
+    }
+
+    /// Adds a packet to the message being reassembled
+    fn receive(&mut self, packet: &[u8]) -> Received {
+        if packet.len() < 7 {
+            return Received::Incomplete;
+        }
+        let channel = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+
+        if packet[4] & 0x80 == 0 {
+            let pending = match self.pending.as_mut() {
+                Some(pending) if pending.channel == channel => pending,
+                /* Spurious continuation packets are ignored */
+                _ => return Received::Incomplete,
+            };
+            if pending.started.elapsed() >= TRANSACTION_TIMEOUT {
+                self.pending = None;
+                return Received::Error(channel, CtapHidError::MessageTimeout);
+            }
+            if packet[4] != pending.sequence {
+                self.pending = None;
+                return Received::Error(channel, CtapHidError::InvalidSequence);
+            }
+            let missing = pending.length - pending.data.len();
+            let data = &packet[5..];
+            pending
+                .data
+                .extend_from_slice(&data[..missing.min(data.len())]);
+            pending.sequence += 1;
+            if pending.data.len() < pending.length {
+                return Received::Incomplete;
+            }
+            let pending = self.pending.take().unwrap();
+            return Received::Message(pending.channel, pending.command, pending.data);
+        }
+
+        let command = packet[4];
+        if let Some(pending) = self.pending.as_ref() {
+            if pending.channel == channel && command != CMD_INIT {
+                self.pending = None;
+                return Received::Error(channel, CtapHidError::InvalidSequence);
+            } else if pending.channel != channel && pending.started.elapsed() < TRANSACTION_TIMEOUT
+            {
+                return Received::Error(channel, CtapHidError::ChannelBusy);
+            }
+            self.pending = None;
+        }
+        let valid_channel = if channel == BROADCAST_CHANNEL {
+            command == CMD_INIT
+        } else {
+            self.channels.contains(&channel)
+        };
+        if !valid_channel {
+            return Received::Error(channel, CtapHidError::InvalidChannel);
+        }
+        let length = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+        if length > MAX_MESSAGE_SIZE {
+            return Received::Error(channel, CtapHidError::InvalidLength);
+        }
+
+        let data = &packet[7..];
+        let data = data[..length.min(data.len())].to_vec();
+        if data.len() == length {
+            return Received::Message(channel, command, data);
+        }
+        self.pending = Some(Pending {
+            channel,
+            command,
+            length,
+            data,
+            sequence: 0,
+            started: Instant::now(),
+        });
+        Received::Incomplete
+    }
+
+    fn dispatch<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        channel: u32,
+        command: u8,
+        data: Vec<u8>,
+    ) -> io::Result<usize> {
+        let error = |device: &mut UHIDDevice<T>, error: CtapHidError| {
+            write_message(device, channel, CMD_ERROR, &[error as u8])
+        };
+        match command {
+            CMD_INIT => {
+                if data.len() != 8 {
+                    return error(device, CtapHidError::InvalidLength);
+                }
+                let assigned = if channel == BROADCAST_CHANNEL {
+                    self.allocate_channel()
+                } else {
+                    channel
+                };
+                let mut response = data;
+                response.extend_from_slice(&assigned.to_be_bytes());
+                response.extend_from_slice(&[
+                    PROTOCOL_VERSION,
+                    self.version.0,
+                    self.version.1,
+                    self.version.2,
+                    self.capabilities(),
+                ]);
+                write_message(device, channel, CMD_INIT, &response)
+            }
+            CMD_PING => write_message(device, channel, CMD_PING, &data),
+            CMD_WINK => {
+                self.authenticator.wink();
+                write_message(device, channel, CMD_WINK, &[])
+            }
+            CMD_MSG if self.authenticator.supports_msg() => {
+                let response = self.authenticator.msg(&data);
+                write_message(device, channel, CMD_MSG, &response)
+            }
+            CMD_CBOR => {
+                if data.is_empty() {
+                    return error(device, CtapHidError::InvalidLength);
+                }
+                let mut keepalive = |status: KeepaliveStatus| {
+                    write_message(device, channel, CMD_KEEPALIVE, &[status as u8]).map(|_| ())
+                };
+                let response = self.authenticator.cbor(&data, &mut keepalive);
+                write_message(device, channel, CMD_CBOR, &response)
+            }
+            CMD_CANCEL => {
+                self.authenticator.cancel();
+                Ok(0)
+            }
+            _ => error(device, CtapHidError::InvalidCommand),
+        }
+    }
+}
+
+impl<A: Authenticator> Preset for SecurityKey<A> {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.usage_page(0xf1d0) /* FIDO Alliance */
+            .usage(0x01) /* U2F Authenticator Device */
+            .collection(Collection::Application)
+            .usage(0x20) /* Input Report Data */
+            .logical_minimum(0)
+            .logical_maximum(u8::MAX as i32)
+            .report_size(8)
+            .report_count(PACKET_SIZE as u32)
+            .input(MainFlags::Variable.into())
+            .usage(0x21) /* Output Report Data */
+            .output(MainFlags::Variable.into())
+            .end_collection();
+    }
+
+    /// Takes CTAPHID packets from output reports and answers complete messages
+    fn handle<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        event: &OutputEvent,
+    ) -> io::Result<bool> {
+        let data = match event {
+            OutputEvent::Output { data } => data,
+            _ => return Ok(false),
+        };
+        /* Clients write 65 bytes to hidraw, starting with the 0 report number of a descriptor
+         * without report IDs, and it reaches the output event as is */
+        let packet = match data.split_first() {
+            Some((0, packet)) if data.len() == PACKET_SIZE + 1 => packet,
+            _ => &data[..],
+        };
+        match self.receive(packet) {
+            Received::Incomplete => {}
+            Received::Error(channel, error) => {
+                write_message(device, channel, CMD_ERROR, &[error as u8])?;
+            }
+            Received::Message(channel, command, data) => {
+                self.dispatch(device, channel, command, data)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{InputEvent, UHID_EVENT_SIZE};
+    use std::convert::TryFrom;
+    use std::os::unix::net::UnixStream;
+
+    struct Echo;
+
+    impl Authenticator for Echo {
+        fn cbor(
+            &mut self,
+            request: &[u8],
+            _keepalive: &mut dyn FnMut(KeepaliveStatus) -> io::Result<()>,
+        ) -> Vec<u8> {
+            request.to_vec()
+        }
+    }
+
+    #[test]
+    fn reassembles_continuation_packets() {
+        let mut key = SecurityKey::new(Echo);
+        let channel = key.allocate_channel();
+        let message: Vec<u8> = (0..100).collect();
+        let sent = packets(channel, CMD_PING, &message);
+        assert_eq!(sent.len(), 2);
+        assert_eq!(&sent[1][..6], &[0, 0, 0, 1, 0, 57]);
+
+        assert_eq!(key.receive(&sent[0]), Received::Incomplete);
+        assert_eq!(
+            key.receive(&packets(7, CMD_PING, &[])[0]),
+            Received::Error(7, CtapHidError::ChannelBusy)
+        );
+        assert_eq!(
+            key.receive(&sent[1]),
+            Received::Message(channel, CMD_PING, message)
+        );
+        assert_eq!(
+            key.receive(&packets(7, CMD_PING, &[])[0]),
+            Received::Error(7, CtapHidError::InvalidChannel)
+        );
+    }
+
+    #[test]
+    fn answers_init_written_through_hidraw() {
+        let (handle, mut kernel) = UnixStream::pair().unwrap();
+        let mut key = SecurityKey::new(Echo);
+        let mut device =
+            UHIDDevice::create_with_handle(key.create_params("test-key"), handle).unwrap();
+        let mut event = [0; UHID_EVENT_SIZE];
+        kernel.read_exact(&mut event).unwrap();
+
+        let nonce = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut data = vec![0];
+        data.extend_from_slice(&packets(BROADCAST_CHANNEL, CMD_INIT, &nonce)[0]);
+        assert_eq!(data.len(), PACKET_SIZE + 1);
+        assert!(key
+            .handle(&mut device, &OutputEvent::Output { data })
+            .unwrap());
+
+        kernel.read_exact(&mut event).unwrap();
+        let reply = match InputEvent::try_from(&event) {
+            Ok(InputEvent::Input { data }) => data.to_vec(),
+            _ => panic!("expected an input report"),
+        };
+        assert_eq!(reply.len(), PACKET_SIZE);
+        assert_eq!(&reply[..7], &[0xff, 0xff, 0xff, 0xff, CMD_INIT, 0, 17]);
+        assert_eq!(&reply[7..15], &nonce);
+        assert_eq!(&reply[15..19], &1u32.to_be_bytes());
+    }
+}
@@ -0,0 +1,890 @@
+//! Force feedback output reports. The default rumble report is on vendor page 0xFF00 and the PID
+//! reports are only driven by hid-pidff, which does not attach to UHID devices, so both are meant
+//! for hosts talking to the device through hidraw, such as games using SDL or a forwarding
+//! proxy. For evdev force feedback, `ForceFeedback::xbox_one_s` emulates the rumble report of a
+//! controller hid-microsoft drives through ff-memless.
+
+use std::collections::VecDeque;
+use std::io::{self, prelude::*};
+
+use enumflags2::BitFlags;
+
+use crate::codec::{Bus, OutputEvent, ReportType};
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::Preset;
+use crate::uhid_device::{CreateParams, UHIDDevice};
+
+/// Report ID offsets of the PID reports from the rumble report ID
+const SET_EFFECT: u8 = 1;
+const SET_ENVELOPE: u8 = 2;
+const SET_CONDITION: u8 = 3;
+const SET_PERIODIC: u8 = 4;
+const SET_CONSTANT_FORCE: u8 = 5;
+const SET_RAMP_FORCE: u8 = 6;
+const EFFECT_OPERATION: u8 = 7;
+const BLOCK_FREE: u8 = 8;
+const DEVICE_CONTROL: u8 = 9;
+const DEVICE_GAIN: u8 = 10;
+const CREATE_NEW_EFFECT: u8 = 11;
+const BLOCK_LOAD: u8 = 12;
+const POOL: u8 = 13;
+
+/// Logical range of magnitudes, offsets and coefficients, as used by most PID devices
+const MAX_MAGNITUDE: i32 = 10000;
+const RAM_POOL_SIZE: u16 = u16::MAX;
+/// English rotation system, angle in degrees
+const UNIT_DEGREES: u32 = 0x14;
+/// SI linear system, time in seconds
+const UNIT_SECONDS: u32 = 0x1001;
+
+/// XB1S_FF_REPORT of hid-microsoft and its enable bits
+const XBOX_RUMBLE_REPORT: u8 = 0x03;
+const XBOX_ENABLE_WEAK: u8 = 0b01;
+const XBOX_ENABLE_STRONG: u8 = 0b10;
+/// Largest actuator magnitude written by hid-microsoft, in percent
+const XBOX_MAX_MAGNITUDE: u8 = 100;
+
+const BLOCK_LOAD_SUCCESS: u8 = 1;
+const BLOCK_LOAD_FULL: u8 = 2;
+
+/// Effect types of the PID page, in the order of their array indices
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum EffectType {
+    Constant = 1,
+    Ramp = 2,
+    Square = 3,
+    Sine = 4,
+    Triangle = 5,
+    SawtoothUp = 6,
+    SawtoothDown = 7,
+    Spring = 8,
+    Damper = 9,
+    Inertia = 10,
+    Friction = 11,
+}
+
+const EFFECT_TYPES: [(EffectType, u32); 11] = [
+    (EffectType::Constant, 0x26),
+    (EffectType::Ramp, 0x27),
+    (EffectType::Square, 0x30),
+    (EffectType::Sine, 0x31),
+    (EffectType::Triangle, 0x32),
+    (EffectType::SawtoothUp, 0x33),
+    (EffectType::SawtoothDown, 0x34),
+    (EffectType::Spring, 0x40),
+    (EffectType::Damper, 0x41),
+    (EffectType::Inertia, 0x42),
+    (EffectType::Friction, 0x43),
+];
+
+impl EffectType {
+    fn from_index(index: u8) -> Option<EffectType> {
+        EFFECT_TYPES
+            .iter()
+            .map(|(effect_type, _)| *effect_type)
+            .find(|effect_type| *effect_type as u8 == index)
+    }
+}
+
+/// Operations of the Effect Operation report
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum EffectOperation {
+    Start = 1,
+    /// Starts the effect and stops all others
+    StartSolo = 2,
+    Stop = 3,
+}
+
+impl EffectOperation {
+    fn from_index(index: u8) -> Option<EffectOperation> {
+        match index {
+            1 => Some(EffectOperation::Start),
+            2 => Some(EffectOperation::StartSolo),
+            3 => Some(EffectOperation::Stop),
+            _ => None,
+        }
+    }
+}
+
+/// Commands of the PID Device Control report
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum DeviceControl {
+    EnableActuators = 1,
+    DisableActuators = 2,
+    StopAllEffects = 3,
+    Reset = 4,
+    Pause = 5,
+    Continue = 6,
+}
+
+impl DeviceControl {
+    fn from_index(index: u8) -> Option<DeviceControl> {
+        match index {
+            1 => Some(DeviceControl::EnableActuators),
+            2 => Some(DeviceControl::DisableActuators),
+            3 => Some(DeviceControl::StopAllEffects),
+            4 => Some(DeviceControl::Reset),
+            5 => Some(DeviceControl::Pause),
+            6 => Some(DeviceControl::Continue),
+            _ => None,
+        }
+    }
+}
+
+/// Contents of a Set Effect report
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectParameters {
+    pub block: u8,
+    pub effect_type: EffectType,
+    /// `u16::MAX` means infinite
+    pub duration_ms: u16,
+    pub trigger_repeat_ms: u16,
+    pub gain: u8,
+    /// Button number that starts the effect, 0xff for none
+    pub trigger_button: u8,
+    /// Direction of the X and Y axis in 1/256 of a full turn
+    pub direction: (u8, u8),
+    pub start_delay_ms: u16,
+}
+
+/// A force feedback command from the host, decoded from an output report or SET_REPORT request.
+/// Blocks are the effect slots handed out by `CreateEffect`, numbered from 1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForceFeedbackEvent {
+    /// Motor speeds of the simple rumble report
+    Rumble {
+        strong: u8,
+        weak: u8,
+    },
+    CreateEffect {
+        block: u8,
+        effect_type: EffectType,
+    },
+    SetEffect(EffectParameters),
+    SetEnvelope {
+        block: u8,
+        attack_level: u16,
+        fade_level: u16,
+        attack_time_ms: u16,
+        fade_time_ms: u16,
+    },
+    SetCondition {
+        block: u8,
+        center: i16,
+        positive_coefficient: i16,
+        negative_coefficient: i16,
+        positive_saturation: u16,
+        negative_saturation: u16,
+        dead_band: u16,
+    },
+    SetPeriodic {
+        block: u8,
+        magnitude: u16,
+        offset: i16,
+        /// In 1/256 of a period
+        phase: u8,
+        period_ms: u16,
+    },
+    SetConstantForce {
+        block: u8,
+        magnitude: i16,
+    },
+    SetRampForce {
+        block: u8,
+        start: i16,
+        end: i16,
+    },
+    Operation {
+        block: u8,
+        operation: EffectOperation,
+        /// Number of repetitions, 0xff for infinite
+        loop_count: u8,
+    },
+    FreeEffect {
+        block: u8,
+    },
+    Control(DeviceControl),
+    Gain(u8),
+}
+
+/// Little-endian fields of a report, read in order. Missing bytes read as zero.
+struct Fields<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn new(data: &'a [u8]) -> Fields<'a> {
+        Fields { data, position: 0 }
+    }
+
+    fn u8(&mut self) -> u8 {
+        let value = self.data.get(self.position).copied().unwrap_or(0);
+        self.position += 1;
+        value
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes([self.u8(), self.u8()])
+    }
+
+    fn i16(&mut self) -> i16 {
+        self.u16() as i16
+    }
+}
+
+/// Force feedback output reports. The rumble report under `report_id` carries the speed of a
+/// strong and a weak motor. With `with_pid`, the reports of the HID Physical Interface Device
+/// page follow under `report_id + 1` to `report_id + 13`.
+///
+/// Decoded commands are queued by `Preset::handle` and taken with `next_event`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForceFeedback {
+    report_id: u8,
+    /// Whether the rumble report uses the Xbox One S layout
+    xbox: bool,
+    /// Effect type of each allocated block, `None` without PID support
+    blocks: Option<Vec<Option<EffectType>>>,
+    /// Block index and status for the next Block Load GET_REPORT
+    block_load: (u8, u8),
+    events: VecDeque<ForceFeedbackEvent>,
+}
+
+impl ForceFeedback {
+    /// Fails on report ID 0, as the reports are told apart by their ID
+    pub fn new(report_id: u8) -> io::Result<ForceFeedback> {
+        if report_id == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "force feedback needs a nonzero report ID",
+            ));
+        }
+        Ok(ForceFeedback {
+            report_id,
+            xbox: false,
+            blocks: None,
+            block_load: (0, BLOCK_LOAD_FULL),
+            events: VecDeque::new(),
+        })
+    }
+
+    /// The rumble report of an Xbox One S controller over Bluetooth, report ID 3. With the IDs of
+    /// `xbox_one_s_params`, hid-microsoft binds to the device and registers FF_RUMBLE through
+    /// ff-memless, then writes this report for every rumble effect played on the evdev node. The
+    /// device also needs an input collection, such as a `Gamepad` with another report ID, for the
+    /// kernel to create that node.
+    pub fn xbox_one_s() -> ForceFeedback {
+        ForceFeedback {
+            report_id: XBOX_RUMBLE_REPORT,
+            xbox: true,
+            blocks: None,
+            block_load: (0, BLOCK_LOAD_FULL),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Parameters of an Xbox One S controller over Bluetooth, 045e:02fd, which hid-microsoft
+    /// handles with its force feedback quirk
+    pub fn xbox_one_s_params(name: &str, rd_data: Vec<u8>) -> CreateParams {
+        CreateParams {
+            name: name.to_string(),
+            phys: "".to_string(),
+            uniq: "".to_string(),
+            bus: Bus::BLUETOOTH,
+            vendor: 0x045e,
+            product: 0x02fd,
+            version: 0,
+            country: 0,
+            rd_data,
+        }
+    }
+
+    /// Adds the PID reports with room for `max_effects` simultaneously loaded effects. Fails if
+    /// their report IDs would run past 255.
+    pub fn with_pid(mut self, max_effects: u8) -> io::Result<ForceFeedback> {
+        if self.report_id > u8::MAX - POOL {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "report ID {} leaves no room for the PID reports",
+                    self.report_id
+                ),
+            ));
+        }
+        self.blocks = Some(vec![None; max_effects.max(1) as usize]);
+        Ok(self)
+    }
+
+    /// Takes the oldest command received from the host
+    pub fn next_event(&mut self) -> Option<ForceFeedbackEvent> {
+        self.events.pop_front()
+    }
+
+    fn max_effects(&self) -> u8 {
+        self.blocks.as_ref().map_or(0, |blocks| blocks.len() as u8)
+    }
+
+    /// The PID report offset of a report number, if it belongs to this preset
+    fn pid_report(&self, report_number: u8) -> Option<u8> {
+        self.blocks.as_ref()?;
+        let offset = report_number.checked_sub(self.report_id)?;
+        if (SET_EFFECT..=POOL).contains(&offset) {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    fn create_effect(&mut self, effect_type: EffectType) -> Option<ForceFeedbackEvent> {
+        let free = self
+            .blocks
+            .as_mut()?
+            .iter_mut()
+            .enumerate()
+            .find(|(_, block)| block.is_none());
+        match free {
+            Some((index, block)) => {
+                *block = Some(effect_type);
+                let block = index as u8 + 1;
+                self.block_load = (block, BLOCK_LOAD_SUCCESS);
+                Some(ForceFeedbackEvent::CreateEffect { block, effect_type })
+            }
+            None => {
+                self.block_load = (0, BLOCK_LOAD_FULL);
+                None
+            }
+        }
+    }
+
+    fn free_effect(&mut self, block: u8) {
+        if let Some(slot) = self
+            .blocks
+            .as_mut()
+            .and_then(|blocks| blocks.get_mut((block as usize).wrapping_sub(1)))
+        {
+            *slot = None;
+        }
+    }
+
+    /// Decodes an output report starting with its report ID
+    fn parse_output(&mut self, data: &[u8]) -> Option<ForceFeedbackEvent> {
+        let mut fields = Fields::new(data);
+        let report_number = fields.u8();
+        if report_number == self.report_id && self.xbox {
+            /* Enable bits, then left trigger, right trigger, strong and weak magnitudes */
+            let enable = fields.u8();
+            let (_, _, strong, weak) = (fields.u8(), fields.u8(), fields.u8(), fields.u8());
+            let speed = |magnitude: u8, bit: u8| {
+                if enable & bit == 0 {
+                    0
+                } else {
+                    (magnitude.min(XBOX_MAX_MAGNITUDE) as u32 * u8::MAX as u32
+                        / XBOX_MAX_MAGNITUDE as u32) as u8
+                }
+            };
+            return Some(ForceFeedbackEvent::Rumble {
+                strong: speed(strong, XBOX_ENABLE_STRONG),
+                weak: speed(weak, XBOX_ENABLE_WEAK),
+            });
+        }
+        if report_number == self.report_id {
+            return Some(ForceFeedbackEvent::Rumble {
+                strong: fields.u8(),
+                weak: fields.u8(),
+            });
+        }
+        let event = match self.pid_report(report_number)? {
+            SET_EFFECT => ForceFeedbackEvent::SetEffect(EffectParameters {
+                block: fields.u8(),
+                effect_type: EffectType::from_index(fields.u8())?,
+                duration_ms: fields.u16(),
+                trigger_repeat_ms: fields.u16(),
+                gain: fields.u8(),
+                trigger_button: fields.u8(),
+                direction: (fields.u8(), fields.u8()),
+                start_delay_ms: fields.u16(),
+            }),
+            SET_ENVELOPE => ForceFeedbackEvent::SetEnvelope {
+                block: fields.u8(),
+                attack_level: fields.u16(),
+                fade_level: fields.u16(),
+                attack_time_ms: fields.u16(),
+                fade_time_ms: fields.u16(),
+            },
+            SET_CONDITION => ForceFeedbackEvent::SetCondition {
+                block: fields.u8(),
+                center: fields.i16(),
+                positive_coefficient: fields.i16(),
+                negative_coefficient: fields.i16(),
+                positive_saturation: fields.u16(),
+                negative_saturation: fields.u16(),
+                dead_band: fields.u16(),
+            },
+            SET_PERIODIC => ForceFeedbackEvent::SetPeriodic {
+                block: fields.u8(),
+                magnitude: fields.u16(),
+                offset: fields.i16(),
+                phase: fields.u8(),
+                period_ms: fields.u16(),
+            },
+            SET_CONSTANT_FORCE => ForceFeedbackEvent::SetConstantForce {
+                block: fields.u8(),
+                magnitude: fields.i16(),
+            },
+            SET_RAMP_FORCE => ForceFeedbackEvent::SetRampForce {
+                block: fields.u8(),
+                start: fields.i16(),
+                end: fields.i16(),
+            },
+            EFFECT_OPERATION => ForceFeedbackEvent::Operation {
+                block: fields.u8(),
+                operation: EffectOperation::from_index(fields.u8())?,
+                loop_count: fields.u8(),
+            },
+            BLOCK_FREE => {
+                let block = fields.u8();
+                self.free_effect(block);
+                ForceFeedbackEvent::FreeEffect { block }
+            }
+            DEVICE_CONTROL => {
+                let control = DeviceControl::from_index(fields.u8())?;
+                if control == DeviceControl::Reset {
+                    if let Some(blocks) = self.blocks.as_mut() {
+                        blocks.iter_mut().for_each(|block| *block = None);
+                    }
+                }
+                ForceFeedbackEvent::Control(control)
+            }
+            DEVICE_GAIN => ForceFeedbackEvent::Gain(fields.u8()),
+            _ => return None,
+        };
+        Some(event)
+    }
+
+    fn feature_report(&self, report_number: u8) -> Option<Vec<u8>> {
+        match self.pid_report(report_number)? {
+            BLOCK_LOAD => {
+                let available = RAM_POOL_SIZE.to_le_bytes();
+                let (block, status) = self.block_load;
+                Some(vec![
+                    report_number,
+                    block,
+                    status,
+                    available[0],
+                    available[1],
+                ])
+            }
+            POOL => {
+                let size = RAM_POOL_SIZE.to_le_bytes();
+                /* Device Managed Pool */
+                Some(vec![report_number, size[0], size[1], self.max_effects(), 1])
+            }
+            _ => None,
+        }
+    }
+
+    fn describe_pid(&self, rd: &mut ReportDescriptorBuilder) {
+        let id = |offset: u8| self.report_id + offset;
+        let field = |rd: &mut ReportDescriptorBuilder,
+                     usage: u32,
+                     minimum: i32,
+                     maximum: i32,
+                     size: u32| {
+            rd.usage(usage)
+                .logical_minimum(minimum)
+                .logical_maximum(maximum)
+                .report_size(size)
+                .report_count(1);
+        };
+        let block_index = |rd: &mut ReportDescriptorBuilder| {
+            field(rd, 0x22, 1, self.max_effects() as i32, 8); /* Effect Block Index */
+        };
+        let array = |rd: &mut ReportDescriptorBuilder, usage: u32, usages: &[u32]| {
+            rd.usage(usage).collection(Collection::Logical);
+            for usage in usages {
+                rd.usage(*usage);
+            }
+            rd.logical_minimum(1)
+                .logical_maximum(usages.len() as i32)
+                .report_size(8)
+                .report_count(1);
+        };
+        let effect_types: Vec<u32> = EFFECT_TYPES.iter().map(|(_, usage)| *usage).collect();
+        let output = MainFlags::Variable.into();
+        let milliseconds = |rd: &mut ReportDescriptorBuilder, usage: u32| {
+            rd.push().unit(UNIT_SECONDS).unit_exponent(-3);
+            field(rd, usage, 0, u16::MAX as i32, 16);
+            rd.output(output).pop();
+        };
+
+        rd.usage(0x21) /* Set Effect Report */
+            .collection(Collection::Logical)
+            .report_id(id(SET_EFFECT));
+        block_index(rd);
+        rd.output(output);
+        array(rd, 0x25, &effect_types); /* Effect Type */
+        rd.output(BitFlags::empty()).end_collection();
+        milliseconds(rd, 0x50); /* Duration */
+        milliseconds(rd, 0x54); /* Trigger Repeat Interval */
+        field(rd, 0x52, 0, u8::MAX as i32, 8); /* Gain */
+        rd.output(output);
+        field(rd, 0x53, 0, u8::MAX as i32, 8); /* Trigger Button */
+        rd.output(output)
+            .usage(0x57) /* Direction */
+            .collection(Collection::Logical)
+            .push()
+            .unit(UNIT_DEGREES)
+            .physical_minimum(0)
+            .physical_maximum(360)
+            .usage(0x0a0001) /* Ordinals, Instance 1 */
+            .usage(0x0a0002) /* Ordinals, Instance 2 */
+            .logical_minimum(0)
+            .logical_maximum(u8::MAX as i32)
+            .report_size(8)
+            .report_count(2)
+            .output(output)
+            .pop()
+            .end_collection();
+        milliseconds(rd, 0xa7); /* Start Delay */
+        rd.end_collection();
+
+        rd.usage(0x5a) /* Set Envelope Report */
+            .collection(Collection::Logical)
+            .report_id(id(SET_ENVELOPE));
+        block_index(rd);
+        rd.output(output);
+        field(rd, 0x5b, 0, MAX_MAGNITUDE, 16); /* Attack Level */
+        rd.output(output);
+        field(rd, 0x5d, 0, MAX_MAGNITUDE, 16); /* Fade Level */
+        rd.output(output);
+        milliseconds(rd, 0x5c); /* Attack Time */
+        milliseconds(rd, 0x5e); /* Fade Time */
+        rd.end_collection();
+
+        rd.usage(0x5f) /* Set Condition Report */
+            .collection(Collection::Logical)
+            .report_id(id(SET_CONDITION));
+        block_index(rd);
+        rd.output(output);
+        for usage in [0x60, 0x61, 0x62] {
+            /* CP Offset, Positive Coefficient, Negative Coefficient */
+            field(rd, usage, -MAX_MAGNITUDE, MAX_MAGNITUDE, 16);
+            rd.output(output);
+        }
+        for usage in [0x63, 0x64, 0x65] {
+            /* Positive Saturation, Negative Saturation, Dead Band */
+            field(rd, usage, 0, MAX_MAGNITUDE, 16);
+            rd.output(output);
+        }
+        rd.end_collection();
+
+        rd.usage(0x6e) /* Set Periodic Report */
+            .collection(Collection::Logical)
+            .report_id(id(SET_PERIODIC));
+        block_index(rd);
+        rd.output(output);
+        field(rd, 0x70, 0, MAX_MAGNITUDE, 16); /* Magnitude */
+        rd.output(output);
+        field(rd, 0x6f, -MAX_MAGNITUDE, MAX_MAGNITUDE, 16); /* Offset */
+        rd.output(output)
+            .push()
+            .unit(UNIT_DEGREES)
+            .physical_minimum(0)
+            .physical_maximum(360);
+        field(rd, 0x71, 0, u8::MAX as i32, 8); /* Phase */
+        rd.output(output).pop();
+        milliseconds(rd, 0x72); /* Period */
+        rd.end_collection();
+
+        rd.usage(0x73) /* Set Constant Force Report */
+            .collection(Collection::Logical)
+            .report_id(id(SET_CONSTANT_FORCE));
+        block_index(rd);
+        rd.output(output);
+        field(rd, 0x70, -MAX_MAGNITUDE, MAX_MAGNITUDE, 16); /* Magnitude */
+        rd.output(output).end_collection();
+
+        rd.usage(0x74) /* Set Ramp Force Report */
+            .collection(Collection::Logical)
+            .report_id(id(SET_RAMP_FORCE));
+        block_index(rd);
+        rd.output(output);
+        field(rd, 0x75, -MAX_MAGNITUDE, MAX_MAGNITUDE, 16); /* Ramp Start */
+        rd.output(output);
+        field(rd, 0x76, -MAX_MAGNITUDE, MAX_MAGNITUDE, 16); /* Ramp End */
+        rd.output(output).end_collection();
+
+        rd.usage(0x77) /* Effect Operation Report */
+            .collection(Collection::Logical)
+            .report_id(id(EFFECT_OPERATION));
+        block_index(rd);
+        rd.output(output);
+        /* Effect Operation: Op Effect Start, Op Effect Start Solo, Op Effect Stop */
+        array(rd, 0x78, &[0x79, 0x7a, 0x7b]);
+        rd.output(BitFlags::empty()).end_collection();
+        field(rd, 0x7c, 0, u8::MAX as i32, 8); /* Loop Count */
+        rd.output(output).end_collection();
+
+        rd.usage(0x90) /* PID Block Free Report */
+            .collection(Collection::Logical)
+            .report_id(id(BLOCK_FREE));
+        block_index(rd);
+        rd.output(output).end_collection();
+
+        rd.usage(0x95) /* PID Device Control Report */
+            .collection(Collection::Logical)
+            .report_id(id(DEVICE_CONTROL));
+        array(rd, 0x96, &[0x97, 0x98, 0x99, 0x9a, 0x9b, 0x9c]); /* PID Device Control */
+        rd.output(BitFlags::empty())
+            .end_collection()
+            .end_collection();
+
+        rd.usage(0x7d) /* Device Gain Report */
+            .collection(Collection::Logical)
+            .report_id(id(DEVICE_GAIN));
+        field(rd, 0x7e, 0, u8::MAX as i32, 8); /* Device Gain */
+        rd.output(output).end_collection();
+
+        rd.usage(0xab) /* Create New Effect Report */
+            .collection(Collection::Logical)
+            .report_id(id(CREATE_NEW_EFFECT));
+        array(rd, 0x25, &effect_types); /* Effect Type */
+        rd.feature(BitFlags::empty())
+            .end_collection()
+            .end_collection();
+
+        rd.usage(0x89) /* PID Block Load Report */
+            .collection(Collection::Logical)
+            .report_id(id(BLOCK_LOAD));
+        block_index(rd);
+        rd.feature(output);
+        /* Block Load Status: Block Load Success, Block Load Full */
+        array(rd, 0x8b, &[0x8c, 0x8d]);
+        rd.feature(BitFlags::empty()).end_collection();
+        field(rd, 0xac, 0, u16::MAX as i32, 16); /* RAM Pool Available */
+        rd.feature(output).end_collection();
+
+        rd.usage(0x7f) /* PID Pool Report */
+            .collection(Collection::Logical)
+            .report_id(id(POOL));
+        field(rd, 0x80, 0, u16::MAX as i32, 16); /* RAM Pool Size */
+        rd.feature(output);
+        field(rd, 0x83, 0, u8::MAX as i32, 8); /* Simultaneous Effects Max */
+        rd.feature(output)
+            .usage(0xa9) /* Device Managed Pool */
+            .usage(0xaa) /* Shared Parameter Blocks */
+            .logical_minimum(0)
+            .logical_maximum(1)
+            .report_size(1)
+            .report_count(2)
+            .feature(output)
+            .report_count(6)
+            .feature(MainFlags::Constant.into())
+            .end_collection();
+    }
+}
+
+impl Preset for ForceFeedback {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        if self.xbox {
+            /* Set Effect report of the real controller: enabled actuators, four magnitudes,
+             * duration, start delay and loop count */
+            rd.usage_page(0x0f) /* Physical Interface Device */
+                .usage(0x21) /* Set Effect Report */
+                .collection(Collection::Logical)
+                .report_id(self.report_id)
+                .usage(0x97) /* DC Enable Actuators */
+                .logical_minimum(0)
+                .logical_maximum(1)
+                .report_size(4)
+                .report_count(1)
+                .output(MainFlags::Variable.into())
+                .report_count(1)
+                .output(MainFlags::Constant.into())
+                .usage(0x70) /* Magnitude */
+                .logical_maximum(XBOX_MAX_MAGNITUDE as i32)
+                .report_size(8)
+                .report_count(4)
+                .output(MainFlags::Variable.into())
+                .usage(0x50) /* Duration */
+                .usage(0xa7) /* Start Delay */
+                .usage(0x7c) /* Loop Count */
+                .logical_maximum(u8::MAX as i32)
+                .report_count(3)
+                .output(MainFlags::Variable.into())
+                .end_collection();
+            if self.blocks.is_some() {
+                rd.usage(0x01) /* Physical Interface Device */
+                    .collection(Collection::Application);
+                self.describe_pid(rd);
+                rd.end_collection();
+            }
+            return;
+        }
+        rd.usage_page(0x0f) /* Physical Interface Device */
+            .usage(0x01) /* Physical Interface Device */
+            .collection(Collection::Application)
+            .report_id(self.report_id)
+            .push()
+            .usage_page(0xff00) /* Vendor-defined */
+            .usage(0x01) /* Strong motor */
+            .usage(0x02) /* Weak motor */
+            .logical_minimum(0)
+            .logical_maximum(u8::MAX as i32)
+            .report_size(8)
+            .report_count(2)
+            .output(MainFlags::Variable.into())
+            .pop();
+        if self.blocks.is_some() {
+            self.describe_pid(rd);
+        }
+        rd.end_collection();
+    }
+
+    fn handle<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        event: &OutputEvent,
+    ) -> io::Result<bool> {
+        match event {
+            OutputEvent::Output { data } => match self.parse_output(data) {
+                Some(event) => {
+                    self.events.push_back(event);
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+            OutputEvent::SetReport {
+                id,
+                report_number,
+                report_type: ReportType::Output,
+                data,
+            } if *report_number == self.report_id || self.pid_report(*report_number).is_some() => {
+                let event = self.parse_output(data);
+                let err = match event {
+                    Some(_) => 0,
+                    None => libc::EINVAL as u16,
+                };
+                device.write_set_report_reply(*id, err)?;
+                self.events.extend(event);
+                Ok(true)
+            }
+            OutputEvent::SetReport {
+                id,
+                report_number,
+                report_type: ReportType::Feature,
+                data,
+            } if self.pid_report(*report_number) == Some(CREATE_NEW_EFFECT) => {
+                let effect_type = EffectType::from_index(data.get(1).copied().unwrap_or(0));
+                let event = effect_type.and_then(|effect_type| self.create_effect(effect_type));
+                let err = match event {
+                    Some(_) => 0,
+                    None => libc::EINVAL as u16,
+                };
+                device.write_set_report_reply(*id, err)?;
+                self.events.extend(event);
+                Ok(true)
+            }
+            OutputEvent::GetReport {
+                id,
+                report_number,
+                report_type: ReportType::Feature,
+            } => match self.feature_report(*report_number) {
+                Some(report) => {
+                    device.write_get_report_reply(*id, 0, report)?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_pid_reports_and_allocates_blocks() {
+        let mut ff = ForceFeedback::new(0x10).unwrap().with_pid(1).unwrap();
+        assert_eq!(
+            ff.parse_output(&[0x10, 0xff, 0x40]),
+            Some(ForceFeedbackEvent::Rumble {
+                strong: 0xff,
+                weak: 0x40
+            })
+        );
+        assert_eq!(
+            ff.create_effect(EffectType::Sine),
+            Some(ForceFeedbackEvent::CreateEffect {
+                block: 1,
+                effect_type: EffectType::Sine
+            })
+        );
+        assert_eq!(ff.create_effect(EffectType::Spring), None);
+        assert_eq!(ff.feature_report(0x1c), Some(vec![0x1c, 0, 2, 0xff, 0xff]));
+
+        assert_eq!(
+            ff.parse_output(&[0x15, 1, 0x30, 0xf8]),
+            Some(ForceFeedbackEvent::SetConstantForce {
+                block: 1,
+                magnitude: -2000
+            })
+        );
+        assert_eq!(
+            ff.parse_output(&[0x17, 1, 3, 0]),
+            Some(ForceFeedbackEvent::Operation {
+                block: 1,
+                operation: EffectOperation::Stop,
+                loop_count: 0
+            })
+        );
+        ff.parse_output(&[0x18, 1]);
+        assert!(ff.create_effect(EffectType::Spring).is_some());
+        assert_eq!(ff.parse_output(&[0x2a, 1]), None);
+        assert_eq!(ff.parse_output(&[0x0f, 1]), None);
+
+        assert!(ForceFeedback::new(0).is_err());
+        assert!(ForceFeedback::new(u8::MAX - POOL)
+            .unwrap()
+            .with_pid(1)
+            .is_ok());
+        assert!(ForceFeedback::new(u8::MAX - POOL + 1)
+            .unwrap()
+            .with_pid(1)
+            .is_err());
+    }
+
+    #[test]
+    fn decodes_hid_microsoft_rumble() {
+        let mut ff = ForceFeedback::xbox_one_s();
+        let descriptor = crate::ReportDescriptor::parse(&ff.descriptor()).unwrap();
+        let fields = descriptor.fields().unwrap();
+        /* The 9-byte report written by ms_ff_worker */
+        assert_eq!(
+            fields
+                .iter()
+                .map(|field| field.report_size * field.report_count)
+                .sum::<u32>(),
+            8 * 8
+        );
+        assert_eq!(
+            ff.parse_output(&[0x03, 0x03, 0, 0, 100, 50, 0xff, 0, 0xff]),
+            Some(ForceFeedbackEvent::Rumble {
+                strong: 0xff,
+                weak: 0x7f
+            })
+        );
+        assert_eq!(
+            ff.parse_output(&[0x03, 0x01, 0, 0, 100, 50, 0xff, 0, 0xff]),
+            Some(ForceFeedbackEvent::Rumble {
+                strong: 0,
+                weak: 0x7f
+            })
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use std::io::{self, prelude::*};
+
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::Preset;
+use crate::uhid_device::UHIDDevice;
+
+/// English rotation system, angle in degrees
+const UNIT_DEGREES: u32 = 0x14;
+const MAX_BUTTONS: u8 = 32;
+const MAX_HATS: u8 = 2;
+
+/// Generic Desktop axes. hid-input maps them to the evdev axis of the same name.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum GamepadAxis {
+    X = 0x30,
+    Y = 0x31,
+    Z = 0x32,
+    Rx = 0x33,
+    Ry = 0x34,
+    Rz = 0x35,
+    Slider = 0x36,
+    Dial = 0x37,
+    Wheel = 0x38,
+}
+
+/// Size of the axis and trigger fields
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AxisResolution {
+    /// Axes from -127 to 127, triggers from 0 to 255
+    Bits8,
+    /// Axes from -32767 to 32767, triggers from 0 to 65535
+    Bits16,
+}
+
+impl AxisResolution {
+    fn bits(self) -> u32 {
+        match self {
+            AxisResolution::Bits8 => 8,
+            AxisResolution::Bits16 => 16,
+        }
+    }
+
+    fn max_axis(self) -> i32 {
+        match self {
+            AxisResolution::Bits8 => i8::MAX as i32,
+            AxisResolution::Bits16 => i16::MAX as i32,
+        }
+    }
+
+    fn max_trigger(self) -> u32 {
+        match self {
+            AxisResolution::Bits8 => u8::MAX as u32,
+            AxisResolution::Bits16 => u16::MAX as u32,
+        }
+    }
+
+    fn push(self, report: &mut Vec<u8>, value: u32) {
+        match self {
+            AxisResolution::Bits8 => report.push(value as u8),
+            AxisResolution::Bits16 => report.extend_from_slice(&(value as u16).to_le_bytes()),
+        }
+    }
+}
+
+/// Direction of a hat switch, clockwise from up
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum Hat {
+    Up = 0,
+    UpRight = 1,
+    Right = 2,
+    DownRight = 3,
+    Down = 4,
+    DownLeft = 5,
+    Left = 6,
+    UpLeft = 7,
+    /// Sent as a value outside of the logical range, which the Null State flag marks as no input
+    Centered = 8,
+}
+
+/// Controls of a gamepad. Values beyond what the gamepad was configured with are ignored and
+/// out-of-range values are clamped when the report is built.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GamepadState {
+    /// Bit `n` holds button `n + 1`
+    pub buttons: u32,
+    /// One value per configured axis
+    pub axes: Vec<i32>,
+    /// One direction per configured hat switch
+    pub hats: Vec<Hat>,
+    /// Left and right trigger
+    pub triggers: (u32, u32),
+}
+
+impl GamepadState {
+    pub fn set_button(&mut self, index: u8, pressed: bool) {
+        if index < MAX_BUTTONS {
+            if pressed {
+                self.buttons |= 1 << index;
+            } else {
+                self.buttons &= !(1 << index);
+            }
+        }
+    }
+
+    pub fn is_pressed(&self, index: u8) -> bool {
+        index < MAX_BUTTONS && self.buttons & 1 << index != 0
+    }
+}
+
+/// A gamepad with up to 32 buttons, any number of Generic Desktop axes, up to two hat switches and
+/// optional analog triggers. Its Gamepad application collection makes hid-input map the first 16
+/// buttons to BTN_SOUTH and onwards, the rest to BTN_TRIGGER_HAPPY. A second hat switch only
+/// reaches evdev with HID_QUIRK_INCREMENT_USAGE_ON_DUPLICATE, see `with_hats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gamepad {
+    report_id: u8,
+    buttons: u8,
+    axes: Vec<GamepadAxis>,
+    resolution: AxisResolution,
+    hats: u8,
+    triggers: bool,
+    state: GamepadState,
+    /// The report last written by `send_state`
+    sent: Option<Vec<u8>>,
+}
+
+impl Gamepad {
+    /// A gamepad with `buttons` buttons, clamped to 32, and the given axes
+    pub fn new(
+        report_id: u8,
+        buttons: u8,
+        axes: &[GamepadAxis],
+        resolution: AxisResolution,
+    ) -> Gamepad {
+        Gamepad {
+            report_id,
+            buttons: buttons.min(MAX_BUTTONS),
+            axes: axes.to_vec(),
+            resolution,
+            hats: 0,
+            triggers: false,
+            state: GamepadState {
+                buttons: 0,
+                axes: vec![0; axes.len()],
+                hats: Vec::new(),
+                triggers: (0, 0),
+            },
+            sent: None,
+        }
+    }
+
+    /// Adds hat switches, at most two. The first one is reported as ABS_HAT0X/Y. Both use the
+    /// Hat Switch usage, so hid-input drops the second one as a duplicate unless the device gets
+    /// HID_QUIRK_INCREMENT_USAGE_ON_DUPLICATE, for example with the `quirks` parameter of the
+    /// hid module, which then maps it to ABS_HAT1X/Y.
+    pub fn with_hats(mut self, hats: u8) -> Gamepad {
+        self.hats = hats.min(MAX_HATS);
+        self.state.hats = vec![Hat::Centered; self.hats as usize];
+        self
+    }
+
+    /// Adds left and right triggers as the Brake and Accelerator usages of the Simulation
+    /// Controls page, reported as ABS_BRAKE and ABS_GAS
+    pub fn with_triggers(mut self) -> Gamepad {
+        self.triggers = true;
+        self
+    }
+
+    pub fn state(&self) -> &GamepadState {
+        &self.state
+    }
+
+    /// The state sent by the next `send_state`
+    pub fn state_mut(&mut self) -> &mut GamepadState {
+        &mut self.state
+    }
+
+    fn report(&self) -> Vec<u8> {
+        let mut report = vec![self.report_id];
+        let button_bytes = (self.buttons as usize).div_ceil(8);
+        report.extend_from_slice(&self.state.buttons.to_le_bytes()[..button_bytes]);
+
+        let max_axis = self.resolution.max_axis();
+        for i in 0..self.axes.len() {
+            let value = self.state.axes.get(i).copied().unwrap_or(0);
+            self.resolution
+                .push(&mut report, value.clamp(-max_axis, max_axis) as u32);
+        }
+
+        if self.hats > 0 {
+            let hat = |i: usize| self.state.hats.get(i).copied().unwrap_or(Hat::Centered) as u8;
+            let second = if self.hats > 1 { hat(1) } else { 0 };
+            report.push(hat(0) | second << 4);
+        }
+
+        if self.triggers {
+            let max_trigger = self.resolution.max_trigger();
+            self.resolution
+                .push(&mut report, self.state.triggers.0.min(max_trigger));
+            self.resolution
+                .push(&mut report, self.state.triggers.1.min(max_trigger));
+        }
+        report
+    }
+
+    /// Writes the current state, unless it would repeat the last report. Returns the number of
+    /// bytes written, zero when nothing changed.
+    pub fn send_state<T: Read + Write>(&mut self, device: &mut UHIDDevice<T>) -> io::Result<usize> {
+        let report = self.report();
+        if self.sent.as_ref() == Some(&report) {
+            return Ok(0);
+        }
+        let written = device.write(&report)?;
+        self.sent = Some(report);
+        Ok(written)
+    }
+}
+
+impl Preset for Gamepad {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.usage_page(0x01) /* Generic Desktop */
+            .usage(0x05) /* Gamepad */
+            .collection(Collection::Application)
+            .report_id(self.report_id);
+
+        if self.buttons > 0 {
+            rd.usage_page(0x09) /* Button */
+                .usage_minimum(1)
+                .usage_maximum(self.buttons as u32)
+                .logical_minimum(0)
+                .logical_maximum(1)
+                .report_size(1)
+                .report_count(self.buttons as u32)
+                .input(MainFlags::Variable.into());
+            let padding = (8 - self.buttons % 8) % 8;
+            if padding > 0 {
+                rd.report_count(padding as u32)
+                    .input(MainFlags::Constant.into());
+            }
+        }
+
+        rd.usage_page(0x01); /* Generic Desktop */
+        if !self.axes.is_empty() {
+            let max_axis = self.resolution.max_axis();
+            for axis in self.axes.iter() {
+                rd.usage(*axis as u32);
+            }
+            rd.logical_minimum(-max_axis)
+                .logical_maximum(max_axis)
+                .report_size(self.resolution.bits())
+                .report_count(self.axes.len() as u32)
+                .input(MainFlags::Variable.into());
+        }
+
+        if self.hats > 0 {
+            for _ in 0..self.hats {
+                rd.usage(0x39); /* Hat Switch */
+            }
+            rd.push()
+                .logical_minimum(0)
+                .logical_maximum(7)
+                .physical_minimum(0)
+                .physical_maximum(315)
+                .unit(UNIT_DEGREES)
+                .report_size(4)
+                .report_count(self.hats as u32)
+                .input(MainFlags::Variable | MainFlags::NullState)
+                .pop();
+            if self.hats == 1 {
+                rd.report_size(4)
+                    .report_count(1)
+                    .input(MainFlags::Constant.into());
+            }
+        }
+
+        if self.triggers {
+            rd.usage_page(0x02) /* Simulation Controls */
+                .usage(0xc5) /* Brake */
+                .usage(0xc4) /* Accelerator */
+                .logical_minimum(0)
+                .logical_maximum(self.resolution.max_trigger() as i32)
+                .report_size(self.resolution.bits())
+                .report_count(2)
+                .input(MainFlags::Variable.into());
+        }
+        rd.end_collection();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamps_and_packs_state() {
+        let mut gamepad = Gamepad::new(
+            1,
+            10,
+            &[GamepadAxis::X, GamepadAxis::Y],
+            AxisResolution::Bits16,
+        )
+        .with_hats(1)
+        .with_triggers();
+        let state = gamepad.state_mut();
+        state.set_button(9, true);
+        state.axes = vec![-40000, 300];
+        state.hats[0] = Hat::Left;
+        state.triggers = (70000, 5);
+        assert_eq!(
+            gamepad.report(),
+            vec![1, 0, 0x02, 0x01, 0x80, 0x2c, 0x01, 0x06, 0xff, 0xff, 5, 0]
+        );
+        assert!(gamepad.state().is_pressed(9));
+    }
+}
@@ -0,0 +1,665 @@
+//! A Logitech peripheral speaking HID++ 2.0. Requests come in short (7 bytes) or long (20 bytes)
+//! output reports and address a feature by its index in the feature table; Root and FeatureSet
+//! are built in and every other feature is a `Feature` implementation. Device name, battery
+//! status, reprogrammable controls and adjustable DPI are provided.
+
+use std::any::Any;
+use std::io::{self, prelude::*};
+
+use enumflags2::BitFlags;
+
+use crate::codec::{Bus, OutputEvent};
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::Preset;
+use crate::uhid_device::{CreateParams, UHIDDevice};
+
+const REPORT_SHORT: u8 = 0x10;
+const REPORT_LONG: u8 = 0x11;
+const SHORT_SIZE: usize = 7;
+pub const LONG_SIZE: usize = 20;
+/// Parameter bytes of a long report
+pub const MAX_PARAMETERS: usize = LONG_SIZE - 4;
+/// Feature index of HID++ 2.0 error replies
+const ERROR_INDEX: u8 = 0xff;
+/// Index of directly connected devices, as opposed to devices paired to a receiver
+pub const DIRECT_DEVICE_INDEX: u8 = 0xff;
+const PROTOCOL_VERSION: (u8, u8) = (4, 2);
+
+pub const ROOT: u16 = 0x0000;
+pub const FEATURE_SET: u16 = 0x0001;
+pub const DEVICE_NAME: u16 = 0x0005;
+pub const BATTERY_STATUS: u16 = 0x1000;
+pub const REPROG_CONTROLS_V4: u16 = 0x1b04;
+pub const ADJUSTABLE_DPI: u16 = 0x2201;
+
+/// Error codes of HID++ 2.0 error replies
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum HidppError {
+    Unknown = 0x01,
+    InvalidArgument = 0x02,
+    OutOfRange = 0x03,
+    HardwareError = 0x04,
+    InvalidFeatureIndex = 0x06,
+    InvalidFunction = 0x07,
+    Busy = 0x08,
+    Unsupported = 0x09,
+}
+
+/// Gives features a way to be found again by their type in `Peripheral::feature_mut`
+pub trait AsAny {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A feature of the feature table
+pub trait Feature: AsAny {
+    /// Feature ID, such as `BATTERY_STATUS`
+    fn id(&self) -> u16;
+
+    fn version(&self) -> u8 {
+        0
+    }
+
+    /// Obsolete (0x80), hidden (0x40) and engineering (0x20) flags
+    fn feature_type(&self) -> u8 {
+        0
+    }
+
+    /// Answers a call to `function` with the parameters of the reply, at most `MAX_PARAMETERS`
+    /// bytes. `parameters` has the padding of the request report.
+    fn call(&mut self, function: u8, parameters: &[u8]) -> Result<Vec<u8>, HidppError>;
+}
+
+fn parameter_u16(parameters: &[u8], offset: usize) -> Result<u16, HidppError> {
+    match parameters.get(offset..offset + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err(HidppError::InvalidArgument),
+    }
+}
+
+/// Kind of device returned by `DeviceName`
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum DeviceType {
+    Keyboard = 0,
+    RemoteControl = 1,
+    Numpad = 2,
+    Mouse = 3,
+    Trackpad = 4,
+    Trackball = 5,
+    Presenter = 6,
+    Receiver = 7,
+}
+
+/// Marketing name and type of the device (0x0005)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceName {
+    name: Vec<u8>,
+    device_type: DeviceType,
+}
+
+impl DeviceName {
+    pub fn new(name: &str, device_type: DeviceType) -> DeviceName {
+        DeviceName {
+            name: name.as_bytes().to_vec(),
+            device_type,
+        }
+    }
+}
+
+impl Feature for DeviceName {
+    fn id(&self) -> u16 {
+        DEVICE_NAME
+    }
+
+    fn call(&mut self, function: u8, parameters: &[u8]) -> Result<Vec<u8>, HidppError> {
+        match function {
+            0 => Ok(vec![self.name.len().min(u8::MAX as usize) as u8]),
+            1 => {
+                let start = *parameters.first().unwrap_or(&0) as usize;
+                if start >= self.name.len() {
+                    return Err(HidppError::OutOfRange);
+                }
+                let end = self.name.len().min(start + MAX_PARAMETERS);
+                Ok(self.name[start..end].to_vec())
+            }
+            2 => Ok(vec![self.device_type as u8]),
+            _ => Err(HidppError::InvalidFunction),
+        }
+    }
+}
+
+/// Charging state of `BatteryStatus`
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum BatteryState {
+    Discharging = 0,
+    Recharging = 1,
+    AlmostFull = 2,
+    Full = 3,
+    SlowRecharge = 4,
+    InvalidBattery = 5,
+    ThermalError = 6,
+}
+
+/// Levels reported by `BatteryStatus`, highest first
+const BATTERY_LEVELS: [u8; 4] = [100, 50, 20, 5];
+
+/// Battery level in percent and charging state (0x1000)
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatteryStatus {
+    level: u8,
+    state: BatteryState,
+}
+
+impl BatteryStatus {
+    pub fn new(level: u8) -> BatteryStatus {
+        BatteryStatus {
+            level: level.min(100),
+            state: BatteryState::Discharging,
+        }
+    }
+
+    pub fn set_level(&mut self, level: u8) {
+        self.level = level.min(100);
+    }
+
+    pub fn set_state(&mut self, state: BatteryState) {
+        self.state = state;
+    }
+
+    /// Level, next lower level and state, as sent by function 0 and by the battery event
+    pub fn level_status(&self) -> Vec<u8> {
+        let next = BATTERY_LEVELS
+            .iter()
+            .copied()
+            .find(|level| *level < self.level)
+            .unwrap_or(0);
+        vec![self.level, next, self.state as u8]
+    }
+}
+
+impl Feature for BatteryStatus {
+    fn id(&self) -> u16 {
+        BATTERY_STATUS
+    }
+
+    fn call(&mut self, function: u8, _parameters: &[u8]) -> Result<Vec<u8>, HidppError> {
+        match function {
+            0 => Ok(self.level_status()),
+            /* Number of levels, flags, nominal battery life, critical level */
+            1 => Ok(vec![BATTERY_LEVELS.len() as u8, 0, 0, 0, 5]),
+            _ => Err(HidppError::InvalidFunction),
+        }
+    }
+}
+
+/// Capabilities of a reprogrammable control
+#[derive(BitFlags, Copy, Clone, Debug, PartialEq)]
+#[repr(u8)]
+pub enum ControlFlags {
+    Mouse = 0b0000_0001,
+    FKey = 0b0000_0010,
+    HotKey = 0b0000_0100,
+    FnToggle = 0b0000_1000,
+    Reprogrammable = 0b0001_0000,
+    Divertable = 0b0010_0000,
+    Persistent = 0b0100_0000,
+    Virtual = 0b1000_0000,
+}
+
+/// A button known to `ReprogControls`, by control ID and task ID
+#[derive(Debug, Clone, PartialEq)]
+pub struct Control {
+    pub cid: u16,
+    pub task: u16,
+    pub flags: BitFlags<ControlFlags>,
+    diverted: bool,
+    remap: u16,
+}
+
+impl Control {
+    pub fn new(cid: u16, task: u16, flags: BitFlags<ControlFlags>) -> Control {
+        Control {
+            cid,
+            task,
+            flags,
+            diverted: false,
+            remap: cid,
+        }
+    }
+}
+
+const REPORTING_DIVERT: u8 = 0x01;
+const REPORTING_DIVERT_VALID: u8 = 0x02;
+
+/// Controls that software can divert to HID++ notifications or remap (0x1b04)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReprogControls {
+    controls: Vec<Control>,
+}
+
+impl ReprogControls {
+    pub fn new(controls: Vec<Control>) -> ReprogControls {
+        ReprogControls { controls }
+    }
+
+    pub fn is_diverted(&self, cid: u16) -> bool {
+        self.controls
+            .iter()
+            .any(|control| control.cid == cid && control.diverted)
+    }
+
+    /// Control the host remapped `cid` to, `cid` itself unless changed
+    pub fn remap(&self, cid: u16) -> Option<u16> {
+        self.controls
+            .iter()
+            .find(|control| control.cid == cid)
+            .map(|control| control.remap)
+    }
+
+    /// Parameters of the diverted buttons event (event 0) for the pressed controls, keeping the
+    /// first four that are diverted
+    pub fn diverted_buttons_event(&self, pressed: &[u16]) -> Vec<u8> {
+        let mut parameters = Vec::new();
+        for cid in pressed.iter().filter(|cid| self.is_diverted(**cid)).take(4) {
+            parameters.extend_from_slice(&cid.to_be_bytes());
+        }
+        parameters.resize(8, 0);
+        parameters
+    }
+
+    fn reporting(control: &Control) -> Vec<u8> {
+        let mut reply = control.cid.to_be_bytes().to_vec();
+        reply.push(if control.diverted {
+            REPORTING_DIVERT
+        } else {
+            0
+        });
+        reply.extend_from_slice(&control.remap.to_be_bytes());
+        reply
+    }
+}
+
+impl Feature for ReprogControls {
+    fn id(&self) -> u16 {
+        REPROG_CONTROLS_V4
+    }
+
+    fn version(&self) -> u8 {
+        4
+    }
+
+    fn call(&mut self, function: u8, parameters: &[u8]) -> Result<Vec<u8>, HidppError> {
+        match function {
+            0 => Ok(vec![self.controls.len() as u8]),
+            1 => {
+                let index = *parameters.first().unwrap_or(&0) as usize;
+                let control = self.controls.get(index).ok_or(HidppError::OutOfRange)?;
+                let mut reply = control.cid.to_be_bytes().to_vec();
+                reply.extend_from_slice(&control.task.to_be_bytes());
+                /* Flags, position, group, group mask, additional flags */
+                reply.extend_from_slice(&[control.flags.bits(), 0, 0, 0, 0]);
+                Ok(reply)
+            }
+            2 | 3 => {
+                let cid = parameter_u16(parameters, 0)?;
+                let control = self
+                    .controls
+                    .iter_mut()
+                    .find(|control| control.cid == cid)
+                    .ok_or(HidppError::InvalidArgument)?;
+                if function == 3 {
+                    let reporting = *parameters.get(2).unwrap_or(&0);
+                    if reporting & REPORTING_DIVERT_VALID != 0 {
+                        if !control.flags.contains(ControlFlags::Divertable) {
+                            return Err(HidppError::InvalidArgument);
+                        }
+                        control.diverted = reporting & REPORTING_DIVERT != 0;
+                    }
+                    match parameter_u16(parameters, 3)? {
+                        0 => {}
+                        remap => control.remap = remap,
+                    }
+                }
+                Ok(ReprogControls::reporting(control))
+            }
+            _ => Err(HidppError::InvalidFunction),
+        }
+    }
+}
+
+/// Resolution of a single sensor, chosen from a list of DPI values (0x2201)
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdjustableDpi {
+    supported: Vec<u16>,
+    default: u16,
+    dpi: u16,
+}
+
+impl AdjustableDpi {
+    /// A sensor at `default`, which is added to `supported` if missing
+    pub fn new(supported: &[u16], default: u16) -> AdjustableDpi {
+        let mut supported = supported.to_vec();
+        if !supported.contains(&default) {
+            supported.push(default);
+            supported.sort_unstable();
+        }
+        AdjustableDpi {
+            supported,
+            default,
+            dpi: default,
+        }
+    }
+
+    pub fn dpi(&self) -> u16 {
+        self.dpi
+    }
+}
+
+impl Feature for AdjustableDpi {
+    fn id(&self) -> u16 {
+        ADJUSTABLE_DPI
+    }
+
+    fn call(&mut self, function: u8, parameters: &[u8]) -> Result<Vec<u8>, HidppError> {
+        if function > 0 && parameters.first().copied().unwrap_or(0) != 0 {
+            return Err(HidppError::InvalidArgument);
+        }
+        match function {
+            0 => Ok(vec![1]),
+            1 => {
+                /* Zero terminated, truncated to the long report */
+                let mut reply = vec![0];
+                for dpi in self.supported.iter().take((MAX_PARAMETERS - 3) / 2) {
+                    reply.extend_from_slice(&dpi.to_be_bytes());
+                }
+                reply.extend_from_slice(&[0, 0]);
+                Ok(reply)
+            }
+            2 => {
+                let mut reply = vec![0];
+                reply.extend_from_slice(&self.dpi.to_be_bytes());
+                reply.extend_from_slice(&self.default.to_be_bytes());
+                Ok(reply)
+            }
+            3 => {
+                let dpi = parameter_u16(parameters, 1)?;
+                if !self.supported.contains(&dpi) {
+                    return Err(HidppError::InvalidArgument);
+                }
+                self.dpi = dpi;
+                let mut reply = vec![0];
+                reply.extend_from_slice(&dpi.to_be_bytes());
+                Ok(reply)
+            }
+            _ => Err(HidppError::InvalidFunction),
+        }
+    }
+}
+
+/// A HID++ 2.0 device answering requests addressed to its device index. Replies are long
+/// reports echoing the feature index, function and software ID of the request. Short and long
+/// reports use report IDs 0x10 and 0x11, so it is meant to be the only preset of its device.
+pub struct Peripheral {
+    device_index: u8,
+    features: Vec<Box<dyn Feature>>,
+}
+
+impl Peripheral {
+    /// A directly connected device with Root and FeatureSet only
+    pub fn new() -> Peripheral {
+        Peripheral {
+            device_index: DIRECT_DEVICE_INDEX,
+            features: Vec::new(),
+        }
+    }
+
+    /// Answers as the device paired at `index` of a receiver instead
+    pub fn with_device_index(mut self, index: u8) -> Peripheral {
+        self.device_index = index;
+        self
+    }
+
+    /// Appends a feature to the table, at the next index
+    pub fn with_feature<F: Feature + 'static>(mut self, feature: F) -> Peripheral {
+        self.features.push(Box::new(feature));
+        self
+    }
+
+    /// Parameters for a Logitech USB device with this descriptor
+    pub fn create_params(&self, name: &str, product: u32) -> CreateParams {
+        CreateParams {
+            name: name.to_string(),
+            phys: "".to_string(),
+            uniq: "".to_string(),
+            bus: Bus::USB,
+            vendor: 0x046d,
+            product,
+            version: 0,
+            country: 0,
+            rd_data: self.descriptor(),
+        }
+    }
+
+    /// Index of the feature in the table, Root being 0 and FeatureSet 1
+    pub fn feature_index(&self, id: u16) -> Option<u8> {
+        match id {
+            ROOT => Some(0),
+            FEATURE_SET => Some(1),
+            _ => self
+                .features
+                .iter()
+                .position(|feature| feature.id() == id)
+                .map(|position| position as u8 + 2),
+        }
+    }
+
+    /// The first feature of type `F`, to change its state
+    pub fn feature_mut<F: Feature + 'static>(&mut self) -> Option<&mut F> {
+        self.features
+            .iter_mut()
+            .find_map(|feature| feature.as_mut().as_any_mut().downcast_mut::<F>())
+    }
+
+    fn feature_set(&self, function: u8, parameters: &[u8]) -> Result<Vec<u8>, HidppError> {
+        match function {
+            /* Root is not counted */
+            0 => Ok(vec![self.features.len() as u8 + 1]),
+            1 => {
+                let index = *parameters.first().unwrap_or(&0) as usize;
+                let (id, feature_type, version) = match index {
+                    0 => (ROOT, 0, 0),
+                    1 => (FEATURE_SET, 0, 0),
+                    _ => {
+                        let feature = self.features.get(index - 2).ok_or(HidppError::OutOfRange)?;
+                        (feature.id(), feature.feature_type(), feature.version())
+                    }
+                };
+                let mut reply = id.to_be_bytes().to_vec();
+                reply.extend_from_slice(&[feature_type, version]);
+                Ok(reply)
+            }
+            _ => Err(HidppError::InvalidFunction),
+        }
+    }
+
+    fn root(&self, function: u8, parameters: &[u8]) -> Result<Vec<u8>, HidppError> {
+        match function {
+            0 => {
+                let id = parameter_u16(parameters, 0)?;
+                /* Unknown features have index 0 */
+                let (index, feature_type, version) = match self.feature_index(id) {
+                    Some(index) if index >= 2 => {
+                        let feature = &self.features[index as usize - 2];
+                        (index, feature.feature_type(), feature.version())
+                    }
+                    Some(index) => (index, 0, 0),
+                    None => (0, 0, 0),
+                };
+                Ok(vec![index, feature_type, version])
+            }
+            1 => Ok(vec![
+                PROTOCOL_VERSION.0,
+                PROTOCOL_VERSION.1,
+                *parameters.get(2).unwrap_or(&0),
+            ]),
+            _ => Err(HidppError::InvalidFunction),
+        }
+    }
+
+    /// Answers a short or long request report, `None` if it is not addressed to this device
+    fn process(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let size = match *request.first()? {
+            REPORT_SHORT => SHORT_SIZE,
+            REPORT_LONG => LONG_SIZE,
+            _ => return None,
+        };
+        if request.len() < size || request[1] != self.device_index {
+            return None;
+        }
+        let (index, function_and_software) = (request[2], request[3]);
+        let function = function_and_software >> 4;
+        let parameters = &request[4..size];
+
+        let result = match index {
+            0 => self.root(function, parameters),
+            1 => self.feature_set(function, parameters),
+            _ => match self.features.get_mut(index as usize - 2) {
+                Some(feature) => feature.call(function, parameters),
+                None => Err(HidppError::InvalidFeatureIndex),
+            },
+        };
+        let mut reply = match result {
+            Ok(mut parameters) => {
+                parameters.truncate(MAX_PARAMETERS);
+                let mut reply = vec![REPORT_LONG, self.device_index, index];
+                reply.push(function_and_software);
+                reply.extend(parameters);
+                reply
+            }
+            Err(error) => vec![
+                REPORT_LONG,
+                self.device_index,
+                ERROR_INDEX,
+                index,
+                function_and_software,
+                error as u8,
+            ],
+        };
+        reply.resize(LONG_SIZE, 0);
+        Some(reply)
+    }
+
+    /// Sends an unsolicited event of feature `id`, such as the battery or diverted buttons
+    /// event, with a software ID of 0
+    pub fn send_event<T: Read + Write>(
+        &self,
+        device: &mut UHIDDevice<T>,
+        id: u16,
+        event: u8,
+        parameters: &[u8],
+    ) -> io::Result<usize> {
+        let index = self.feature_index(id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no HID++ feature {:#06x}", id),
+            )
+        })?;
+        let mut report = vec![REPORT_LONG, self.device_index, index, event << 4];
+        report.extend_from_slice(&parameters[..parameters.len().min(MAX_PARAMETERS)]);
+        report.resize(LONG_SIZE, 0);
+        device.write(&report)
+    }
+}
+
+impl Default for Peripheral {
+    fn default() -> Peripheral {
+        Peripheral::new()
+    }
+}
+
+impl Preset for Peripheral {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        for (usage, report_id, size) in [
+            (0x01, REPORT_SHORT, SHORT_SIZE),
+            (0x02, REPORT_LONG, LONG_SIZE),
+        ] {
+            rd.usage_page(0xff00) /* Vendor-defined */
+                .usage(usage)
+                .collection(Collection::Application)
+                .report_id(report_id)
+                .logical_minimum(0)
+                .logical_maximum(u8::MAX as i32)
+                .report_size(8)
+                .report_count(size as u32 - 1)
+                .usage(usage)
+                .input(MainFlags::Variable.into())
+                .usage(usage)
+                .output(MainFlags::Variable.into())
+                .end_collection();
+        }
+    }
+
+    fn handle<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        event: &OutputEvent,
+    ) -> io::Result<bool> {
+        let data = match event {
+            OutputEvent::Output { data } => data,
+            _ => return Ok(false),
+        };
+        match self.process(data) {
+            Some(reply) => {
+                device.write(&reply)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_features_and_calls_them() {
+        let mut peripheral = Peripheral::new()
+            .with_feature(DeviceName::new("Virtual Mouse", DeviceType::Mouse))
+            .with_feature(AdjustableDpi::new(&[400, 800, 1600], 800));
+
+        /* Root getFeature(AdjustableDpi) in a short report */
+        let reply = peripheral
+            .process(&[REPORT_SHORT, 0xff, 0, 0x0a, 0x22, 0x01, 0])
+            .unwrap();
+        assert_eq!(&reply[..7], &[REPORT_LONG, 0xff, 0, 0x0a, 3, 0, 0]);
+
+        let mut request = vec![REPORT_LONG, 0xff, 3, 0x3a, 0, 0x06, 0x40];
+        request.resize(LONG_SIZE, 0);
+        let reply = peripheral.process(&request).unwrap();
+        assert_eq!(&reply[..7], &[REPORT_LONG, 0xff, 3, 0x3a, 0, 0x06, 0x40]);
+        assert_eq!(
+            peripheral.feature_mut::<AdjustableDpi>().unwrap().dpi(),
+            1600
+        );
+
+        request[5] = 0x01;
+        let reply = peripheral.process(&request).unwrap();
+        assert_eq!(
+            &reply[..6],
+            &[REPORT_LONG, 0xff, ERROR_INDEX, 3, 0x3a, 0x02]
+        );
+        let reply = peripheral
+            .process(&[REPORT_SHORT, 0xff, 9, 0x0a, 0, 0, 0])
+            .unwrap();
+        assert_eq!(reply[5], HidppError::InvalidFeatureIndex as u8);
+    }
+}
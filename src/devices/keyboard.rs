@@ -0,0 +1,632 @@
+use std::convert::TryFrom;
+use std::io::{self, prelude::*};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use enumflags2::BitFlags;
+
+use crate::codec::OutputEvent;
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::{Chord, Layout, LayoutError, Preset};
+use crate::uhid_device::UHIDDevice;
+
+/// First and last usage covered by the N-key rollover bitmap
+const NKRO_FIRST_USAGE: u8 = 0x04;
+const NKRO_LAST_USAGE: u8 = 0xe7;
+const NKRO_USAGES: usize = (NKRO_LAST_USAGE - NKRO_FIRST_USAGE) as usize + 1;
+const NKRO_BITMAP_SIZE: usize = NKRO_USAGES.div_ceil(8);
+const BOOT_KEY_SLOTS: usize = 6;
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Usages of the Keyboard/Keypad page (0x07).
+/// See section 10 of the HID Usage Tables
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Key {
+    A = 0x04,
+    B = 0x05,
+    C = 0x06,
+    D = 0x07,
+    E = 0x08,
+    F = 0x09,
+    G = 0x0a,
+    H = 0x0b,
+    I = 0x0c,
+    J = 0x0d,
+    K = 0x0e,
+    L = 0x0f,
+    M = 0x10,
+    N = 0x11,
+    O = 0x12,
+    P = 0x13,
+    Q = 0x14,
+    R = 0x15,
+    S = 0x16,
+    T = 0x17,
+    U = 0x18,
+    V = 0x19,
+    W = 0x1a,
+    X = 0x1b,
+    Y = 0x1c,
+    Z = 0x1d,
+    Num1 = 0x1e,
+    Num2 = 0x1f,
+    Num3 = 0x20,
+    Num4 = 0x21,
+    Num5 = 0x22,
+    Num6 = 0x23,
+    Num7 = 0x24,
+    Num8 = 0x25,
+    Num9 = 0x26,
+    Num0 = 0x27,
+    Enter = 0x28,
+    Escape = 0x29,
+    Backspace = 0x2a,
+    Tab = 0x2b,
+    Space = 0x2c,
+    Minus = 0x2d,
+    Equal = 0x2e,
+    LeftBracket = 0x2f,
+    RightBracket = 0x30,
+    Backslash = 0x31,
+    NonUsHash = 0x32,
+    Semicolon = 0x33,
+    Apostrophe = 0x34,
+    Grave = 0x35,
+    Comma = 0x36,
+    Dot = 0x37,
+    Slash = 0x38,
+    CapsLock = 0x39,
+    F1 = 0x3a,
+    F2 = 0x3b,
+    F3 = 0x3c,
+    F4 = 0x3d,
+    F5 = 0x3e,
+    F6 = 0x3f,
+    F7 = 0x40,
+    F8 = 0x41,
+    F9 = 0x42,
+    F10 = 0x43,
+    F11 = 0x44,
+    F12 = 0x45,
+    PrintScreen = 0x46,
+    ScrollLock = 0x47,
+    Pause = 0x48,
+    Insert = 0x49,
+    Home = 0x4a,
+    PageUp = 0x4b,
+    Delete = 0x4c,
+    End = 0x4d,
+    PageDown = 0x4e,
+    Right = 0x4f,
+    Left = 0x50,
+    Down = 0x51,
+    Up = 0x52,
+    NumLock = 0x53,
+    KpSlash = 0x54,
+    KpAsterisk = 0x55,
+    KpMinus = 0x56,
+    KpPlus = 0x57,
+    KpEnter = 0x58,
+    Kp1 = 0x59,
+    Kp2 = 0x5a,
+    Kp3 = 0x5b,
+    Kp4 = 0x5c,
+    Kp5 = 0x5d,
+    Kp6 = 0x5e,
+    Kp7 = 0x5f,
+    Kp8 = 0x60,
+    Kp9 = 0x61,
+    Kp0 = 0x62,
+    KpDot = 0x63,
+    NonUsBackslash = 0x64,
+    Application = 0x65,
+    F13 = 0x68,
+    F14 = 0x69,
+    F15 = 0x6a,
+    F16 = 0x6b,
+    F17 = 0x6c,
+    F18 = 0x6d,
+    F19 = 0x6e,
+    F20 = 0x6f,
+    F21 = 0x70,
+    F22 = 0x71,
+    F23 = 0x72,
+    F24 = 0x73,
+    LeftCtrl = 0xe0,
+    LeftShift = 0xe1,
+    LeftAlt = 0xe2,
+    LeftMeta = 0xe3,
+    RightCtrl = 0xe4,
+    RightShift = 0xe5,
+    RightAlt = 0xe6,
+    RightMeta = 0xe7,
+}
+
+impl Key {
+    pub fn is_modifier(self) -> bool {
+        self >= Key::LeftCtrl
+    }
+}
+
+/// LED usages (page 0x08) the kernel sets through output reports
+#[derive(BitFlags, Copy, Clone, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Led {
+    NumLock = 0b0000_0001,
+    CapsLock = 0b0000_0010,
+    ScrollLock = 0b0000_0100,
+    Compose = 0b0000_1000,
+    Kana = 0b0001_0000,
+}
+
+/// Which of the two keyboard reports is sent
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Protocol {
+    /// Modifier byte plus up to six keys, as understood by firmware
+    Boot,
+    /// One bit per usage, any number of keys at once
+    Report,
+}
+
+/// An N-key rollover keyboard. Key state is kept as a bitmap over usages 0x04 to 0xE7, which
+/// includes the modifiers, so chords of any size reach the kernel intact.
+/// An optional second collection carries the classic 6-key boot report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyboard {
+    report_id: u8,
+    boot_report_id: Option<u8>,
+    protocol: Protocol,
+    bitmap: [u8; NKRO_BITMAP_SIZE],
+    layout: Layout,
+    typing_delay: Duration,
+}
+
+impl Keyboard {
+    /// A keyboard with only the N-key rollover report
+    pub fn new(report_id: u8) -> Keyboard {
+        Keyboard {
+            report_id,
+            boot_report_id: None,
+            protocol: Protocol::Report,
+            bitmap: [0; NKRO_BITMAP_SIZE],
+            layout: Layout::us(),
+            typing_delay: Duration::from_millis(5),
+        }
+    }
+
+    /// A keyboard that also describes the boot-protocol report under `boot_report_id`
+    pub fn with_boot_protocol(report_id: u8, boot_report_id: u8) -> Keyboard {
+        Keyboard {
+            boot_report_id: Some(boot_report_id),
+            ..Keyboard::new(report_id)
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Switches the report that is sent. Selecting `Protocol::Boot` has no effect unless the
+    /// keyboard was created `with_boot_protocol`.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        if protocol == Protocol::Report || self.boot_report_id.is_some() {
+            self.protocol = protocol;
+        }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// The layout `type_text` assumes the host is configured with, US by default
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    /// Pause after every report sent by `type_text`, 5ms by default
+    pub fn set_typing_delay(&mut self, delay: Duration) {
+        self.typing_delay = delay;
+    }
+
+    pub fn is_pressed(&self, key: Key) -> bool {
+        let (byte, bit) = Self::bit_position(key as u8);
+        self.bitmap[byte] & bit != 0
+    }
+
+    /// Keys currently held, in usage order
+    pub fn pressed(&self) -> Vec<Key> {
+        (NKRO_FIRST_USAGE..=NKRO_LAST_USAGE)
+            .filter_map(|usage| Key::try_from(usage).ok())
+            .filter(|key| self.is_pressed(*key))
+            .collect()
+    }
+
+    fn bit_position(usage: u8) -> (usize, u8) {
+        let index = (usage - NKRO_FIRST_USAGE) as usize;
+        (index / 8, 1 << (index % 8))
+    }
+
+    fn set_key(&mut self, key: Key, pressed: bool) {
+        let (byte, bit) = Self::bit_position(key as u8);
+        if pressed {
+            self.bitmap[byte] |= bit;
+        } else {
+            self.bitmap[byte] &= !bit;
+        }
+    }
+
+    /// Encodes the current key state for the active protocol, report ID first
+    pub fn report(&self) -> Vec<u8> {
+        match (self.protocol, self.boot_report_id) {
+            (Protocol::Boot, Some(boot_report_id)) => self.boot_report(boot_report_id).to_vec(),
+            _ => {
+                let mut report = Vec::with_capacity(NKRO_BITMAP_SIZE + 1);
+                report.push(self.report_id);
+                report.extend_from_slice(&self.bitmap);
+                report
+            }
+        }
+    }
+
+    fn boot_report(&self, boot_report_id: u8) -> [u8; 2 + BOOT_KEY_SLOTS + 1] {
+        let mut report = [0; 2 + BOOT_KEY_SLOTS + 1];
+        report[0] = boot_report_id;
+        let pressed = self.pressed();
+        let (modifiers, keys): (Vec<Key>, Vec<Key>) =
+            pressed.into_iter().partition(|key| key.is_modifier());
+        report[1] = modifiers
+            .iter()
+            .fold(0, |acc, key| acc | 1 << (*key as u8 - Key::LeftCtrl as u8));
+        if keys.len() > BOOT_KEY_SLOTS {
+            report[3..].iter_mut().for_each(|x| *x = ERROR_ROLL_OVER);
+        } else {
+            keys.iter()
+                .enumerate()
+                .for_each(|(i, key)| report[3 + i] = *key as u8);
+        }
+        report
+    }
+
+    /// Holds a key down and sends the new state
+    pub fn press<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        key: Key,
+    ) -> io::Result<usize> {
+        self.set_key(key, true);
+        device.write(&self.report())
+    }
+
+    pub fn release<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        key: Key,
+    ) -> io::Result<usize> {
+        self.set_key(key, false);
+        device.write(&self.report())
+    }
+
+    /// Presses and releases a key in two consecutive reports
+    pub fn tap<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        key: Key,
+    ) -> io::Result<usize> {
+        self.press(device, key)?;
+        self.release(device, key)
+    }
+
+    /// Replaces the held keys with `keys` in a single report, e.g. a stenography chord
+    pub fn set_keys<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        keys: &[Key],
+    ) -> io::Result<usize> {
+        self.bitmap = [0; NKRO_BITMAP_SIZE];
+        keys.iter().for_each(|key| self.set_key(*key, true));
+        device.write(&self.report())
+    }
+
+    pub fn release_all<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+    ) -> io::Result<usize> {
+        self.set_keys(device, &[])
+    }
+
+    /// Types `text` according to the current layout. Every character is checked first, so an
+    /// unsupported one fails without sending anything. Keys held before are released.
+    pub fn type_text<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        text: &str,
+    ) -> Result<(), LayoutError> {
+        let strokes = text
+            .chars()
+            .map(|c| {
+                self.layout
+                    .strokes(c)
+                    .map(<[Chord]>::to_vec)
+                    .ok_or(LayoutError::UnsupportedCharacter(c))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.release_all(device)?;
+        for chord in strokes.iter().flatten() {
+            self.type_chord(device, chord)?;
+        }
+        Ok(())
+    }
+
+    /// Modifiers go out in a report of their own, since the kernel walks the bitmap in usage
+    /// order and would otherwise see the key before the modifiers
+    fn type_chord<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        chord: &Chord,
+    ) -> io::Result<()> {
+        let mut keys = chord.modifiers.clone();
+        if !keys.is_empty() {
+            self.set_keys(device, &keys)?;
+            thread::sleep(self.typing_delay);
+        }
+        keys.push(chord.key);
+        self.set_keys(device, &keys)?;
+        thread::sleep(self.typing_delay);
+        keys.pop();
+        if !keys.is_empty() {
+            self.set_keys(device, &keys)?;
+            thread::sleep(self.typing_delay);
+        }
+        self.release_all(device)?;
+        thread::sleep(self.typing_delay);
+        Ok(())
+    }
+
+    /// Decodes the LED state if `event` is an output report addressed to this keyboard
+    pub fn leds(&self, event: &OutputEvent) -> Option<BitFlags<Led>> {
+        match event {
+            OutputEvent::Output { data } if data.len() >= 2 && data[0] == self.report_id => {
+                Some(BitFlags::from_bits_truncate(data[1]))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Preset for Keyboard {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.usage_page(0x01) /* Generic Desktop */
+            .usage(0x06) /* Keyboard */
+            .collection(Collection::Application)
+            .report_id(self.report_id)
+            .usage_page(0x07) /* Keyboard/Keypad */
+            .usage_minimum(NKRO_FIRST_USAGE as u32)
+            .usage_maximum(NKRO_LAST_USAGE as u32)
+            .logical_minimum(0)
+            .logical_maximum(1)
+            .report_size(1)
+            .report_count(NKRO_USAGES as u32)
+            .input(MainFlags::Variable.into())
+            .report_count(1)
+            .report_size((NKRO_BITMAP_SIZE * 8 - NKRO_USAGES) as u32)
+            .input(MainFlags::Constant.into())
+            .usage_page(0x08) /* LEDs */
+            .usage_minimum(0x01)
+            .usage_maximum(0x05)
+            .report_count(5)
+            .report_size(1)
+            .output(MainFlags::Variable.into())
+            .report_count(1)
+            .report_size(3)
+            .output(MainFlags::Constant.into())
+            .end_collection();
+
+        if let Some(boot_report_id) = self.boot_report_id {
+            rd.usage_page(0x01) /* Generic Desktop */
+                .usage(0x06) /* Keyboard */
+                .collection(Collection::Application)
+                .report_id(boot_report_id)
+                .usage_page(0x07) /* Keyboard/Keypad */
+                .usage_minimum(Key::LeftCtrl as u32)
+                .usage_maximum(Key::RightMeta as u32)
+                .logical_minimum(0)
+                .logical_maximum(1)
+                .report_size(1)
+                .report_count(8)
+                .input(MainFlags::Variable.into())
+                .report_count(1)
+                .report_size(8)
+                .input(MainFlags::Constant.into())
+                .usage_minimum(0x00)
+                .usage_maximum(0xdd)
+                .logical_maximum(0xdd)
+                .report_count(BOOT_KEY_SLOTS as u32)
+                .input(BitFlags::empty())
+                .end_collection();
+        }
+    }
+}
+
+impl TryFrom<u8> for Key {
+    type Error = u8;
+    fn try_from(usage: u8) -> Result<Self, Self::Error> {
+        match usage {
+            0x04..=0x65 | 0x68..=0x73 | 0xe0..=0xe7 => {
+                Ok(unsafe { std::mem::transmute::<u8, Key>(usage) })
+            }
+            _ => Err(usage),
+        }
+    }
+}
+
+/// Parses the variant name, ignoring case, e.g. "LeftShift" or "kp1"
+impl FromStr for Key {
+    type Err = String;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "a" => Key::A,
+            "b" => Key::B,
+            "c" => Key::C,
+            "d" => Key::D,
+            "e" => Key::E,
+            "f" => Key::F,
+            "g" => Key::G,
+            "h" => Key::H,
+            "i" => Key::I,
+            "j" => Key::J,
+            "k" => Key::K,
+            "l" => Key::L,
+            "m" => Key::M,
+            "n" => Key::N,
+            "o" => Key::O,
+            "p" => Key::P,
+            "q" => Key::Q,
+            "r" => Key::R,
+            "s" => Key::S,
+            "t" => Key::T,
+            "u" => Key::U,
+            "v" => Key::V,
+            "w" => Key::W,
+            "x" => Key::X,
+            "y" => Key::Y,
+            "z" => Key::Z,
+            "num1" => Key::Num1,
+            "num2" => Key::Num2,
+            "num3" => Key::Num3,
+            "num4" => Key::Num4,
+            "num5" => Key::Num5,
+            "num6" => Key::Num6,
+            "num7" => Key::Num7,
+            "num8" => Key::Num8,
+            "num9" => Key::Num9,
+            "num0" => Key::Num0,
+            "enter" => Key::Enter,
+            "escape" => Key::Escape,
+            "backspace" => Key::Backspace,
+            "tab" => Key::Tab,
+            "space" => Key::Space,
+            "minus" => Key::Minus,
+            "equal" => Key::Equal,
+            "leftbracket" => Key::LeftBracket,
+            "rightbracket" => Key::RightBracket,
+            "backslash" => Key::Backslash,
+            "nonushash" => Key::NonUsHash,
+            "semicolon" => Key::Semicolon,
+            "apostrophe" => Key::Apostrophe,
+            "grave" => Key::Grave,
+            "comma" => Key::Comma,
+            "dot" => Key::Dot,
+            "slash" => Key::Slash,
+            "capslock" => Key::CapsLock,
+            "f1" => Key::F1,
+            "f2" => Key::F2,
+            "f3" => Key::F3,
+            "f4" => Key::F4,
+            "f5" => Key::F5,
+            "f6" => Key::F6,
+            "f7" => Key::F7,
+            "f8" => Key::F8,
+            "f9" => Key::F9,
+            "f10" => Key::F10,
+            "f11" => Key::F11,
+            "f12" => Key::F12,
+            "printscreen" => Key::PrintScreen,
+            "scrolllock" => Key::ScrollLock,
+            "pause" => Key::Pause,
+            "insert" => Key::Insert,
+            "home" => Key::Home,
+            "pageup" => Key::PageUp,
+            "delete" => Key::Delete,
+            "end" => Key::End,
+            "pagedown" => Key::PageDown,
+            "right" => Key::Right,
+            "left" => Key::Left,
+            "down" => Key::Down,
+            "up" => Key::Up,
+            "numlock" => Key::NumLock,
+            "kpslash" => Key::KpSlash,
+            "kpasterisk" => Key::KpAsterisk,
+            "kpminus" => Key::KpMinus,
+            "kpplus" => Key::KpPlus,
+            "kpenter" => Key::KpEnter,
+            "kp1" => Key::Kp1,
+            "kp2" => Key::Kp2,
+            "kp3" => Key::Kp3,
+            "kp4" => Key::Kp4,
+            "kp5" => Key::Kp5,
+            "kp6" => Key::Kp6,
+            "kp7" => Key::Kp7,
+            "kp8" => Key::Kp8,
+            "kp9" => Key::Kp9,
+            "kp0" => Key::Kp0,
+            "kpdot" => Key::KpDot,
+            "nonusbackslash" => Key::NonUsBackslash,
+            "application" => Key::Application,
+            "f13" => Key::F13,
+            "f14" => Key::F14,
+            "f15" => Key::F15,
+            "f16" => Key::F16,
+            "f17" => Key::F17,
+            "f18" => Key::F18,
+            "f19" => Key::F19,
+            "f20" => Key::F20,
+            "f21" => Key::F21,
+            "f22" => Key::F22,
+            "f23" => Key::F23,
+            "f24" => Key::F24,
+            "leftctrl" => Key::LeftCtrl,
+            "leftshift" => Key::LeftShift,
+            "leftalt" => Key::LeftAlt,
+            "leftmeta" => Key::LeftMeta,
+            "rightctrl" => Key::RightCtrl,
+            "rightshift" => Key::RightShift,
+            "rightalt" => Key::RightAlt,
+            "rightmeta" => Key::RightMeta,
+            _ => return Err(name.to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nkro_report_holds_every_key() {
+        let mut keyboard = Keyboard::new(1);
+        let chord = [
+            Key::S,
+            Key::T,
+            Key::K,
+            Key::P,
+            Key::W,
+            Key::H,
+            Key::R,
+            Key::LeftShift,
+        ];
+        chord.iter().for_each(|key| keyboard.set_key(*key, true));
+
+        let report = keyboard.report();
+        assert_eq!(report.len(), NKRO_BITMAP_SIZE + 1);
+        assert_eq!(report[0], 1);
+        assert_eq!(keyboard.pressed().len(), chord.len());
+        /* LeftShift is usage 0xe1, bit 221 of the bitmap */
+        assert_eq!(report[1 + 221 / 8], 1 << (221 % 8));
+    }
+
+    #[test]
+    fn boot_report_rolls_over() {
+        let mut keyboard = Keyboard::with_boot_protocol(1, 2);
+        keyboard.set_protocol(Protocol::Boot);
+        keyboard.set_key(Key::LeftCtrl, true);
+        keyboard.set_key(Key::C, true);
+        assert_eq!(keyboard.report(), vec![2, 0x01, 0, 0x06, 0, 0, 0, 0, 0]);
+
+        [Key::A, Key::B, Key::D, Key::E, Key::F, Key::G]
+            .iter()
+            .for_each(|key| keyboard.set_key(*key, true));
+        assert_eq!(keyboard.report(), vec![2, 0x01, 0, 1, 1, 1, 1, 1, 1]);
+    }
+}
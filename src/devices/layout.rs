@@ -0,0 +1,577 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::devices::Key;
+
+/// Keys held together to produce one keystroke, modifiers first
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chord {
+    pub modifiers: Vec<Key>,
+    pub key: Key,
+}
+
+impl Chord {
+    pub fn new(modifiers: &[Key], key: Key) -> Chord {
+        Chord {
+            modifiers: modifiers.to_vec(),
+            key,
+        }
+    }
+}
+
+/// Loading a layout can fail on IO or on a malformed line. Typing fails if the layout has no way
+/// to produce a character, before anything is sent.
+#[derive(Debug)]
+pub enum LayoutError {
+    Io(io::Error),
+    Parse { line: usize, message: String },
+    UnsupportedCharacter(char),
+}
+
+impl From<io::Error> for LayoutError {
+    fn from(err: io::Error) -> Self {
+        LayoutError::Io(err)
+    }
+}
+
+/// Maps characters to the keystrokes that type them on a host configured with the same layout.
+/// Characters reached through a dead key map to two chords, the dead key and the base key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    name: String,
+    strokes: HashMap<char, Vec<Chord>>,
+}
+
+const SHIFT: Key = Key::LeftShift;
+const ALTGR: Key = Key::RightAlt;
+
+/// Each string lists the plain and shifted character of a key, `\0` marks a level without one
+type KeyTable = &'static [(Key, &'static str)];
+
+struct DeadKey {
+    key: Key,
+    shift: bool,
+    accent: char,
+    /// Pairs of base and composed characters
+    compositions: &'static str,
+}
+
+const QWERTY_LETTERS: KeyTable = &[
+    (Key::Q, "qQ"),
+    (Key::W, "wW"),
+    (Key::E, "eE"),
+    (Key::R, "rR"),
+    (Key::T, "tT"),
+    (Key::Y, "yY"),
+    (Key::U, "uU"),
+    (Key::I, "iI"),
+    (Key::O, "oO"),
+    (Key::P, "pP"),
+    (Key::A, "aA"),
+    (Key::S, "sS"),
+    (Key::D, "dD"),
+    (Key::F, "fF"),
+    (Key::G, "gG"),
+    (Key::H, "hH"),
+    (Key::J, "jJ"),
+    (Key::K, "kK"),
+    (Key::L, "lL"),
+    (Key::Z, "zZ"),
+    (Key::X, "xX"),
+    (Key::C, "cC"),
+    (Key::V, "vV"),
+    (Key::B, "bB"),
+    (Key::N, "nN"),
+    (Key::M, "mM"),
+];
+
+const US_DIGITS: KeyTable = &[
+    (Key::Grave, "`~"),
+    (Key::Num1, "1!"),
+    (Key::Num2, "2@"),
+    (Key::Num3, "3#"),
+    (Key::Num4, "4$"),
+    (Key::Num5, "5%"),
+    (Key::Num6, "6^"),
+    (Key::Num7, "7&"),
+    (Key::Num8, "8*"),
+    (Key::Num9, "9("),
+    (Key::Num0, "0)"),
+];
+
+const US_SYMBOLS: KeyTable = &[
+    (Key::Minus, "-_"),
+    (Key::Equal, "=+"),
+    (Key::LeftBracket, "[{"),
+    (Key::RightBracket, "]}"),
+    (Key::Backslash, "\\|"),
+    (Key::Semicolon, ";:"),
+    (Key::Apostrophe, "'\""),
+    (Key::Comma, ",<"),
+    (Key::Dot, ".>"),
+    (Key::Slash, "/?"),
+];
+
+const UK_SYMBOLS: KeyTable = &[
+    (Key::Grave, "`¬"),
+    (Key::Num1, "1!"),
+    (Key::Num2, "2\""),
+    (Key::Num3, "3£"),
+    (Key::Num4, "4$"),
+    (Key::Num5, "5%"),
+    (Key::Num6, "6^"),
+    (Key::Num7, "7&"),
+    (Key::Num8, "8*"),
+    (Key::Num9, "9("),
+    (Key::Num0, "0)"),
+    (Key::Minus, "-_"),
+    (Key::Equal, "=+"),
+    (Key::LeftBracket, "[{"),
+    (Key::RightBracket, "]}"),
+    (Key::Semicolon, ";:"),
+    (Key::Apostrophe, "'@"),
+    (Key::NonUsHash, "#~"),
+    (Key::NonUsBackslash, "\\|"),
+    (Key::Comma, ",<"),
+    (Key::Dot, ".>"),
+    (Key::Slash, "/?"),
+];
+
+const UK_ALTGR: KeyTable = &[
+    (Key::Grave, "¦\0"),
+    (Key::Num4, "€\0"),
+    (Key::A, "áÁ"),
+    (Key::E, "éÉ"),
+    (Key::I, "íÍ"),
+    (Key::O, "óÓ"),
+    (Key::U, "úÚ"),
+];
+
+const DE_KEYS: KeyTable = &[
+    (Key::Grave, "\0°"),
+    (Key::Num1, "1!"),
+    (Key::Num2, "2\""),
+    (Key::Num3, "3§"),
+    (Key::Num4, "4$"),
+    (Key::Num5, "5%"),
+    (Key::Num6, "6&"),
+    (Key::Num7, "7/"),
+    (Key::Num8, "8("),
+    (Key::Num9, "9)"),
+    (Key::Num0, "0="),
+    (Key::Minus, "ß?"),
+    (Key::Q, "qQ"),
+    (Key::W, "wW"),
+    (Key::E, "eE"),
+    (Key::R, "rR"),
+    (Key::T, "tT"),
+    (Key::Y, "zZ"),
+    (Key::U, "uU"),
+    (Key::I, "iI"),
+    (Key::O, "oO"),
+    (Key::P, "pP"),
+    (Key::LeftBracket, "üÜ"),
+    (Key::RightBracket, "+*"),
+    (Key::A, "aA"),
+    (Key::S, "sS"),
+    (Key::D, "dD"),
+    (Key::F, "fF"),
+    (Key::G, "gG"),
+    (Key::H, "hH"),
+    (Key::J, "jJ"),
+    (Key::K, "kK"),
+    (Key::L, "lL"),
+    (Key::Semicolon, "öÖ"),
+    (Key::Apostrophe, "äÄ"),
+    (Key::NonUsHash, "#'"),
+    (Key::NonUsBackslash, "<>"),
+    (Key::Z, "yY"),
+    (Key::X, "xX"),
+    (Key::C, "cC"),
+    (Key::V, "vV"),
+    (Key::B, "bB"),
+    (Key::N, "nN"),
+    (Key::M, "mM"),
+    (Key::Comma, ",;"),
+    (Key::Dot, ".:"),
+    (Key::Slash, "-_"),
+];
+
+const DE_ALTGR: KeyTable = &[
+    (Key::Num2, "²\0"),
+    (Key::Num3, "³\0"),
+    (Key::Num7, "{\0"),
+    (Key::Num8, "[\0"),
+    (Key::Num9, "]\0"),
+    (Key::Num0, "}\0"),
+    (Key::Minus, "\\\0"),
+    (Key::Q, "@\0"),
+    (Key::E, "€\0"),
+    (Key::RightBracket, "~\0"),
+    (Key::NonUsBackslash, "|\0"),
+    (Key::M, "µ\0"),
+];
+
+const DE_DEAD_KEYS: &[DeadKey] = &[
+    DeadKey {
+        key: Key::Grave,
+        shift: false,
+        accent: '^',
+        compositions: "aâeêiîoôuûAÂEÊIÎOÔUÛ",
+    },
+    DeadKey {
+        key: Key::Equal,
+        shift: false,
+        accent: '´',
+        compositions: "aáeéiíoóuúyýAÁEÉIÍOÓUÚYÝ",
+    },
+    DeadKey {
+        key: Key::Equal,
+        shift: true,
+        accent: '`',
+        compositions: "aàeèiìoòuùAÀEÈIÌOÒUÙ",
+    },
+];
+
+const FR_KEYS: KeyTable = &[
+    (Key::Grave, "²\0"),
+    (Key::Num1, "&1"),
+    (Key::Num2, "é2"),
+    (Key::Num3, "\"3"),
+    (Key::Num4, "'4"),
+    (Key::Num5, "(5"),
+    (Key::Num6, "-6"),
+    (Key::Num7, "è7"),
+    (Key::Num8, "_8"),
+    (Key::Num9, "ç9"),
+    (Key::Num0, "à0"),
+    (Key::Minus, ")°"),
+    (Key::Equal, "=+"),
+    (Key::Q, "aA"),
+    (Key::W, "zZ"),
+    (Key::E, "eE"),
+    (Key::R, "rR"),
+    (Key::T, "tT"),
+    (Key::Y, "yY"),
+    (Key::U, "uU"),
+    (Key::I, "iI"),
+    (Key::O, "oO"),
+    (Key::P, "pP"),
+    (Key::RightBracket, "$£"),
+    (Key::A, "qQ"),
+    (Key::S, "sS"),
+    (Key::D, "dD"),
+    (Key::F, "fF"),
+    (Key::G, "gG"),
+    (Key::H, "hH"),
+    (Key::J, "jJ"),
+    (Key::K, "kK"),
+    (Key::L, "lL"),
+    (Key::Semicolon, "mM"),
+    (Key::Apostrophe, "ù%"),
+    (Key::NonUsHash, "*µ"),
+    (Key::NonUsBackslash, "<>"),
+    (Key::Z, "wW"),
+    (Key::X, "xX"),
+    (Key::C, "cC"),
+    (Key::V, "vV"),
+    (Key::B, "bB"),
+    (Key::N, "nN"),
+    (Key::M, ",?"),
+    (Key::Comma, ";."),
+    (Key::Dot, ":/"),
+    (Key::Slash, "!§"),
+];
+
+const FR_ALTGR: KeyTable = &[
+    (Key::Num2, "~\0"),
+    (Key::Num3, "#\0"),
+    (Key::Num4, "{\0"),
+    (Key::Num5, "[\0"),
+    (Key::Num6, "|\0"),
+    (Key::Num7, "`\0"),
+    (Key::Num8, "\\\0"),
+    (Key::Num9, "^\0"),
+    (Key::Num0, "@\0"),
+    (Key::Minus, "]\0"),
+    (Key::Equal, "}\0"),
+    (Key::E, "€\0"),
+    (Key::RightBracket, "¤\0"),
+];
+
+const FR_DEAD_KEYS: &[DeadKey] = &[
+    DeadKey {
+        key: Key::LeftBracket,
+        shift: false,
+        accent: '^',
+        compositions: "aâeêiîoôuûAÂEÊIÎOÔUÛ",
+    },
+    DeadKey {
+        key: Key::LeftBracket,
+        shift: true,
+        accent: '¨',
+        compositions: "aäeëiïoöuüyÿAÄEËIÏOÖUÜ",
+    },
+];
+
+const DVORAK_KEYS: KeyTable = &[
+    (Key::Minus, "[{"),
+    (Key::Equal, "]}"),
+    (Key::Q, "'\""),
+    (Key::W, ",<"),
+    (Key::E, ".>"),
+    (Key::R, "pP"),
+    (Key::T, "yY"),
+    (Key::Y, "fF"),
+    (Key::U, "gG"),
+    (Key::I, "cC"),
+    (Key::O, "rR"),
+    (Key::P, "lL"),
+    (Key::LeftBracket, "/?"),
+    (Key::RightBracket, "=+"),
+    (Key::Backslash, "\\|"),
+    (Key::A, "aA"),
+    (Key::S, "oO"),
+    (Key::D, "eE"),
+    (Key::F, "uU"),
+    (Key::G, "iI"),
+    (Key::H, "dD"),
+    (Key::J, "hH"),
+    (Key::K, "tT"),
+    (Key::L, "nN"),
+    (Key::Semicolon, "sS"),
+    (Key::Apostrophe, "-_"),
+    (Key::Z, ";:"),
+    (Key::X, "qQ"),
+    (Key::C, "jJ"),
+    (Key::V, "kK"),
+    (Key::B, "xX"),
+    (Key::N, "bB"),
+    (Key::M, "mM"),
+    (Key::Comma, "wW"),
+    (Key::Dot, "vV"),
+    (Key::Slash, "zZ"),
+];
+
+impl Layout {
+    /// An empty layout that only types space, tab and newline
+    pub fn new(name: &str) -> Layout {
+        let mut layout = Layout {
+            name: name.to_string(),
+            strokes: HashMap::new(),
+        };
+        layout.insert(' ', vec![Chord::new(&[], Key::Space)]);
+        layout.insert('\t', vec![Chord::new(&[], Key::Tab)]);
+        layout.insert('\n', vec![Chord::new(&[], Key::Enter)]);
+        layout
+    }
+
+    fn from_tables(
+        name: &str,
+        keys: &[KeyTable],
+        altgr: KeyTable,
+        dead_keys: &[DeadKey],
+    ) -> Layout {
+        let mut layout = Layout::new(name);
+        for (key, levels) in keys.iter().flat_map(|table| table.iter()) {
+            layout.add_levels(*key, levels, &[], &[SHIFT]);
+        }
+        for (key, levels) in altgr.iter() {
+            layout.add_levels(*key, levels, &[ALTGR], &[SHIFT, ALTGR]);
+        }
+        for dead_key in dead_keys {
+            let prefix = if dead_key.shift {
+                Chord::new(&[SHIFT], dead_key.key)
+            } else {
+                Chord::new(&[], dead_key.key)
+            };
+            let compositions: Vec<char> = dead_key.compositions.chars().collect();
+            for pair in compositions.chunks(2) {
+                if let Some(base) = layout.strokes(pair[0]) {
+                    let mut strokes = vec![prefix.clone()];
+                    strokes.extend_from_slice(base);
+                    layout.insert_missing(pair[1], strokes);
+                }
+            }
+            /* A dead key followed by space produces the accent itself */
+            layout.insert_missing(dead_key.accent, vec![prefix, Chord::new(&[], Key::Space)]);
+        }
+        layout
+    }
+
+    fn add_levels(&mut self, key: Key, levels: &str, plain: &[Key], shifted: &[Key]) {
+        let mut chars = levels.chars();
+        if let Some(c) = chars.next().filter(|c| *c != '\0') {
+            self.insert_missing(c, vec![Chord::new(plain, key)]);
+        }
+        if let Some(c) = chars.next().filter(|c| *c != '\0') {
+            self.insert_missing(c, vec![Chord::new(shifted, key)]);
+        }
+    }
+
+    fn insert_missing(&mut self, c: char, strokes: Vec<Chord>) {
+        self.strokes.entry(c).or_insert(strokes);
+    }
+
+    /// US English QWERTY
+    pub fn us() -> Layout {
+        Layout::from_tables("us", &[QWERTY_LETTERS, US_DIGITS, US_SYMBOLS], &[], &[])
+    }
+
+    /// British English QWERTY
+    pub fn uk() -> Layout {
+        Layout::from_tables("gb", &[QWERTY_LETTERS, UK_SYMBOLS], UK_ALTGR, &[])
+    }
+
+    /// German QWERTZ with dead circumflex, acute and grave
+    pub fn de() -> Layout {
+        Layout::from_tables("de", &[DE_KEYS], DE_ALTGR, DE_DEAD_KEYS)
+    }
+
+    /// French AZERTY with dead circumflex and diaeresis
+    pub fn fr() -> Layout {
+        Layout::from_tables("fr", &[FR_KEYS], FR_ALTGR, FR_DEAD_KEYS)
+    }
+
+    /// US Dvorak
+    pub fn dvorak() -> Layout {
+        Layout::from_tables("us(dvorak)", &[US_DIGITS, DVORAK_KEYS], &[], &[])
+    }
+
+    /// Reads a custom layout, see `Layout::parse` for the format
+    pub fn load(path: &Path) -> Result<Layout, LayoutError> {
+        Layout::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses a custom layout. Every line holds a character followed by the chords that type it,
+    /// separated by whitespace. A chord joins key names with `+`, the last one being the key and
+    /// the others modifiers; `Shift`, `AltGr`, `Ctrl`, `Alt` and `Meta` are accepted as
+    /// shorthands. Characters can also be written as `U+00E9`, which is required for space and `#`.
+    /// Empty lines and lines starting with `#` are skipped, and `name <name>` sets the name.
+    ///
+    /// ```text
+    /// name de-custom
+    /// z    Y
+    /// @    AltGr+Q
+    /// é    Equal E
+    /// U+0023 NonUsHash
+    /// ```
+    pub fn parse(source: &str) -> Result<Layout, LayoutError> {
+        let mut layout = Layout::new("custom");
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let error = |message: String| LayoutError::Parse {
+                line: line_number,
+                message,
+            };
+            let mut tokens = line.split_whitespace();
+            let first = match tokens.next() {
+                Some(token) if !token.starts_with('#') => token,
+                _ => continue,
+            };
+            if first == "name" {
+                layout.name = tokens.collect::<Vec<_>>().join(" ");
+                continue;
+            }
+            let c =
+                parse_char(first).ok_or_else(|| error(format!("invalid character '{}'", first)))?;
+            let strokes = tokens
+                .map(|token| {
+                    parse_chord(token).map_err(|name| error(format!("unknown key '{}'", name)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if strokes.is_empty() {
+                return Err(error(format!("no keys given for '{}'", first)));
+            }
+            layout.insert(c, strokes);
+        }
+        Ok(layout)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The chords that type `c`, in order
+    pub fn strokes(&self, c: char) -> Option<&[Chord]> {
+        self.strokes.get(&c).map(Vec::as_slice)
+    }
+
+    /// Adds or replaces the chords for a character
+    pub fn insert(&mut self, c: char, strokes: Vec<Chord>) {
+        self.strokes.insert(c, strokes);
+    }
+}
+
+fn parse_char(token: &str) -> Option<char> {
+    if let Some(hex) = token.strip_prefix("U+") {
+        return u32::from_str_radix(hex, 16)
+            .ok()
+            .and_then(std::char::from_u32);
+    }
+    let mut chars = token.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
+
+fn parse_chord(token: &str) -> Result<Chord, String> {
+    let mut keys = token
+        .split('+')
+        .map(|name| match name.to_ascii_lowercase().as_str() {
+            "shift" => Ok(Key::LeftShift),
+            "altgr" => Ok(Key::RightAlt),
+            "ctrl" => Ok(Key::LeftCtrl),
+            "alt" => Ok(Key::LeftAlt),
+            "meta" => Ok(Key::LeftMeta),
+            _ => name.parse(),
+        })
+        .collect::<Result<Vec<Key>, String>>()?;
+    let key = keys.pop().ok_or_else(String::new)?;
+    Ok(Chord {
+        modifiers: keys,
+        key,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dead_keys_compose() {
+        let de = Layout::de();
+        assert_eq!(de.strokes('z'), Some(&[Chord::new(&[], Key::Y)][..]));
+        assert_eq!(
+            de.strokes('è'),
+            Some(&[Chord::new(&[SHIFT], Key::Equal), Chord::new(&[], Key::E)][..])
+        );
+        assert_eq!(
+            de.strokes('^'),
+            Some(&[Chord::new(&[], Key::Grave), Chord::new(&[], Key::Space)][..])
+        );
+        assert_eq!(Layout::us().strokes('ö'), None);
+    }
+
+    #[test]
+    fn parses_custom_layout() {
+        let layout =
+            Layout::parse("# test\nname neo test\nU+0023 Shift+Num3\né AltGr+E\n").unwrap();
+        assert_eq!(layout.name(), "neo test");
+        assert_eq!(
+            layout.strokes('#'),
+            Some(&[Chord::new(&[SHIFT], Key::Num3)][..])
+        );
+        assert_eq!(
+            layout.strokes('é'),
+            Some(&[Chord::new(&[ALTGR], Key::E)][..])
+        );
+        assert!(matches!(
+            Layout::parse("x Nope"),
+            Err(LayoutError::Parse { line: 1, .. })
+        ));
+    }
+}
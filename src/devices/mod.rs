@@ -0,0 +1,68 @@
+//! Ready-made virtual devices. Each preset generates its own report descriptor and encodes input
+//! reports, while the `UHIDDevice` they are sent through stays owned by the caller. This way
+//! several presets can share one device as long as their report IDs differ.
+
+mod battery;
+mod control;
+mod dualsense;
+mod force_feedback;
+mod gamepad;
+mod keyboard;
+mod layout;
+mod mouse;
+mod multitouch;
+mod pen;
+mod pointer;
+mod rog;
+mod sensor;
+mod switch_pro;
+mod ups;
+
+pub mod fido;
+pub mod hidpp;
+
+pub use battery::*;
+pub use control::*;
+pub use dualsense::*;
+pub use force_feedback::*;
+pub use gamepad::*;
+pub use keyboard::*;
+pub use layout::*;
+pub use mouse::*;
+pub use multitouch::*;
+pub use pen::*;
+pub use pointer::*;
+pub use rog::*;
+pub use sensor::*;
+pub use switch_pro::*;
+pub use ups::*;
+
+use std::io::{self, prelude::*};
+
+use crate::codec::OutputEvent;
+use crate::descriptor::ReportDescriptorBuilder;
+use crate::uhid_device::UHIDDevice;
+
+/// A building block of a virtual HID device
+pub trait Preset {
+    /// Appends the top-level collections of this preset to a report descriptor
+    fn describe(&self, rd: &mut ReportDescriptorBuilder);
+
+    /// A report descriptor containing only this preset, ready for `CreateParams::rd_data`
+    fn descriptor(&self) -> Vec<u8> {
+        let mut rd = ReportDescriptorBuilder::new();
+        self.describe(&mut rd);
+        rd.build()
+    }
+
+    /// Reacts to an output event read from the device, typically by answering a GET_REPORT or
+    /// SET_REPORT request for one of this preset's feature reports.
+    /// Returns whether the event was meant for this preset.
+    fn handle<T: Read + Write>(
+        &mut self,
+        _device: &mut UHIDDevice<T>,
+        _event: &OutputEvent,
+    ) -> io::Result<bool> {
+        Ok(false)
+    }
+}
@@ -0,0 +1,299 @@
+use std::io::{self, prelude::*};
+
+use crate::codec::{OutputEvent, ReportType};
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::Preset;
+use crate::uhid_device::UHIDDevice;
+
+const MAX_MOTION: i32 = i16::MAX as i32;
+const MAX_WHEEL: i32 = i8::MAX as i32;
+/// Physical maximum of both Resolution Multiplier fields
+const WHEEL_MULTIPLIER: i32 = 8;
+/// Hi-res wheel values are in fractions of a notch, like REL_WHEEL_HI_RES
+const HI_RES_PER_NOTCH: i32 = 120;
+
+/// Buttons 1 to 8 of the Button page, named after the evdev codes the kernel maps them to
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum MouseButton {
+    Left = 0b0000_0001,
+    Right = 0b0000_0010,
+    Middle = 0b0000_0100,
+    Side = 0b0000_1000,
+    Extra = 0b0001_0000,
+    Forward = 0b0010_0000,
+    Back = 0b0100_0000,
+    Task = 0b1000_0000,
+}
+
+/// A relative mouse with eight buttons, 16-bit motion and both scroll wheels.
+/// Each wheel sits in a logical collection with a Resolution Multiplier feature field; once the
+/// kernel enables the multiplier through SET_REPORT, one notch is sent as eight wheel units.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mouse {
+    report_id: u8,
+    buttons: u8,
+    vertical_hi_res: bool,
+    horizontal_hi_res: bool,
+    /// Hi-res scroll amounts too small for a whole wheel unit, carried to the next report
+    vertical_remainder: i32,
+    horizontal_remainder: i32,
+}
+
+impl Mouse {
+    pub fn new(report_id: u8) -> Mouse {
+        Mouse {
+            report_id,
+            buttons: 0,
+            vertical_hi_res: false,
+            horizontal_hi_res: false,
+            vertical_remainder: 0,
+            horizontal_remainder: 0,
+        }
+    }
+
+    /// Wheel units per notch of the vertical and horizontal wheel, as negotiated with the host
+    pub fn resolution_multipliers(&self) -> (i32, i32) {
+        let multiplier = |hi_res| if hi_res { WHEEL_MULTIPLIER } else { 1 };
+        (
+            multiplier(self.vertical_hi_res),
+            multiplier(self.horizontal_hi_res),
+        )
+    }
+
+    fn report(&self, x: i16, y: i16, wheel: i8, pan: i8) -> [u8; 8] {
+        let x = x.to_le_bytes();
+        let y = y.to_le_bytes();
+        [
+            self.report_id,
+            self.buttons,
+            x[0],
+            x[1],
+            y[0],
+            y[1],
+            wheel as u8,
+            pan as u8,
+        ]
+    }
+
+    fn feature_report(&self) -> [u8; 2] {
+        [
+            self.report_id,
+            self.vertical_hi_res as u8 | (self.horizontal_hi_res as u8) << 2,
+        ]
+    }
+
+    /// Converts hi-res scroll amounts to wheel and pan units, keeping what is left over
+    fn wheel_units(&mut self, vertical: i32, horizontal: i32) -> (i32, i32) {
+        let (vertical_multiplier, horizontal_multiplier) = self.resolution_multipliers();
+        let units = |remainder: &mut i32, amount: i32, multiplier: i32| {
+            let total = *remainder + amount * multiplier;
+            *remainder = total % HI_RES_PER_NOTCH;
+            total / HI_RES_PER_NOTCH
+        };
+        (
+            units(&mut self.vertical_remainder, vertical, vertical_multiplier),
+            units(
+                &mut self.horizontal_remainder,
+                horizontal,
+                horizontal_multiplier,
+            ),
+        )
+    }
+
+    /// Sends motion, wheel and pan deltas, split over as many reports as their ranges require
+    fn send_deltas<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        mut dx: i32,
+        mut dy: i32,
+        mut wheel: i32,
+        mut pan: i32,
+    ) -> io::Result<usize> {
+        let mut written = 0;
+        loop {
+            let step = |remaining: &mut i32, max: i32| {
+                let value = (*remaining).max(-max).min(max);
+                *remaining -= value;
+                value
+            };
+            let report = self.report(
+                step(&mut dx, MAX_MOTION) as i16,
+                step(&mut dy, MAX_MOTION) as i16,
+                step(&mut wheel, MAX_WHEEL) as i8,
+                step(&mut pan, MAX_WHEEL) as i8,
+            );
+            written += device.write(&report)?;
+            if dx == 0 && dy == 0 && wheel == 0 && pan == 0 {
+                return Ok(written);
+            }
+        }
+    }
+
+    /// Moves the pointer by any distance, splitting it into reports of at most 32767 counts
+    pub fn move_by<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        dx: i32,
+        dy: i32,
+    ) -> io::Result<usize> {
+        self.send_deltas(device, dx, dy, 0, 0)
+    }
+
+    pub fn press<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        button: MouseButton,
+    ) -> io::Result<usize> {
+        self.buttons |= button as u8;
+        self.send_deltas(device, 0, 0, 0, 0)
+    }
+
+    pub fn release<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        button: MouseButton,
+    ) -> io::Result<usize> {
+        self.buttons &= !(button as u8);
+        self.send_deltas(device, 0, 0, 0, 0)
+    }
+
+    /// Presses and releases a button in two consecutive reports
+    pub fn click<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        button: MouseButton,
+    ) -> io::Result<usize> {
+        Ok(self.press(device, button)? + self.release(device, button)?)
+    }
+
+    /// Scrolls by whole notches. Positive values scroll up and to the right.
+    pub fn scroll<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        vertical: i32,
+        horizontal: i32,
+    ) -> io::Result<usize> {
+        self.scroll_hi_res(
+            device,
+            vertical * HI_RES_PER_NOTCH,
+            horizontal * HI_RES_PER_NOTCH,
+        )
+    }
+
+    /// Scrolls in 1/120ths of a notch. Without high-resolution scrolling enabled by the host, or
+    /// below the resolution of the multiplier, the rest is kept until it adds up to a wheel unit.
+    pub fn scroll_hi_res<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        vertical: i32,
+        horizontal: i32,
+    ) -> io::Result<usize> {
+        let (wheel, pan) = self.wheel_units(vertical, horizontal);
+        if wheel == 0 && pan == 0 {
+            return Ok(0);
+        }
+        self.send_deltas(device, 0, 0, wheel, pan)
+    }
+}
+
+impl Preset for Mouse {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        let wheel = |rd: &mut ReportDescriptorBuilder, usage_page: u16, usage: u32| {
+            rd.collection(Collection::Logical)
+                .usage_page(0x01) /* Generic Desktop */
+                .usage(0x48) /* Resolution Multiplier */
+                .logical_minimum(0)
+                .logical_maximum(1)
+                .physical_minimum(1)
+                .physical_maximum(WHEEL_MULTIPLIER)
+                .report_size(2)
+                .report_count(1)
+                .feature(MainFlags::Variable.into())
+                .usage_page(usage_page)
+                .usage(usage)
+                .logical_minimum(-MAX_WHEEL)
+                .logical_maximum(MAX_WHEEL)
+                .physical_minimum(0)
+                .physical_maximum(0)
+                .report_size(8)
+                .input(MainFlags::Variable | MainFlags::Relative)
+                .end_collection();
+        };
+
+        rd.usage_page(0x01) /* Generic Desktop */
+            .usage(0x02) /* Mouse */
+            .collection(Collection::Application)
+            .report_id(self.report_id)
+            .usage(0x01) /* Pointer */
+            .collection(Collection::Physical)
+            .usage_page(0x09) /* Button */
+            .usage_minimum(1)
+            .usage_maximum(8)
+            .logical_minimum(0)
+            .logical_maximum(1)
+            .report_size(1)
+            .report_count(8)
+            .input(MainFlags::Variable.into())
+            .usage_page(0x01) /* Generic Desktop */
+            .usage(0x30) /* X */
+            .usage(0x31) /* Y */
+            .logical_minimum(-MAX_MOTION)
+            .logical_maximum(MAX_MOTION)
+            .report_size(16)
+            .report_count(2)
+            .input(MainFlags::Variable | MainFlags::Relative);
+        wheel(rd, 0x01, 0x38); /* Generic Desktop, Wheel */
+        wheel(rd, 0x0c, 0x0238); /* Consumer, AC Pan */
+        rd.report_size(4)
+            .report_count(1)
+            .feature(MainFlags::Constant.into())
+            .end_collection()
+            .end_collection();
+    }
+
+    fn handle<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        event: &OutputEvent,
+    ) -> io::Result<bool> {
+        match event {
+            OutputEvent::GetReport {
+                id,
+                report_number,
+                report_type: ReportType::Feature,
+            } if *report_number == self.report_id => {
+                device.write_get_report_reply(*id, 0, self.feature_report().to_vec())?;
+                Ok(true)
+            }
+            OutputEvent::SetReport {
+                id,
+                report_number,
+                report_type: ReportType::Feature,
+                data,
+            } if *report_number == self.report_id => {
+                let multipliers = data.get(1).copied().unwrap_or(0);
+                self.vertical_hi_res = multipliers & 0b0011 != 0;
+                self.horizontal_hi_res = multipliers & 0b1100 != 0;
+                device.write_set_report_reply(*id, 0)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hi_res_scroll_keeps_remainder() {
+        let mut mouse = Mouse::new(1);
+        mouse.vertical_hi_res = true;
+        assert_eq!(mouse.wheel_units(120, 120), (8, 1));
+        assert_eq!(mouse.wheel_units(10, 60), (0, 0));
+        assert_eq!(mouse.wheel_units(10, 60), (1, 1));
+        assert_eq!(mouse.report(-300, 2, 1, 0), [1, 0, 0xd4, 0xfe, 2, 0, 1, 0]);
+    }
+}
@@ -0,0 +1,384 @@
+use std::io::{self, prelude::*};
+use std::time::Instant;
+
+use crate::codec::{OutputEvent, ReportType};
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::{Preset, Screen};
+use crate::uhid_device::UHIDDevice;
+
+const TIP_SWITCH: u8 = 0b01;
+const CONFIDENCE: u8 = 0b10;
+/// Flags, contact identifier, X and Y
+const CONTACT_SIZE: usize = 6;
+/// Input Mode value selecting multi-touch reports on a touchpad
+pub const INPUT_MODE_TOUCHPAD: u8 = 3;
+
+/// Contact identifier, whether it touches and its position
+type ContactEntry = (u8, bool, (u16, u16));
+
+/// A multi-touch digitizer handled by the kernel's hid-multitouch driver, either a touchscreen or
+/// a precision touchpad. The input report and the Contact Count Maximum feature report use
+/// `report_id`; a touchpad also has a Device Configuration collection with the Input Mode
+/// feature report under `report_id + 1`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiTouch {
+    report_id: u8,
+    touchpad: bool,
+    screen: Screen,
+    max_contacts: u8,
+    /// Contacts touching after the last frame, with their last position
+    active: Vec<(u8, (u16, u16))>,
+    button: bool,
+    input_mode: u8,
+    surface_switch: bool,
+    button_switch: bool,
+    epoch: Instant,
+}
+
+/// Contacts of one frame, sent together by `Frame::send`
+pub struct Frame<'a, T: Read + Write> {
+    touch: &'a mut MultiTouch,
+    device: &'a mut UHIDDevice<T>,
+    contacts: Vec<(u8, (u16, u16))>,
+    button: bool,
+}
+
+impl MultiTouch {
+    fn new(
+        report_id: u8,
+        touchpad: bool,
+        screen: Screen,
+        max_contacts: u8,
+    ) -> io::Result<MultiTouch> {
+        if report_id == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "multitouch needs a nonzero report ID",
+            ));
+        }
+        if touchpad && report_id == u8::MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "touchpad report ID {} leaves no room for Input Mode",
+                    report_id
+                ),
+            ));
+        }
+        Ok(MultiTouch {
+            report_id,
+            touchpad,
+            screen,
+            max_contacts: max_contacts.max(1),
+            active: Vec::new(),
+            button: false,
+            input_mode: 0,
+            surface_switch: true,
+            button_switch: true,
+            epoch: Instant::now(),
+        })
+    }
+
+    /// A touchscreen tracking up to `max_contacts` fingers. Fails for report ID 0, as every
+    /// report starts with its report ID.
+    pub fn touchscreen(report_id: u8, screen: Screen, max_contacts: u8) -> io::Result<MultiTouch> {
+        MultiTouch::new(report_id, false, screen, max_contacts)
+    }
+
+    /// A clickpad tracking up to `max_contacts` fingers. Coordinates are scaled from the
+    /// `width` and `height` of `surface`. Fails for report IDs 0 and 255, as the Input Mode
+    /// report needs the next report ID.
+    pub fn touchpad(report_id: u8, surface: Screen, max_contacts: u8) -> io::Result<MultiTouch> {
+        MultiTouch::new(report_id, true, surface, max_contacts)
+    }
+
+    /// The Input Mode last set by the host, `INPUT_MODE_TOUCHPAD` once hid-multitouch took over
+    pub fn input_mode(&self) -> u8 {
+        self.input_mode
+    }
+
+    /// Starts a frame. Contacts touching in the previous frame but left out of this one are lifted.
+    pub fn frame<'a, T: Read + Write>(&'a mut self, device: &'a mut UHIDDevice<T>) -> Frame<'a, T> {
+        let button = self.button;
+        Frame {
+            touch: self,
+            device,
+            contacts: Vec::new(),
+            button,
+        }
+    }
+
+    /// Time since creation in the 100µs units of the Scan Time field
+    fn scan_time(&self) -> u16 {
+        (self.epoch.elapsed().as_micros() / 100) as u16
+    }
+
+    /// Encodes a frame. When more contacts change than one report has slots for, the rest follow
+    /// in further reports whose Contact Count is zero, as in the hybrid mode of the Windows
+    /// touch specification.
+    fn frame_reports(
+        &mut self,
+        contacts: &[(u8, (u16, u16))],
+        button: bool,
+        scan_time: u16,
+    ) -> Vec<Vec<u8>> {
+        let mut entries: Vec<ContactEntry> = contacts
+            .iter()
+            .map(|(id, position)| (*id, true, *position))
+            .collect();
+        for (id, position) in self.active.iter() {
+            if !contacts.iter().any(|(contact, _)| contact == id) {
+                entries.push((*id, false, *position));
+            }
+        }
+        self.active = contacts.to_vec();
+        self.button = button;
+
+        let slots = self.max_contacts as usize;
+        let mut chunks: Vec<&[ContactEntry]> = entries.chunks(slots).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut report = vec![self.report_id];
+                for slot in 0..slots {
+                    match chunk.get(slot) {
+                        Some((id, touching, (x, y))) => {
+                            let mut flags = if *touching { TIP_SWITCH } else { 0 };
+                            if self.touchpad {
+                                flags |= CONFIDENCE;
+                            }
+                            report.extend_from_slice(&[flags, *id]);
+                            report.extend_from_slice(&x.to_le_bytes());
+                            report.extend_from_slice(&y.to_le_bytes());
+                        }
+                        None => report.extend_from_slice(&[0; CONTACT_SIZE]),
+                    }
+                }
+                report.extend_from_slice(&scan_time.to_le_bytes());
+                report.push(if i == 0 { entries.len() as u8 } else { 0 });
+                if self.touchpad {
+                    report.push(button as u8);
+                }
+                report
+            })
+            .collect()
+    }
+
+    fn describe_contact(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.usage(0x22) /* Finger */
+            .collection(Collection::Logical)
+            .logical_minimum(0)
+            .logical_maximum(1)
+            .report_size(1)
+            .report_count(1)
+            .usage(0x42) /* Tip Switch */
+            .input(MainFlags::Variable.into());
+        if self.touchpad {
+            rd.usage(0x47) /* Confidence */
+                .input(MainFlags::Variable.into())
+                .report_count(6);
+        } else {
+            rd.report_count(7);
+        }
+        rd.input(MainFlags::Constant.into())
+            .usage(0x51) /* Contact Identifier */
+            .logical_maximum(u8::MAX as i32)
+            .report_size(8)
+            .report_count(1)
+            .input(MainFlags::Variable.into());
+        self.screen.describe_axes(rd);
+        rd.end_collection();
+    }
+
+    fn feature_report(&self, report_number: u8) -> Option<Vec<u8>> {
+        if report_number == self.report_id {
+            Some(vec![self.report_id, self.max_contacts])
+        } else if self.touchpad && report_number == self.report_id + 1 {
+            let switches = self.surface_switch as u8 | (self.button_switch as u8) << 1;
+            Some(vec![report_number, self.input_mode, switches])
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, T: Read + Write> Frame<'a, T> {
+    /// Adds a touching contact at a position scaled from the screen or surface size
+    pub fn contact(mut self, id: u8, x: u32, y: u32) -> Self {
+        let position = self.touch.screen.scale(x, y);
+        self.contacts.retain(|(contact, _)| *contact != id);
+        self.contacts.push((id, position));
+        self
+    }
+
+    /// Sets whether the touchpad is clicked down. Ignored by touchscreens.
+    pub fn button(mut self, pressed: bool) -> Self {
+        self.button = pressed;
+        self
+    }
+
+    pub fn send(self) -> io::Result<usize> {
+        let scan_time = self.touch.scan_time();
+        let reports = self
+            .touch
+            .frame_reports(&self.contacts, self.button, scan_time);
+        let mut written = 0;
+        for report in reports {
+            written += self.device.write(&report)?;
+        }
+        Ok(written)
+    }
+}
+
+impl Preset for MultiTouch {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.usage_page(0x0d) /* Digitizer */
+            .usage(if self.touchpad { 0x05 } else { 0x04 }) /* Touch Pad or Touch Screen */
+            .collection(Collection::Application)
+            .report_id(self.report_id);
+        for _ in 0..self.max_contacts {
+            self.describe_contact(rd);
+        }
+        rd.push()
+            .usage(0x56) /* Scan Time */
+            .logical_minimum(0)
+            .logical_maximum(u16::MAX as i32)
+            .unit_exponent(-4)
+            .unit(0x1001) /* Seconds */
+            .report_size(16)
+            .report_count(1)
+            .input(MainFlags::Variable.into())
+            .pop()
+            .usage(0x54) /* Contact Count */
+            .logical_maximum(i8::MAX as i32)
+            .report_size(8)
+            .report_count(1)
+            .input(MainFlags::Variable.into());
+        if self.touchpad {
+            rd.usage_page(0x09) /* Button */
+                .usage(0x01)
+                .logical_maximum(1)
+                .report_size(1)
+                .report_count(1)
+                .input(MainFlags::Variable.into())
+                .report_count(7)
+                .input(MainFlags::Constant.into())
+                .usage_page(0x0d); /* Digitizer */
+        }
+        rd.usage(0x55) /* Contact Count Maximum */
+            .logical_maximum(self.max_contacts as i32)
+            .report_size(8)
+            .report_count(1)
+            .feature(MainFlags::Variable.into())
+            .end_collection();
+
+        if self.touchpad {
+            rd.usage(0x0e) /* Device Configuration */
+                .collection(Collection::Application)
+                .report_id(self.report_id + 1)
+                .usage(0x22) /* Finger */
+                .collection(Collection::Logical)
+                .usage(0x52) /* Input Mode */
+                .logical_maximum(10)
+                .report_size(8)
+                .report_count(1)
+                .feature(MainFlags::Variable.into())
+                .usage(0x57) /* Surface Switch */
+                .usage(0x58) /* Button Switch */
+                .logical_maximum(1)
+                .report_size(1)
+                .report_count(2)
+                .feature(MainFlags::Variable.into())
+                .report_count(6)
+                .feature(MainFlags::Constant.into())
+                .end_collection()
+                .end_collection();
+        }
+    }
+
+    fn handle<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        event: &OutputEvent,
+    ) -> io::Result<bool> {
+        match event {
+            OutputEvent::GetReport {
+                id,
+                report_number,
+                report_type: ReportType::Feature,
+            } => match self.feature_report(*report_number) {
+                Some(report) => {
+                    device.write_get_report_reply(*id, 0, report)?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+            OutputEvent::SetReport {
+                id,
+                report_number,
+                report_type: ReportType::Feature,
+                data,
+            } if self.feature_report(*report_number).is_some() => {
+                if *report_number != self.report_id {
+                    self.input_mode = data.get(1).copied().unwrap_or(0);
+                    let switches = data.get(2).copied().unwrap_or(0b11);
+                    self.surface_switch = switches & 0b01 != 0;
+                    self.button_switch = switches & 0b10 != 0;
+                }
+                device.write_set_report_reply(*id, 0)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifted_contacts_spill_into_hybrid_reports() {
+        let screen = Screen {
+            width: 100,
+            height: 100,
+            width_mm: 100,
+            height_mm: 100,
+        };
+        let mut touch = MultiTouch::touchscreen(1, screen, 2).unwrap();
+
+        let reports = touch.frame_reports(&[(7, (1, 2)), (8, (3, 4))], false, 10);
+        assert_eq!(
+            reports,
+            vec![vec![1, 1, 7, 1, 0, 2, 0, 1, 8, 3, 0, 4, 0, 10, 0, 2]]
+        );
+
+        let reports = touch.frame_reports(&[(9, (5, 6))], false, 20);
+        assert_eq!(
+            reports,
+            vec![
+                vec![1, 1, 9, 5, 0, 6, 0, 0, 7, 1, 0, 2, 0, 20, 0, 3],
+                vec![1, 0, 8, 3, 0, 4, 0, 0, 0, 0, 0, 0, 0, 20, 0, 0],
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_report_ids_without_room() {
+        let screen = Screen {
+            width: 100,
+            height: 100,
+            width_mm: 100,
+            height_mm: 100,
+        };
+        assert!(MultiTouch::touchscreen(0, screen, 2).is_err());
+        assert!(MultiTouch::touchscreen(u8::MAX, screen, 2).is_ok());
+        assert!(MultiTouch::touchpad(0, screen, 2).is_err());
+        assert!(MultiTouch::touchpad(u8::MAX, screen, 2).is_err());
+        assert!(MultiTouch::touchpad(u8::MAX - 1, screen, 2).is_ok());
+    }
+}
@@ -0,0 +1,288 @@
+use std::io::{self, prelude::*};
+
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::{Preset, Screen};
+use crate::uhid_device::UHIDDevice;
+
+const MAX_PRESSURE: u16 = 4095;
+const MAX_TILT: i8 = 60;
+const MAX_TWIST: u16 = 359;
+/// English rotation system, angle in degrees
+const UNIT_DEGREES: u32 = 0x14;
+
+const TIP_SWITCH: u8 = 0b0_0001;
+const BARREL_SWITCH: u8 = 0b0_0010;
+const ERASER: u8 = 0b0_0100;
+const INVERT: u8 = 0b0_1000;
+const IN_RANGE: u8 = 0b1_0000;
+
+/// One sample of a stroke, at a pixel position with pressure between 0.0 and 1.0
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PenPoint {
+    pub x: u32,
+    pub y: u32,
+    pub pressure: f32,
+}
+
+/// A stylus on the Digitizer page with tip, barrel and eraser switches, pressure and X/Y tilt.
+/// Twist and a transducer serial number are added to the descriptor on request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pen {
+    report_id: u8,
+    screen: Screen,
+    twist_enabled: bool,
+    serial_number: Option<u32>,
+    in_range: bool,
+    touching: bool,
+    barrel: bool,
+    eraser: bool,
+    /// Whether the eraser end is the one in range, latched from `eraser` when entering range
+    inverted: bool,
+    position: (u16, u16),
+    pressure: u16,
+    tilt: (i8, i8),
+    twist: u16,
+}
+
+impl Pen {
+    pub fn new(report_id: u8, screen: Screen) -> Pen {
+        Pen {
+            report_id,
+            screen,
+            twist_enabled: false,
+            serial_number: None,
+            in_range: false,
+            touching: false,
+            barrel: false,
+            eraser: false,
+            inverted: false,
+            position: (0, 0),
+            pressure: 0,
+            tilt: (0, 0),
+            twist: 0,
+        }
+    }
+
+    /// Adds a Twist field, reported as rotation around the pen axis
+    pub fn with_twist(mut self) -> Pen {
+        self.twist_enabled = true;
+        self
+    }
+
+    /// Adds a Transducer Serial Number field carrying `serial_number`, which must be below 2^31
+    pub fn with_serial_number(mut self, serial_number: u32) -> Pen {
+        self.serial_number = Some(serial_number & i32::MAX as u32);
+        self
+    }
+
+    /// Sets the tilt in degrees, clamped to ±60, used from the next report on
+    pub fn set_tilt(&mut self, x: i8, y: i8) {
+        self.tilt = (x.clamp(-MAX_TILT, MAX_TILT), y.clamp(-MAX_TILT, MAX_TILT));
+    }
+
+    /// Sets the twist in degrees, used from the next report on
+    pub fn set_twist(&mut self, degrees: u16) {
+        self.twist = degrees % (MAX_TWIST + 1);
+    }
+
+    /// Holds or releases the barrel button, used from the next report on
+    pub fn set_barrel(&mut self, pressed: bool) {
+        self.barrel = pressed;
+    }
+
+    /// Turns the pen around so the eraser end points at the screen. Takes effect when the pen
+    /// next comes into range.
+    pub fn set_eraser(&mut self, eraser: bool) {
+        self.eraser = eraser;
+    }
+
+    /// Brings the pen into range with the end chosen by `set_eraser`
+    fn enter_range(&mut self) {
+        if !self.in_range {
+            self.in_range = true;
+            self.inverted = self.eraser;
+        }
+    }
+
+    fn report(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.in_range {
+            flags |= IN_RANGE;
+            if self.inverted {
+                flags |= INVERT;
+            }
+        }
+        if self.touching {
+            flags |= if self.inverted { ERASER } else { TIP_SWITCH };
+        }
+        if self.barrel {
+            flags |= BARREL_SWITCH;
+        }
+
+        let mut report = vec![self.report_id, flags];
+        report.extend_from_slice(&self.position.0.to_le_bytes());
+        report.extend_from_slice(&self.position.1.to_le_bytes());
+        report.extend_from_slice(&self.pressure.to_le_bytes());
+        report.extend_from_slice(&[self.tilt.0 as u8, self.tilt.1 as u8]);
+        if self.twist_enabled {
+            report.extend_from_slice(&self.twist.to_le_bytes());
+        }
+        if let Some(serial_number) = self.serial_number {
+            report.extend_from_slice(&serial_number.to_le_bytes());
+        }
+        report
+    }
+
+    fn send<T: Read + Write>(&self, device: &mut UHIDDevice<T>) -> io::Result<usize> {
+        device.write(&self.report())
+    }
+
+    /// Moves the pen above the screen without touching it
+    pub fn hover<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        x: u32,
+        y: u32,
+    ) -> io::Result<usize> {
+        self.enter_range();
+        self.touching = false;
+        self.pressure = 0;
+        self.position = self.screen.scale(x, y);
+        self.send(device)
+    }
+
+    /// Touches the screen at a position with a pressure between 0.0 and 1.0
+    pub fn touch<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        x: u32,
+        y: u32,
+        pressure: f32,
+    ) -> io::Result<usize> {
+        self.enter_range();
+        self.touching = true;
+        self.pressure = (pressure.clamp(0.0, 1.0) * MAX_PRESSURE as f32).round() as u16;
+        self.position = self.screen.scale(x, y);
+        self.send(device)
+    }
+
+    /// Lifts the pen off the screen while keeping it in range
+    pub fn lift<T: Read + Write>(&mut self, device: &mut UHIDDevice<T>) -> io::Result<usize> {
+        self.touching = false;
+        self.pressure = 0;
+        self.send(device)
+    }
+
+    /// Takes the pen out of proximity
+    pub fn leave<T: Read + Write>(&mut self, device: &mut UHIDDevice<T>) -> io::Result<usize> {
+        self.touching = false;
+        self.in_range = false;
+        self.pressure = 0;
+        self.send(device)
+    }
+
+    /// Hovers to the first point, draws through all of them and lifts the pen again
+    pub fn stroke<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        points: &[PenPoint],
+    ) -> io::Result<usize> {
+        let mut written = 0;
+        if let Some(first) = points.first() {
+            written += self.hover(device, first.x, first.y)?;
+        }
+        for point in points {
+            written += self.touch(device, point.x, point.y, point.pressure)?;
+        }
+        written += self.lift(device)?;
+        Ok(written)
+    }
+}
+
+impl Preset for Pen {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.usage_page(0x0d) /* Digitizer */
+            .usage(0x02) /* Pen */
+            .collection(Collection::Application)
+            .report_id(self.report_id)
+            .usage(0x20) /* Stylus */
+            .collection(Collection::Physical)
+            .usage(0x42) /* Tip Switch */
+            .usage(0x44) /* Barrel Switch */
+            .usage(0x45) /* Eraser */
+            .usage(0x3c) /* Invert */
+            .usage(0x32) /* In Range */
+            .logical_minimum(0)
+            .logical_maximum(1)
+            .report_size(1)
+            .report_count(5)
+            .input(MainFlags::Variable.into())
+            .report_count(3)
+            .input(MainFlags::Constant.into());
+        self.screen.describe_axes(rd);
+        rd.usage(0x30) /* Tip Pressure */
+            .logical_maximum(MAX_PRESSURE as i32)
+            .report_size(16)
+            .report_count(1)
+            .input(MainFlags::Variable.into())
+            .push()
+            .unit(UNIT_DEGREES)
+            .unit_exponent(0)
+            .usage(0x3d) /* X Tilt */
+            .usage(0x3e) /* Y Tilt */
+            .logical_minimum(-MAX_TILT as i32)
+            .logical_maximum(MAX_TILT as i32)
+            .physical_minimum(-MAX_TILT as i32)
+            .physical_maximum(MAX_TILT as i32)
+            .report_size(8)
+            .report_count(2)
+            .input(MainFlags::Variable.into());
+        if self.twist_enabled {
+            rd.usage(0x41) /* Twist */
+                .logical_minimum(0)
+                .logical_maximum(MAX_TWIST as i32)
+                .physical_minimum(0)
+                .physical_maximum(MAX_TWIST as i32)
+                .report_size(16)
+                .report_count(1)
+                .input(MainFlags::Variable.into());
+        }
+        rd.pop();
+        if self.serial_number.is_some() {
+            rd.usage(0x5b) /* Transducer Serial Number */
+                .logical_minimum(0)
+                .logical_maximum(i32::MAX)
+                .report_size(32)
+                .report_count(1)
+                .input(MainFlags::Variable.into());
+        }
+        rd.end_collection().end_collection();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eraser_inverts_while_in_range() {
+        let screen = Screen {
+            width: 2,
+            height: 2,
+            width_mm: 100,
+            height_mm: 100,
+        };
+        let mut pen = Pen::new(3, screen).with_twist().with_serial_number(0x1234);
+        pen.set_eraser(true);
+        pen.set_tilt(90, -10);
+        pen.enter_range();
+        /* Only applies once the pen leaves and comes back */
+        pen.set_eraser(false);
+        pen.touching = true;
+        pen.pressure = MAX_PRESSURE;
+        assert_eq!(
+            pen.report(),
+            vec![3, 0x1c, 0, 0, 0, 0, 0xff, 0x0f, 60, 0xf6, 0, 0, 0x34, 0x12, 0, 0]
+        );
+    }
+}
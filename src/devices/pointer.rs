@@ -0,0 +1,245 @@
+use std::io::{self, prelude::*};
+
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::{MouseButton, Preset};
+use crate::uhid_device::UHIDDevice;
+
+/// Logical maximum of absolute X and Y
+const MAX_POSITION: u32 = i16::MAX as u32;
+/// SI linear system, length in centimeters
+const UNIT_CENTIMETER: u32 = 0x11;
+
+/// Size of the screen an absolute device is mapped onto, in pixels for scaling coordinates and in
+/// millimeters for the physical extents the kernel derives the resolution from
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Screen {
+    pub width: u32,
+    pub height: u32,
+    pub width_mm: u32,
+    pub height_mm: u32,
+}
+
+impl Screen {
+    /// Scales a pixel position to the logical range, clamping it to the screen
+    pub fn scale(&self, x: u32, y: u32) -> (u16, u16) {
+        let axis = |value: u32, pixels: u32| {
+            let last = pixels.saturating_sub(1).max(1) as u64;
+            (value.min(last as u32) as u64 * MAX_POSITION as u64 / last) as u16
+        };
+        (axis(x, self.width), axis(y, self.height))
+    }
+
+    /// Appends absolute X and Y fields with physical extents in millimeters
+    pub(crate) fn describe_axes(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.push()
+            .usage_page(0x01) /* Generic Desktop */
+            .logical_minimum(0)
+            .logical_maximum(MAX_POSITION as i32)
+            .physical_minimum(0)
+            .unit(UNIT_CENTIMETER)
+            .unit_exponent(-1)
+            .report_size(16)
+            .report_count(1)
+            .usage(0x30) /* X */
+            .physical_maximum(self.width_mm as i32)
+            .input(MainFlags::Variable.into())
+            .usage(0x31) /* Y */
+            .physical_maximum(self.height_mm as i32)
+            .input(MainFlags::Variable.into())
+            .pop();
+    }
+}
+
+fn position_report(report_id: u8, flags: u8, position: (u16, u16)) -> [u8; 6] {
+    let x = position.0.to_le_bytes();
+    let y = position.1.to_le_bytes();
+    [report_id, flags, x[0], x[1], y[0], y[1]]
+}
+
+/// A pointer that places the cursor at exact screen coordinates, independent of acceleration,
+/// like the tablet device of virtual machines
+#[derive(Debug, Clone, PartialEq)]
+pub struct AbsolutePointer {
+    report_id: u8,
+    screen: Screen,
+    buttons: u8,
+    position: (u16, u16),
+}
+
+impl AbsolutePointer {
+    pub fn new(report_id: u8, screen: Screen) -> AbsolutePointer {
+        AbsolutePointer {
+            report_id,
+            screen,
+            buttons: 0,
+            position: (0, 0),
+        }
+    }
+
+    fn send<T: Read + Write>(&self, device: &mut UHIDDevice<T>) -> io::Result<usize> {
+        device.write(&position_report(
+            self.report_id,
+            self.buttons,
+            self.position,
+        ))
+    }
+
+    /// Moves the cursor to a pixel position on the screen
+    pub fn move_to<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        x: u32,
+        y: u32,
+    ) -> io::Result<usize> {
+        self.position = self.screen.scale(x, y);
+        self.send(device)
+    }
+
+    pub fn press<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        button: MouseButton,
+    ) -> io::Result<usize> {
+        self.buttons |= button as u8;
+        self.send(device)
+    }
+
+    pub fn release<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        button: MouseButton,
+    ) -> io::Result<usize> {
+        self.buttons &= !(button as u8);
+        self.send(device)
+    }
+
+    /// Presses and releases a button at the current position
+    pub fn click<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        button: MouseButton,
+    ) -> io::Result<usize> {
+        Ok(self.press(device, button)? + self.release(device, button)?)
+    }
+}
+
+impl Preset for AbsolutePointer {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.usage_page(0x01) /* Generic Desktop */
+            .usage(0x02) /* Mouse */
+            .collection(Collection::Application)
+            .report_id(self.report_id)
+            .usage(0x01) /* Pointer */
+            .collection(Collection::Physical)
+            .usage_page(0x09) /* Button */
+            .usage_minimum(1)
+            .usage_maximum(8)
+            .logical_minimum(0)
+            .logical_maximum(1)
+            .report_size(1)
+            .report_count(8)
+            .input(MainFlags::Variable.into());
+        self.screen.describe_axes(rd);
+        rd.end_collection().end_collection();
+    }
+}
+
+const TIP_SWITCH: u8 = 0b01;
+const IN_RANGE: u8 = 0b10;
+
+/// A single-touch touchscreen on the Digitizer page, reporting one finger with Tip Switch and
+/// In Range
+#[derive(Debug, Clone, PartialEq)]
+pub struct Touchscreen {
+    report_id: u8,
+    screen: Screen,
+    touching: bool,
+    position: (u16, u16),
+}
+
+impl Touchscreen {
+    pub fn new(report_id: u8, screen: Screen) -> Touchscreen {
+        Touchscreen {
+            report_id,
+            screen,
+            touching: false,
+            position: (0, 0),
+        }
+    }
+
+    fn send<T: Read + Write>(&self, device: &mut UHIDDevice<T>) -> io::Result<usize> {
+        let flags = if self.touching {
+            TIP_SWITCH | IN_RANGE
+        } else {
+            0
+        };
+        device.write(&position_report(self.report_id, flags, self.position))
+    }
+
+    /// Puts the finger down at a pixel position, or moves it there if it is already touching
+    pub fn touch<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        x: u32,
+        y: u32,
+    ) -> io::Result<usize> {
+        self.touching = true;
+        self.position = self.screen.scale(x, y);
+        self.send(device)
+    }
+
+    /// Lifts the finger at its last position
+    pub fn release<T: Read + Write>(&mut self, device: &mut UHIDDevice<T>) -> io::Result<usize> {
+        self.touching = false;
+        self.send(device)
+    }
+
+    pub fn tap<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        x: u32,
+        y: u32,
+    ) -> io::Result<usize> {
+        Ok(self.touch(device, x, y)? + self.release(device)?)
+    }
+}
+
+impl Preset for Touchscreen {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.usage_page(0x0d) /* Digitizer */
+            .usage(0x04) /* Touch Screen */
+            .collection(Collection::Application)
+            .report_id(self.report_id)
+            .usage(0x22) /* Finger */
+            .collection(Collection::Physical)
+            .usage(0x42) /* Tip Switch */
+            .usage(0x32) /* In Range */
+            .logical_minimum(0)
+            .logical_maximum(1)
+            .report_size(1)
+            .report_count(2)
+            .input(MainFlags::Variable.into())
+            .report_count(6)
+            .input(MainFlags::Constant.into());
+        self.screen.describe_axes(rd);
+        rd.end_collection().end_collection();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_to_logical_range() {
+        let screen = Screen {
+            width: 1920,
+            height: 1080,
+            width_mm: 344,
+            height_mm: 194,
+        };
+        assert_eq!(screen.scale(0, 0), (0, 0));
+        assert_eq!(screen.scale(1919, 1079), (32767, 32767));
+        assert_eq!(screen.scale(5000, 540), (32767, 16398));
+    }
+}
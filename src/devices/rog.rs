@@ -0,0 +1,382 @@
+use std::collections::VecDeque;
+use std::io::{self, prelude::*};
+
+use enumflags2::BitFlags;
+
+use crate::codec::{Bus, OutputEvent, ReportType};
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::Preset;
+use crate::uhid_device::{CreateParams, UHIDDevice};
+
+const VENDOR_ASUS: u32 = 0x0b05;
+const PRODUCT_N_KEY: u32 = 0x1866;
+const VENDOR_PAGE: u16 = 0xff31;
+
+/// Hotkey input report and keyboard feature report
+const REPORT_KEYBOARD: u8 = 0x5a;
+/// Aura lighting feature report
+const REPORT_AURA: u8 = 0x5d;
+/// Feature reports are this long, including the report ID
+const FEATURE_SIZE: usize = 64;
+const HOTKEY_SLOTS: usize = 5;
+
+const INIT_STRING: &[u8] = b"ASUS Tech.Inc.";
+const COMMAND_BRIGHTNESS: [u8; 3] = [0xba, 0xc5, 0xc4];
+const COMMAND_FUNCTIONS: [u8; 4] = [0x05, 0x20, 0x31, 0x00];
+const COMMAND_MODE: u8 = 0xb3;
+const COMMAND_APPLY: u8 = 0xb4;
+const COMMAND_SET: u8 = 0xb5;
+/// Keyboard backlight bit of the supported functions
+const FUNCTION_BACKLIGHT: u8 = 0x01;
+
+/// Vendor hotkeys, by their usage on the 0xFF31 page
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum RogHotkey {
+    BrightnessDown = 0x10,
+    BrightnessUp = 0x20,
+    DisplayOff = 0x35,
+    /// The ROG key above the keyboard
+    Rog = 0x38,
+    Touchpad = 0x6b,
+    MicMute = 0x7c,
+    RfKill = 0x88,
+    /// Fn+F5
+    FanMode = 0xae,
+    AuraPrevious = 0xb2,
+    AuraNext = 0xb3,
+    BacklightUp = 0xc4,
+    BacklightDown = 0xc5,
+}
+
+/// Built-in effects of `AuraCommand::Mode`
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum AuraMode {
+    Static = 0,
+    Breathe = 1,
+    Cycle = 2,
+    Rainbow = 3,
+    Star = 4,
+    Rain = 5,
+    Highlight = 6,
+    Laser = 7,
+    Ripple = 8,
+    Pulse = 10,
+    Comet = 11,
+    Flash = 12,
+}
+
+impl AuraMode {
+    fn from_byte(byte: u8) -> Option<AuraMode> {
+        Some(match byte {
+            0 => AuraMode::Static,
+            1 => AuraMode::Breathe,
+            2 => AuraMode::Cycle,
+            3 => AuraMode::Rainbow,
+            4 => AuraMode::Star,
+            5 => AuraMode::Rain,
+            6 => AuraMode::Highlight,
+            7 => AuraMode::Laser,
+            8 => AuraMode::Ripple,
+            10 => AuraMode::Pulse,
+            11 => AuraMode::Comet,
+            12 => AuraMode::Flash,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum AuraSpeed {
+    Low = 0xe1,
+    Medium = 0xeb,
+    High = 0xf5,
+}
+
+impl AuraSpeed {
+    fn from_byte(byte: u8) -> Option<AuraSpeed> {
+        Some(match byte {
+            0xe1 => AuraSpeed::Low,
+            0xeb => AuraSpeed::Medium,
+            0xf5 => AuraSpeed::High,
+            _ => return None,
+        })
+    }
+}
+
+/// An effect set by the host, not shown until `AuraCommand::Set`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuraEffect {
+    /// 0 for the whole keyboard, 1 to 4 for the zones of four-zone keyboards
+    pub zone: u8,
+    pub mode: AuraMode,
+    pub colour1: (u8, u8, u8),
+    pub speed: AuraSpeed,
+    /// Right (0), left (1), up (2) or down (3)
+    pub direction: u8,
+    pub colour2: (u8, u8, u8),
+}
+
+/// A lighting command received through a feature report
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuraCommand {
+    /// The "ASUS Tech.Inc." handshake, on the keyboard or the Aura report
+    Init,
+    /// Backlight brightness from 0 (off) to 3
+    Brightness(u8),
+    Mode(AuraEffect),
+    /// Shows the effect last set with `Mode`
+    Set,
+    /// Saves the effect for the next boot
+    Apply,
+    /// Any other report, such as per-key colours or power states, as received
+    Other(Vec<u8>),
+}
+
+impl AuraCommand {
+    /// Decodes a feature report, starting with its report ID
+    fn parse(data: &[u8]) -> AuraCommand {
+        let body = data.get(1..).unwrap_or(&[]);
+        if body.starts_with(INIT_STRING) {
+            return AuraCommand::Init;
+        }
+        if data[0] == REPORT_KEYBOARD && body.starts_with(&COMMAND_BRIGHTNESS) {
+            return AuraCommand::Brightness(body.get(3).copied().unwrap_or(0).min(3));
+        }
+        if data[0] == REPORT_AURA {
+            match body.first() {
+                Some(&COMMAND_MODE) if body.len() >= 12 => {
+                    let mode = AuraMode::from_byte(body[2]);
+                    let speed = AuraSpeed::from_byte(body[6]);
+                    if let (Some(mode), Some(speed)) = (mode, speed) {
+                        return AuraCommand::Mode(AuraEffect {
+                            zone: body[1],
+                            mode,
+                            colour1: (body[3], body[4], body[5]),
+                            speed,
+                            direction: body[7],
+                            colour2: (body[9], body[10], body[11]),
+                        });
+                    }
+                }
+                Some(&COMMAND_SET) => return AuraCommand::Set,
+                Some(&COMMAND_APPLY) => return AuraCommand::Apply,
+                _ => {}
+            }
+        }
+        AuraCommand::Other(data.to_vec())
+    }
+}
+
+/// The vendor interface of an ASUS ROG laptop keyboard (N-Key device). Hotkeys are sent as an
+/// array input report; lighting commands arrive as feature reports 0x5A and 0x5D and are
+/// queued as `AuraCommand`s. Regular keys are left to a `Keyboard` on another device, as on the
+/// laptops. The report IDs are fixed, so it is meant to be the only preset of its device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RogKeyboard {
+    pressed: Vec<RogHotkey>,
+    /// Answer to the next GET_REPORT of the keyboard feature report
+    feature: Vec<u8>,
+    events: VecDeque<AuraCommand>,
+}
+
+impl RogKeyboard {
+    pub fn new() -> RogKeyboard {
+        RogKeyboard {
+            pressed: Vec::new(),
+            feature: vec![REPORT_KEYBOARD],
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Parameters of the N-Key device of the 2020 laptops, on USB
+    pub fn create_params(&self, name: &str) -> CreateParams {
+        CreateParams {
+            name: name.to_string(),
+            phys: "".to_string(),
+            uniq: "".to_string(),
+            bus: Bus::USB,
+            vendor: VENDOR_ASUS,
+            product: PRODUCT_N_KEY,
+            version: 0,
+            country: 0,
+            rd_data: self.descriptor(),
+        }
+    }
+
+    /// Takes the oldest lighting command received from the host
+    pub fn next_event(&mut self) -> Option<AuraCommand> {
+        self.events.pop_front()
+    }
+
+    fn report(&self) -> Vec<u8> {
+        let mut report = vec![REPORT_KEYBOARD];
+        report.extend(self.pressed.iter().map(|hotkey| *hotkey as u8));
+        report.resize(HOTKEY_SLOTS + 1, 0);
+        report
+    }
+
+    pub fn press<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        hotkey: RogHotkey,
+    ) -> io::Result<usize> {
+        if !self.pressed.contains(&hotkey) {
+            if self.pressed.len() == HOTKEY_SLOTS {
+                return Err(io::Error::other("too many hotkeys pressed"));
+            }
+            self.pressed.push(hotkey);
+        }
+        device.write(&self.report())
+    }
+
+    pub fn release<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        hotkey: RogHotkey,
+    ) -> io::Result<usize> {
+        self.pressed.retain(|pressed| *pressed != hotkey);
+        device.write(&self.report())
+    }
+
+    /// Presses and releases a hotkey
+    pub fn tap<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        hotkey: RogHotkey,
+    ) -> io::Result<usize> {
+        Ok(self.press(device, hotkey)? + self.release(device, hotkey)?)
+    }
+
+    fn set_feature_report(&mut self, data: &[u8]) {
+        /* Function queries are answered by the next read, the rest is echoed like real keyboards */
+        let mut feature = data.to_vec();
+        if data[0] == REPORT_KEYBOARD && data[1..].starts_with(&COMMAND_FUNCTIONS) {
+            feature.resize(7, 0);
+            feature[6] = FUNCTION_BACKLIGHT;
+        } else {
+            self.events.push_back(AuraCommand::parse(data));
+        }
+        if data[0] == REPORT_KEYBOARD {
+            self.feature = feature;
+        }
+    }
+}
+
+impl Default for RogKeyboard {
+    fn default() -> RogKeyboard {
+        RogKeyboard::new()
+    }
+}
+
+impl Preset for RogKeyboard {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.usage_page(VENDOR_PAGE) /* Vendor-defined */
+            .usage(0x76)
+            .collection(Collection::Application)
+            .report_id(REPORT_KEYBOARD)
+            .usage_minimum(0)
+            .usage_maximum(0xff)
+            .logical_minimum(0)
+            .logical_maximum(u8::MAX as i32)
+            .report_size(8)
+            .report_count(HOTKEY_SLOTS as u32)
+            .input(BitFlags::empty())
+            .usage(0x01)
+            .report_count(FEATURE_SIZE as u32 - 1)
+            .feature(MainFlags::Variable.into())
+            .end_collection()
+            .usage_page(VENDOR_PAGE) /* Vendor-defined */
+            .usage(0x79) /* Aura */
+            .collection(Collection::Application)
+            .report_id(REPORT_AURA)
+            .usage(0x01)
+            .feature(MainFlags::Variable.into())
+            .end_collection();
+    }
+
+    fn handle<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        event: &OutputEvent,
+    ) -> io::Result<bool> {
+        match event {
+            OutputEvent::SetReport {
+                id,
+                report_number: REPORT_KEYBOARD | REPORT_AURA,
+                report_type: ReportType::Feature,
+                data,
+            } => {
+                if !data.is_empty() {
+                    self.set_feature_report(data);
+                }
+                device.write_set_report_reply(*id, 0)?;
+                Ok(true)
+            }
+            OutputEvent::GetReport {
+                id,
+                report_number: REPORT_KEYBOARD,
+                report_type: ReportType::Feature,
+            } => {
+                let mut feature = self.feature.clone();
+                feature.resize(FEATURE_SIZE, 0);
+                device.write_get_report_reply(*id, 0, feature)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_aura_commands() {
+        let mut keyboard = RogKeyboard::new();
+        let mut init = vec![REPORT_AURA];
+        init.extend_from_slice(INIT_STRING);
+        keyboard.set_feature_report(&init);
+        keyboard.set_feature_report(&[REPORT_KEYBOARD, 0x05, 0x20, 0x31, 0x00, 0x08]);
+        assert_eq!(keyboard.feature[6], FUNCTION_BACKLIGHT);
+        keyboard.set_feature_report(&[REPORT_KEYBOARD, 0xba, 0xc5, 0xc4, 0x02]);
+        keyboard.set_feature_report(&[
+            REPORT_AURA,
+            0xb3,
+            0,
+            1,
+            0xff,
+            0x00,
+            0x80,
+            0xeb,
+            1,
+            0,
+            0,
+            0x10,
+            0x20,
+        ]);
+        keyboard.set_feature_report(&[REPORT_AURA, 0xbd, 1]);
+
+        assert_eq!(keyboard.next_event(), Some(AuraCommand::Init));
+        assert_eq!(keyboard.next_event(), Some(AuraCommand::Brightness(2)));
+        assert_eq!(
+            keyboard.next_event(),
+            Some(AuraCommand::Mode(AuraEffect {
+                zone: 0,
+                mode: AuraMode::Breathe,
+                colour1: (0xff, 0x00, 0x80),
+                speed: AuraSpeed::Medium,
+                direction: 1,
+                colour2: (0, 0x10, 0x20),
+            }))
+        );
+        assert_eq!(
+            keyboard.next_event(),
+            Some(AuraCommand::Other(vec![REPORT_AURA, 0xbd, 1]))
+        );
+        assert_eq!(keyboard.next_event(), None);
+    }
+}
@@ -0,0 +1,320 @@
+use std::io::{self, prelude::*};
+use std::time::Duration;
+
+use enumflags2::BitFlags;
+
+use crate::codec::{OutputEvent, ReportType};
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::Preset;
+use crate::uhid_device::UHIDDevice;
+
+/// Sensors page usages are written as extended usages so they survive page changes
+const SENSOR_PAGE: u32 = 0x20_0000;
+const REPORTING_STATE: u32 = SENSOR_PAGE | 0x0316;
+const POWER_STATE: u32 = SENSOR_PAGE | 0x0319;
+const REPORT_INTERVAL: u32 = SENSOR_PAGE | 0x030e;
+/// Modifier turning a data field usage into its Change Sensitivity Absolute property
+const CHANGE_SENSITIVITY_ABSOLUTE: u32 = 0x1000;
+/// SI linear system, time in seconds
+const UNIT_SECONDS: u32 = 0x1001;
+
+const REPORTING_NO_EVENTS: u8 = 0;
+const DEFAULT_INTERVAL_MS: u32 = 100;
+
+/// Power states of the Power State property, in the order of their selectors
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum SensorPowerState {
+    Undefined = 0,
+    FullPower = 1,
+    LowPower = 2,
+    Standby = 3,
+    Sleep = 4,
+    Off = 5,
+}
+
+impl SensorPowerState {
+    fn from_index(index: u8) -> SensorPowerState {
+        match index {
+            1 => SensorPowerState::FullPower,
+            2 => SensorPowerState::LowPower,
+            3 => SensorPowerState::Standby,
+            4 => SensorPowerState::Sleep,
+            5 => SensorPowerState::Off,
+            _ => SensorPowerState::Undefined,
+        }
+    }
+}
+
+/// The sensor types with a kernel driver, each handled by its own hid-sensor-* module
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SensorKind {
+    /// X, Y and Z acceleration in 1/100 G
+    Accelerometer3D,
+    /// X, Y and Z angular velocity in 1/100 degrees per second
+    Gyrometer3D,
+    /// Illuminance in lux
+    AmbientLight,
+}
+
+impl SensorKind {
+    fn usage(self) -> u32 {
+        SENSOR_PAGE
+            | match self {
+                SensorKind::Accelerometer3D => 0x73,
+                SensorKind::Gyrometer3D => 0x76,
+                SensorKind::AmbientLight => 0x41,
+            }
+    }
+
+    /// The data field usage its sensitivity refers to, followed by the fields of a sample
+    fn data_usages(self) -> (u32, &'static [u32]) {
+        match self {
+            SensorKind::Accelerometer3D => (0x0452, &[0x0453, 0x0454, 0x0455]),
+            SensorKind::Gyrometer3D => (0x0456, &[0x0457, 0x0458, 0x0459]),
+            SensorKind::AmbientLight => (0x04d0, &[0x04d1]),
+        }
+    }
+
+    fn sample_range(self) -> (i32, i32, u32) {
+        match self {
+            SensorKind::AmbientLight => (0, i32::MAX, 32),
+            _ => (i16::MIN as i32, i16::MAX as i32, 16),
+        }
+    }
+}
+
+/// A sensor on the Sensors page (0x20), picked up by the kernel's hid-sensor-hub driver.
+/// Its properties are a feature report and its samples an input report, both under
+/// `report_id`. Samples are only sent once the host enabled reporting and powered the sensor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sensor {
+    report_id: u8,
+    kind: SensorKind,
+    reporting_state: u8,
+    power_state: SensorPowerState,
+    report_interval_ms: u32,
+    sensitivity: u16,
+    /// Last sample, answered to GET_REPORT requests for the input report
+    sample: Vec<u8>,
+}
+
+impl Sensor {
+    pub fn new(report_id: u8, kind: SensorKind) -> Sensor {
+        let (_, fields) = kind.data_usages();
+        let (_, _, bits) = kind.sample_range();
+        let mut sample = vec![report_id];
+        sample.resize(1 + fields.len() * bits as usize / 8, 0);
+        Sensor {
+            report_id,
+            kind,
+            reporting_state: REPORTING_NO_EVENTS,
+            power_state: SensorPowerState::Undefined,
+            report_interval_ms: DEFAULT_INTERVAL_MS,
+            sensitivity: 0,
+            sample,
+        }
+    }
+
+    pub fn accelerometer(report_id: u8) -> Sensor {
+        Sensor::new(report_id, SensorKind::Accelerometer3D)
+    }
+
+    pub fn gyrometer(report_id: u8) -> Sensor {
+        Sensor::new(report_id, SensorKind::Gyrometer3D)
+    }
+
+    pub fn ambient_light(report_id: u8) -> Sensor {
+        Sensor::new(report_id, SensorKind::AmbientLight)
+    }
+
+    pub fn kind(&self) -> SensorKind {
+        self.kind
+    }
+
+    /// Whether the host asked for samples and the sensor is powered
+    pub fn is_active(&self) -> bool {
+        self.reporting_state != REPORTING_NO_EVENTS
+            && matches!(
+                self.power_state,
+                SensorPowerState::FullPower | SensorPowerState::LowPower
+            )
+    }
+
+    pub fn power_state(&self) -> SensorPowerState {
+        self.power_state
+    }
+
+    /// The interval between samples requested by the host
+    pub fn report_interval(&self) -> Duration {
+        Duration::from_millis(self.report_interval_ms as u64)
+    }
+
+    /// The smallest change worth a sample requested by the host, in units of the sample
+    pub fn sensitivity(&self) -> u16 {
+        self.sensitivity
+    }
+
+    fn encode_sample(&self, values: &[i32]) -> Vec<u8> {
+        let (_, fields) = self.kind.data_usages();
+        let (minimum, maximum, bits) = self.kind.sample_range();
+        let mut report = vec![self.report_id];
+        for i in 0..fields.len() {
+            let value = values.get(i).copied().unwrap_or(0).clamp(minimum, maximum);
+            match bits {
+                16 => report.extend_from_slice(&(value as i16).to_le_bytes()),
+                _ => report.extend_from_slice(&value.to_le_bytes()),
+            }
+        }
+        report
+    }
+
+    /// Sends one value per axis, in the units of the sensor kind. Returns zero without writing
+    /// while the sensor is inactive; the sample is still kept for GET_REPORT requests.
+    pub fn send_sample<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        values: &[i32],
+    ) -> io::Result<usize> {
+        self.sample = self.encode_sample(values);
+        if !self.is_active() {
+            return Ok(0);
+        }
+        device.write(&self.sample)
+    }
+
+    fn feature_report(&self) -> Vec<u8> {
+        let mut report = vec![self.report_id, self.reporting_state, self.power_state as u8];
+        report.extend_from_slice(&self.report_interval_ms.to_le_bytes());
+        report.extend_from_slice(&self.sensitivity.to_le_bytes());
+        report
+    }
+
+    fn set_feature_report(&mut self, data: &[u8]) {
+        if let Some(state) = data.get(1) {
+            self.reporting_state = *state;
+        }
+        if let Some(state) = data.get(2) {
+            self.power_state = SensorPowerState::from_index(*state);
+        }
+        if let Some(interval) = data.get(3..7) {
+            self.report_interval_ms =
+                u32::from_le_bytes([interval[0], interval[1], interval[2], interval[3]]);
+        }
+        if let Some(sensitivity) = data.get(7..9) {
+            self.sensitivity = u16::from_le_bytes([sensitivity[0], sensitivity[1]]);
+        }
+    }
+}
+
+impl Preset for Sensor {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        let (data_usage, fields) = self.kind.data_usages();
+        let (minimum, maximum, bits) = self.kind.sample_range();
+        let selectors = |rd: &mut ReportDescriptorBuilder, usage: u32, first: u32, count: u32| {
+            rd.usage(usage).collection(Collection::Logical);
+            for selector in first..first + count {
+                rd.usage(SENSOR_PAGE | selector);
+            }
+            rd.logical_minimum(0)
+                .logical_maximum(count as i32 - 1)
+                .report_size(8)
+                .report_count(1)
+                .feature(BitFlags::empty())
+                .end_collection();
+        };
+
+        rd.usage_page(0x20) /* Sensors */
+            .usage(self.kind.usage())
+            .collection(Collection::Application)
+            .report_id(self.report_id);
+        /* No Events to Wake On All Events */
+        selectors(rd, REPORTING_STATE, 0x0840, 6);
+        /* Undefined to D4 Power Off */
+        selectors(rd, POWER_STATE, 0x0850, 6);
+        rd.push()
+            .usage(REPORT_INTERVAL)
+            .logical_minimum(0)
+            .logical_maximum(i32::MAX)
+            .unit(UNIT_SECONDS)
+            .unit_exponent(-3)
+            .report_size(32)
+            .report_count(1)
+            .feature(MainFlags::Variable.into())
+            .pop()
+            .usage(SENSOR_PAGE | CHANGE_SENSITIVITY_ABSOLUTE | data_usage)
+            .logical_minimum(0)
+            .logical_maximum(u16::MAX as i32)
+            .report_size(16)
+            .report_count(1)
+            .feature(MainFlags::Variable.into());
+
+        if self.kind != SensorKind::AmbientLight {
+            rd.unit_exponent(-2);
+        }
+        for field in fields {
+            rd.usage(SENSOR_PAGE | field);
+        }
+        rd.logical_minimum(minimum)
+            .logical_maximum(maximum)
+            .report_size(bits)
+            .report_count(fields.len() as u32)
+            .input(MainFlags::Variable.into())
+            .unit_exponent(0)
+            .end_collection();
+    }
+
+    fn handle<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        event: &OutputEvent,
+    ) -> io::Result<bool> {
+        match event {
+            OutputEvent::GetReport {
+                id,
+                report_number,
+                report_type,
+            } if *report_number == self.report_id => {
+                let report = match report_type {
+                    ReportType::Feature => self.feature_report(),
+                    ReportType::Input => self.sample.clone(),
+                    ReportType::Output => return Ok(false),
+                };
+                device.write_get_report_reply(*id, 0, report)?;
+                Ok(true)
+            }
+            OutputEvent::SetReport {
+                id,
+                report_number,
+                report_type: ReportType::Feature,
+                data,
+            } if *report_number == self.report_id => {
+                self.set_feature_report(data);
+                device.write_set_report_reply(*id, 0)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn properties_round_trip() {
+        let mut sensor = Sensor::accelerometer(2);
+        assert!(!sensor.is_active());
+        assert_eq!(sensor.sample, vec![2, 0, 0, 0, 0, 0, 0]);
+
+        sensor.set_feature_report(&[2, 1, 1, 0x10, 0, 0, 0, 5, 0]);
+        assert!(sensor.is_active());
+        assert_eq!(sensor.report_interval(), Duration::from_millis(16));
+        assert_eq!(sensor.feature_report(), vec![2, 1, 1, 0x10, 0, 0, 0, 5, 0]);
+        assert_eq!(
+            sensor.encode_sample(&[100, -100, 40000]),
+            vec![2, 100, 0, 0x9c, 0xff, 0xff, 0x7f]
+        );
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::VecDeque;
+use std::io::{self, prelude::*};
+
+use enumflags2::BitFlags;
+
+use crate::codec::{Bus, OutputEvent};
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::Preset;
+use crate::uhid_device::{CreateParams, UHIDDevice};
+
+const VENDOR_NINTENDO: u32 = 0x057e;
+const PRODUCT_PRO_CONTROLLER: u32 = 0x2009;
+const CONTROLLER_TYPE_PRO: u8 = 0x03;
+
+/// All reports are this long, including the report ID
+const REPORT_SIZE: usize = 64;
+
+const OUTPUT_RUMBLE_AND_SUBCOMMAND: u8 = 0x01;
+const OUTPUT_RUMBLE: u8 = 0x10;
+const OUTPUT_USB_COMMAND: u8 = 0x80;
+const INPUT_SUBCOMMAND_REPLY: u8 = 0x21;
+const INPUT_FULL: u8 = 0x30;
+const INPUT_USB_REPLY: u8 = 0x81;
+
+const SUBCOMMAND_DEVICE_INFO: u8 = 0x02;
+const SUBCOMMAND_INPUT_MODE: u8 = 0x03;
+const SUBCOMMAND_SPI_READ: u8 = 0x10;
+const SUBCOMMAND_PLAYER_LIGHTS: u8 = 0x30;
+const SUBCOMMAND_HOME_LIGHT: u8 = 0x38;
+const SUBCOMMAND_ENABLE_IMU: u8 = 0x40;
+const SUBCOMMAND_ENABLE_VIBRATION: u8 = 0x48;
+
+/// Size of the SPI flash of a real controller
+pub const SWITCH_PRO_FLASH_SIZE: usize = 0x80000;
+const FLASH_IMU_FACTORY_CALIBRATION: usize = 0x6020;
+const FLASH_STICK_FACTORY_CALIBRATION: usize = 0x603d;
+const FLASH_COLORS: usize = 0x6050;
+/// Largest SPI read answered in one subcommand reply
+const MAX_SPI_READ: usize = 0x1d;
+
+pub const SWITCH_PRO_STICK_CENTER: u16 = 2048;
+/// Stick travel from the center, as written to the factory calibration
+pub const SWITCH_PRO_STICK_RANGE: u16 = 1400;
+
+/// Buttons at their bit position in the three button bytes of an input report
+#[derive(BitFlags, Copy, Clone, Debug, PartialEq)]
+#[repr(u32)]
+pub enum SwitchProButton {
+    Y = 1 << 0,
+    X = 1 << 1,
+    B = 1 << 2,
+    A = 1 << 3,
+    R = 1 << 6,
+    Zr = 1 << 7,
+    Minus = 1 << 8,
+    Plus = 1 << 9,
+    RightStick = 1 << 10,
+    LeftStick = 1 << 11,
+    Home = 1 << 12,
+    Capture = 1 << 13,
+    Down = 1 << 16,
+    Up = 1 << 17,
+    Right = 1 << 18,
+    Left = 1 << 19,
+    L = 1 << 22,
+    Zl = 1 << 23,
+}
+
+/// Controls and sensors sent by `SwitchPro::send_state`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwitchProState {
+    pub buttons: BitFlags<SwitchProButton>,
+    /// 12-bit X and Y, `SWITCH_PRO_STICK_CENTER` at rest and up is positive
+    pub left_stick: (u16, u16),
+    pub right_stick: (u16, u16),
+    /// Raw X, Y and Z, scaled by the driver with the IMU calibration of the flash image
+    pub accel: [i16; 3],
+    pub gyro: [i16; 3],
+    /// Battery level in percent
+    pub battery: u8,
+    pub charging: bool,
+}
+
+impl Default for SwitchProState {
+    fn default() -> SwitchProState {
+        SwitchProState {
+            buttons: BitFlags::empty(),
+            left_stick: (SWITCH_PRO_STICK_CENTER, SWITCH_PRO_STICK_CENTER),
+            right_stick: (SWITCH_PRO_STICK_CENTER, SWITCH_PRO_STICK_CENTER),
+            accel: [0; 3],
+            gyro: [0; 3],
+            battery: 100,
+            charging: false,
+        }
+    }
+}
+
+/// A setting sent by the host through a subcommand or rumble data
+#[derive(Debug, Clone, PartialEq)]
+pub enum SwitchProEvent {
+    /// Encoded HD rumble data of the left and right actuator, sent whenever it changes
+    Rumble {
+        left: [u8; 4],
+        right: [u8; 4],
+    },
+    /// Player LEDs that are on in the low nibble, flashing ones in the high nibble
+    PlayerLights(u8),
+    /// Dimming pattern of the home button LED
+    HomeLight(Vec<u8>),
+    InputMode(u8),
+    Imu(bool),
+    Vibration(bool),
+}
+
+fn pack_stick(stick: (u16, u16)) -> [u8; 3] {
+    let (x, y) = (stick.0.min(0xfff), stick.1.min(0xfff));
+    [
+        x as u8,
+        (x >> 8) as u8 | (y as u8 & 0x0f) << 4,
+        (y >> 4) as u8,
+    ]
+}
+
+/// A Nintendo Switch Pro Controller that completes the subcommand handshake of the kernel's
+/// hid-nintendo driver. Device info, SPI flash reads, input mode, IMU, vibration and light
+/// subcommands are answered with 0x21 reports; reads are served from a virtual flash image
+/// holding factory calibration. It is meant to be the only preset of its device, created from
+/// `create_params`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwitchPro {
+    bus: Bus,
+    mac: [u8; 6],
+    flash: Vec<u8>,
+    state: SwitchProState,
+    timer: u8,
+    rumble: [u8; 8],
+    events: VecDeque<SwitchProEvent>,
+}
+
+impl SwitchPro {
+    fn new(bus: Bus, mac: [u8; 6]) -> SwitchPro {
+        let mut flash = vec![0xff; SWITCH_PRO_FLASH_SIZE];
+        let mut imu = Vec::new();
+        /* Accelerometer and gyroscope origins and sensitivities */
+        for value in [
+            0, 0, 0, 0x4000, 0x4000, 0x4000, 0, 0, 0, 0x343b, 0x343b, 0x343b,
+        ] {
+            imu.extend_from_slice(&(value as i16).to_le_bytes());
+        }
+        flash[FLASH_IMU_FACTORY_CALIBRATION..FLASH_IMU_FACTORY_CALIBRATION + imu.len()]
+            .copy_from_slice(&imu);
+        let range = pack_stick((SWITCH_PRO_STICK_RANGE, SWITCH_PRO_STICK_RANGE));
+        let center = pack_stick((SWITCH_PRO_STICK_CENTER, SWITCH_PRO_STICK_CENTER));
+        /* Left: above, center, below; right: center, below, above */
+        let sticks = [range, center, range, center, range, range].concat();
+        flash[FLASH_STICK_FACTORY_CALIBRATION..FLASH_STICK_FACTORY_CALIBRATION + sticks.len()]
+            .copy_from_slice(&sticks);
+        /* Body, buttons, left and right grip */
+        let colors = [
+            0x32, 0x32, 0x32, 0xff, 0xff, 0xff, 0x32, 0x32, 0x32, 0x32, 0x32, 0x32,
+        ];
+        flash[FLASH_COLORS..FLASH_COLORS + colors.len()].copy_from_slice(&colors);
+
+        SwitchPro {
+            bus,
+            mac,
+            flash,
+            state: SwitchProState::default(),
+            timer: 0,
+            rumble: [0; 8],
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn usb(mac: [u8; 6]) -> SwitchPro {
+        SwitchPro::new(Bus::USB, mac)
+    }
+
+    pub fn bluetooth(mac: [u8; 6]) -> SwitchPro {
+        SwitchPro::new(Bus::BLUETOOTH, mac)
+    }
+
+    /// Parameters matching a real controller on the chosen bus
+    pub fn create_params(&self, name: &str) -> CreateParams {
+        let mac = self
+            .mac
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(":");
+        CreateParams {
+            name: name.to_string(),
+            phys: "".to_string(),
+            uniq: mac,
+            bus: self.bus,
+            vendor: VENDOR_NINTENDO,
+            product: PRODUCT_PRO_CONTROLLER,
+            version: 0x0200,
+            country: 0,
+            rd_data: self.descriptor(),
+        }
+    }
+
+    /// The SPI flash image, `SWITCH_PRO_FLASH_SIZE` bytes long
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    /// Gives access to the flash image, for instance to add user calibration at 0x8010
+    pub fn flash_mut(&mut self) -> &mut [u8] {
+        &mut self.flash
+    }
+
+    pub fn state(&self) -> &SwitchProState {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut SwitchProState {
+        &mut self.state
+    }
+
+    /// Takes the oldest setting received from the host
+    pub fn next_event(&mut self) -> Option<SwitchProEvent> {
+        self.events.pop_front()
+    }
+
+    /// Starts an input report with the timer, battery, buttons and sticks
+    fn report_header(&mut self, report_id: u8) -> Vec<u8> {
+        let state = &self.state;
+        let mut report = Vec::with_capacity(REPORT_SIZE);
+        report.push(report_id);
+        report.push(self.timer);
+        self.timer = self.timer.wrapping_add(1);
+        /* Battery level in even steps up to 8, charging flag, powered by the host over USB */
+        let level = (state.battery.min(100) as u32 * 4 / 100) as u8 * 2;
+        let mut battery = level << 4;
+        if state.charging {
+            battery |= 0x10;
+        }
+        if self.bus == Bus::USB {
+            battery |= 0x01;
+        }
+        report.push(battery);
+        report.extend_from_slice(&state.buttons.bits().to_le_bytes()[..3]);
+        report.extend_from_slice(&pack_stick(state.left_stick));
+        report.extend_from_slice(&pack_stick(state.right_stick));
+        /* Vibrator input report */
+        report.push(0x80);
+        report
+    }
+
+    fn full_report(&mut self) -> Vec<u8> {
+        let mut report = self.report_header(INPUT_FULL);
+        /* Three IMU samples, all with the current values */
+        for _ in 0..3 {
+            for value in self.state.accel.iter().chain(self.state.gyro.iter()) {
+                report.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        report.resize(REPORT_SIZE, 0);
+        report
+    }
+
+    /// Sends a full input report with sticks, buttons and IMU. Real controllers send one every
+    /// 8 ms over USB and every 15 ms over Bluetooth.
+    pub fn send_state<T: Read + Write>(&mut self, device: &mut UHIDDevice<T>) -> io::Result<usize> {
+        let report = self.full_report();
+        device.write(&report)
+    }
+
+    fn reply(&mut self, subcommand: u8, ack: u8, data: &[u8]) -> Vec<u8> {
+        let mut report = self.report_header(INPUT_SUBCOMMAND_REPLY);
+        report.push(ack);
+        report.push(subcommand);
+        report.extend_from_slice(data);
+        report.resize(REPORT_SIZE, 0);
+        report
+    }
+
+    /// Answers a subcommand with its reply report
+    fn subcommand(&mut self, subcommand: u8, data: &[u8]) -> Vec<u8> {
+        let argument = data.first().copied().unwrap_or(0);
+        match subcommand {
+            SUBCOMMAND_DEVICE_INFO => {
+                /* Firmware version, type, MAC address, colors stored in SPI */
+                let mut info = vec![0x04, 0x33, CONTROLLER_TYPE_PRO, 0x02];
+                info.extend_from_slice(&self.mac);
+                info.extend_from_slice(&[0x01, 0x01]);
+                return self.reply(subcommand, 0x82, &info);
+            }
+            SUBCOMMAND_SPI_READ => {
+                let mut address = [0; 4];
+                for (i, byte) in data.iter().take(4).enumerate() {
+                    address[i] = *byte;
+                }
+                let start = u32::from_le_bytes(address) as usize;
+                let size = (data.get(4).copied().unwrap_or(0) as usize).min(MAX_SPI_READ);
+                let mut read = address.to_vec();
+                read.push(size as u8);
+                for offset in start..start + size {
+                    read.push(self.flash.get(offset).copied().unwrap_or(0xff));
+                }
+                return self.reply(subcommand, 0x90, &read);
+            }
+            SUBCOMMAND_INPUT_MODE => self.events.push_back(SwitchProEvent::InputMode(argument)),
+            SUBCOMMAND_PLAYER_LIGHTS => self
+                .events
+                .push_back(SwitchProEvent::PlayerLights(argument)),
+            SUBCOMMAND_HOME_LIGHT => self
+                .events
+                .push_back(SwitchProEvent::HomeLight(data.to_vec())),
+            SUBCOMMAND_ENABLE_IMU => self.events.push_back(SwitchProEvent::Imu(argument != 0)),
+            SUBCOMMAND_ENABLE_VIBRATION => self
+                .events
+                .push_back(SwitchProEvent::Vibration(argument != 0)),
+            _ => {}
+        }
+        self.reply(subcommand, 0x80, &[])
+    }
+
+    fn rumble(&mut self, data: &[u8]) {
+        let mut rumble = [0; 8];
+        for (i, byte) in data.iter().take(8).enumerate() {
+            rumble[i] = *byte;
+        }
+        if rumble != self.rumble {
+            self.rumble = rumble;
+            let mut left = [0; 4];
+            let mut right = [0; 4];
+            left.copy_from_slice(&rumble[..4]);
+            right.copy_from_slice(&rumble[4..]);
+            self.events
+                .push_back(SwitchProEvent::Rumble { left, right });
+        }
+    }
+
+    /// Decodes an output report and returns the input report answering it, if any
+    fn process_output(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        match *data.first()? {
+            OUTPUT_RUMBLE_AND_SUBCOMMAND if data.len() >= 11 => {
+                self.rumble(&data[2..10]);
+                Some(self.subcommand(data[10], &data[11..]))
+            }
+            OUTPUT_RUMBLE if data.len() >= 10 => {
+                self.rumble(&data[2..10]);
+                None
+            }
+            OUTPUT_USB_COMMAND if data.len() >= 2 => {
+                let mut reply = vec![INPUT_USB_REPLY, data[1]];
+                if data[1] == 0x01 {
+                    /* Status: controller type and MAC address, least significant byte first */
+                    reply.extend_from_slice(&[0x00, CONTROLLER_TYPE_PRO]);
+                    reply.extend(self.mac.iter().rev());
+                }
+                reply.resize(REPORT_SIZE, 0);
+                Some(reply)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Preset for SwitchPro {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        rd.usage_page(0x01) /* Generic Desktop */
+            .usage(0x04) /* Joystick */
+            .collection(Collection::Application)
+            .usage_page(0xff00) /* Vendor-defined */
+            .logical_minimum(0)
+            .logical_maximum(u8::MAX as i32)
+            .report_size(8)
+            .report_count(REPORT_SIZE as u32 - 1);
+        for report_id in [INPUT_FULL, INPUT_SUBCOMMAND_REPLY, INPUT_USB_REPLY] {
+            rd.report_id(report_id)
+                .usage(report_id as u32)
+                .input(MainFlags::Variable.into());
+        }
+        for report_id in [
+            OUTPUT_RUMBLE_AND_SUBCOMMAND,
+            OUTPUT_RUMBLE,
+            OUTPUT_USB_COMMAND,
+        ] {
+            rd.report_id(report_id)
+                .usage(report_id as u32)
+                .output(MainFlags::Variable.into());
+        }
+        rd.end_collection();
+    }
+
+    fn handle<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        event: &OutputEvent,
+    ) -> io::Result<bool> {
+        match event {
+            OutputEvent::Output { data } => {
+                if let Some(reply) = self.process_output(data) {
+                    device.write(&reply)?;
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_spi_reads_from_flash() {
+        let mut controller = SwitchPro::bluetooth([1, 2, 3, 4, 5, 6]);
+        let mut request = vec![OUTPUT_RUMBLE_AND_SUBCOMMAND, 0];
+        request.extend_from_slice(&[0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40]);
+        request.push(SUBCOMMAND_SPI_READ);
+        request.extend_from_slice(&[0x3d, 0x60, 0, 0, 0x12]);
+
+        let reply = controller.process_output(&request).unwrap();
+        assert_eq!(reply.len(), REPORT_SIZE);
+        assert_eq!(&reply[..3], &[INPUT_SUBCOMMAND_REPLY, 0, 0x80]);
+        assert_eq!(&reply[13..20], &[0x90, 0x10, 0x3d, 0x60, 0, 0, 0x12]);
+        /* Left stick maximum above center, then its center */
+        assert_eq!(&reply[20..26], &[0x78, 0x85, 0x57, 0x00, 0x08, 0x80]);
+        assert!(matches!(
+            controller.next_event(),
+            Some(SwitchProEvent::Rumble { .. })
+        ));
+    }
+}
@@ -0,0 +1,284 @@
+use std::io::{self, prelude::*};
+use std::time::Duration;
+
+use enumflags2::BitFlags;
+
+use crate::codec::{OutputEvent, ReportType};
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::devices::Preset;
+use crate::uhid_device::UHIDDevice;
+
+const POWER_DEVICE_PAGE: u32 = 0x84_0000;
+const BATTERY_SYSTEM_PAGE: u32 = 0x85_0000;
+/// SI linear system, time in seconds
+const UNIT_SECONDS: u32 = 0x1001;
+/// Capacity Mode value for capacities in percent
+const CAPACITY_MODE_PERCENT: u8 = 2;
+
+/// Flags of the Present Status collection
+#[derive(BitFlags, Copy, Clone, Debug, PartialEq)]
+#[repr(u8)]
+pub enum PowerStatus {
+    Charging = 0b0000_0001,
+    Discharging = 0b0000_0010,
+    AcPresent = 0b0000_0100,
+    BatteryPresent = 0b0000_1000,
+    BelowRemainingCapacityLimit = 0b0001_0000,
+    FullyCharged = 0b0010_0000,
+    NeedReplacement = 0b0100_0000,
+    ShutdownImminent = 0b1000_0000,
+}
+
+/// Usages of the `PowerStatus` flags, lowest bit first
+const STATUS_USAGES: [u32; 8] = [
+    BATTERY_SYSTEM_PAGE | 0x44, /* Charging */
+    BATTERY_SYSTEM_PAGE | 0x45, /* Discharging */
+    BATTERY_SYSTEM_PAGE | 0xd0, /* AC Present */
+    BATTERY_SYSTEM_PAGE | 0xd1, /* Battery Present */
+    BATTERY_SYSTEM_PAGE | 0x42, /* Below Remaining Capacity Limit */
+    BATTERY_SYSTEM_PAGE | 0x46, /* Fully Charged */
+    BATTERY_SYSTEM_PAGE | 0x4b, /* Need Replacement */
+    POWER_DEVICE_PAGE | 0x69,   /* Shutdown Imminent */
+];
+
+/// An uninterruptible power supply with one battery, on the Power Device (0x84) and Battery
+/// System (0x85) pages. Remaining capacity in percent, runtime to empty and present status are an
+/// input report and a feature report under `report_id`; the static capacity information is a
+/// feature report under `report_id + 1`. Every setter sends a fresh input report.
+///
+/// The preset is only reachable through hidraw. hid-input does not connect the UPS application
+/// collection, and Remaining Capacity (0x850066) is not the Absolute State Of Charge (0x850065)
+/// it maps to a power_supply, so the kernel creates neither an input device nor a battery.
+/// upower reads UPSes through hiddev, which only exists for USB devices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ups {
+    report_id: u8,
+    remaining_capacity: u8,
+    runtime_to_empty: u32,
+    status: BitFlags<PowerStatus>,
+    remaining_capacity_limit: u8,
+}
+
+impl Ups {
+    /// A fully charged UPS running on AC power. Fails on report IDs 0 and 255, as the capacity
+    /// report needs the next report ID.
+    pub fn new(report_id: u8) -> io::Result<Ups> {
+        if report_id == 0 || report_id == u8::MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "UPS report ID {} leaves no room for the capacity report",
+                    report_id
+                ),
+            ));
+        }
+        Ok(Ups {
+            report_id,
+            remaining_capacity: 100,
+            runtime_to_empty: 3600,
+            status: PowerStatus::AcPresent
+                | PowerStatus::BatteryPresent
+                | PowerStatus::FullyCharged,
+            remaining_capacity_limit: 10,
+        })
+    }
+
+    /// Sets the capacity in percent below which `BelowRemainingCapacityLimit` is expected
+    pub fn with_remaining_capacity_limit(mut self, percent: u8) -> Ups {
+        self.remaining_capacity_limit = percent.min(100);
+        self
+    }
+
+    pub fn remaining_capacity(&self) -> u8 {
+        self.remaining_capacity
+    }
+
+    pub fn runtime_to_empty(&self) -> Duration {
+        Duration::from_secs(self.runtime_to_empty as u64)
+    }
+
+    pub fn status(&self) -> BitFlags<PowerStatus> {
+        self.status
+    }
+
+    fn report(&self) -> Vec<u8> {
+        let mut report = vec![self.report_id, self.remaining_capacity];
+        report.extend_from_slice(&self.runtime_to_empty.to_le_bytes());
+        report.push(self.status.bits());
+        report
+    }
+
+    fn capacity_report(&self) -> Vec<u8> {
+        vec![
+            self.report_id + 1,
+            CAPACITY_MODE_PERCENT,
+            100, /* Design Capacity */
+            100, /* Full Charge Capacity */
+            self.remaining_capacity_limit,
+        ]
+    }
+
+    fn send<T: Read + Write>(&self, device: &mut UHIDDevice<T>) -> io::Result<usize> {
+        device.write(&self.report())
+    }
+
+    /// Sets the remaining capacity in percent, clamped to 100
+    pub fn set_remaining_capacity<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        percent: u8,
+    ) -> io::Result<usize> {
+        self.remaining_capacity = percent.min(100);
+        self.send(device)
+    }
+
+    /// Sets the estimated runtime on battery, in whole seconds
+    pub fn set_runtime_to_empty<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        runtime: Duration,
+    ) -> io::Result<usize> {
+        self.runtime_to_empty = runtime.as_secs().min(i32::MAX as u64) as u32;
+        self.send(device)
+    }
+
+    pub fn set_status<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        status: BitFlags<PowerStatus>,
+    ) -> io::Result<usize> {
+        self.status = status;
+        self.send(device)
+    }
+}
+
+impl Preset for Ups {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        let fields = |rd: &mut ReportDescriptorBuilder| {
+            rd.usage(BATTERY_SYSTEM_PAGE | 0x66) /* Remaining Capacity */
+                .logical_minimum(0)
+                .logical_maximum(100)
+                .report_size(8)
+                .report_count(1)
+                .input(MainFlags::Variable.into())
+                .usage(BATTERY_SYSTEM_PAGE | 0x66) /* Remaining Capacity */
+                .feature(MainFlags::Variable | MainFlags::Volatile)
+                .push()
+                .unit(UNIT_SECONDS)
+                .unit_exponent(0)
+                .usage(BATTERY_SYSTEM_PAGE | 0x68) /* Run Time To Empty */
+                .logical_maximum(i32::MAX)
+                .report_size(32)
+                .input(MainFlags::Variable.into())
+                .usage(BATTERY_SYSTEM_PAGE | 0x68) /* Run Time To Empty */
+                .feature(MainFlags::Variable | MainFlags::Volatile)
+                .pop();
+        };
+        let status = |rd: &mut ReportDescriptorBuilder, feature: bool| {
+            rd.usage(POWER_DEVICE_PAGE | 0x02) /* Present Status */
+                .collection(Collection::Logical);
+            for usage in STATUS_USAGES {
+                rd.usage(usage);
+            }
+            rd.logical_minimum(0)
+                .logical_maximum(1)
+                .report_size(1)
+                .report_count(STATUS_USAGES.len() as u32);
+            if feature {
+                rd.feature(MainFlags::Variable | MainFlags::Volatile);
+            } else {
+                rd.input(MainFlags::Variable.into());
+            }
+            rd.end_collection();
+        };
+
+        rd.usage_page(0x84) /* Power Device */
+            .usage(POWER_DEVICE_PAGE | 0x04) /* UPS */
+            .collection(Collection::Application)
+            .usage(POWER_DEVICE_PAGE | 0x24) /* Power Summary */
+            .collection(Collection::Physical)
+            .report_id(self.report_id);
+        fields(rd);
+        status(rd, false);
+        status(rd, true);
+        rd.report_id(self.report_id + 1)
+            .usage(BATTERY_SYSTEM_PAGE | 0x2c) /* Capacity Mode */
+            .logical_minimum(0)
+            .logical_maximum(u8::MAX as i32)
+            .report_size(8)
+            .report_count(1)
+            .feature(MainFlags::Constant | MainFlags::Variable)
+            .usage(BATTERY_SYSTEM_PAGE | 0x83) /* Design Capacity */
+            .usage(BATTERY_SYSTEM_PAGE | 0x67) /* Full Charge Capacity */
+            .logical_maximum(100)
+            .report_count(2)
+            .feature(MainFlags::Constant | MainFlags::Variable)
+            .usage(BATTERY_SYSTEM_PAGE | 0x29) /* Remaining Capacity Limit */
+            .report_count(1)
+            .feature(MainFlags::Variable.into())
+            .end_collection()
+            .end_collection();
+    }
+
+    fn handle<T: Read + Write>(
+        &mut self,
+        device: &mut UHIDDevice<T>,
+        event: &OutputEvent,
+    ) -> io::Result<bool> {
+        match event {
+            OutputEvent::GetReport {
+                id,
+                report_number,
+                report_type: ReportType::Feature,
+            } => {
+                let report = if *report_number == self.report_id {
+                    self.report()
+                } else if *report_number == self.report_id + 1 {
+                    self.capacity_report()
+                } else {
+                    return Ok(false);
+                };
+                device.write_get_report_reply(*id, 0, report)?;
+                Ok(true)
+            }
+            OutputEvent::GetReport {
+                id,
+                report_number,
+                report_type: ReportType::Input,
+            } if *report_number == self.report_id => {
+                device.write_get_report_reply(*id, 0, self.report())?;
+                Ok(true)
+            }
+            OutputEvent::SetReport {
+                id,
+                report_number,
+                report_type: ReportType::Feature,
+                data,
+            } if *report_number == self.report_id + 1 => {
+                if let Some(limit) = data.get(4) {
+                    self.remaining_capacity_limit = (*limit).min(100);
+                }
+                device.write_set_report_reply(*id, 0)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_capacity_runtime_and_status() {
+        let mut ups = Ups::new(5).unwrap().with_remaining_capacity_limit(20);
+        ups.remaining_capacity = 42;
+        ups.runtime_to_empty = 600;
+        ups.status = PowerStatus::Discharging | PowerStatus::BatteryPresent;
+        assert_eq!(ups.report(), vec![5, 42, 0x58, 0x02, 0, 0, 0b1010]);
+        assert_eq!(ups.capacity_report(), vec![6, 2, 100, 100, 20]);
+        assert!(Ups::new(0).is_err());
+        assert!(Ups::new(u8::MAX).is_err());
+    }
+}
@@ -0,0 +1,350 @@
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, BufReader};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::codec::{InputEvent, OutputEvent, StreamError, UHID_EVENT_SIZE};
+
+/// Start of every log file, the last byte being the format version
+const MAGIC: &[u8; 8] = b"UHIDLOG\x01";
+/// Direction, timestamp in microseconds and event length
+const RECORD_HEADER_SIZE: usize = 1 + 8 + 4;
+
+/// Which way a logged event went
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Direction {
+    /// Written to /dev/uhid, an `InputEvent`
+    Input = 0,
+    /// Read from /dev/uhid, an `OutputEvent`
+    Output = 1,
+}
+
+/// An event of an `EventLog`, as it crossed the handle
+pub struct LogRecord {
+    pub time: SystemTime,
+    pub direction: Direction,
+    event: Box<[u8; UHID_EVENT_SIZE]>,
+}
+
+impl LogRecord {
+    /// The raw event, padded back to its full size
+    pub fn event(&self) -> &[u8; UHID_EVENT_SIZE] {
+        &self.event
+    }
+
+    /// The event written by user space, none for events read from the kernel
+    pub fn input_event(&self) -> Option<Result<InputEvent<'_>, StreamError>> {
+        match self.direction {
+            Direction::Input => Some(InputEvent::try_from(&*self.event)),
+            Direction::Output => None,
+        }
+    }
+
+    /// The event read from the kernel, none for events written by user space
+    pub fn output_event(&self) -> Option<Result<OutputEvent, StreamError>> {
+        match self.direction {
+            Direction::Input => None,
+            Direction::Output => Some(OutputEvent::try_from(*self.event)),
+        }
+    }
+}
+
+/// Wraps the /dev/uhid handle of a device and logs every event crossing it to a file, with
+/// `UHIDDevice::create_with_handle`. Each record holds the direction, a timestamp and the event
+/// without its trailing zeros. Records are appended to an existing log.
+///
+/// Every read and write is logged as one event, so the handle must be event-atomic like
+/// /dev/uhid: a read returns a whole event and a write takes one. Failing to log does not fail
+/// the I/O, as the event has already crossed the handle; the first such error is returned by the
+/// next `flush` instead.
+pub struct EventLog<T: Read + Write> {
+    handle: T,
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// Size past which the file is rotated, and how many rotated files are kept
+    rotation: Option<(u64, usize)>,
+    /// First logging error since the last flush
+    error: Option<io::Error>,
+}
+
+fn open_log(path: &Path) -> io::Result<(File, u64)> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut size = file.metadata()?.len();
+    if size == 0 {
+        file.write_all(MAGIC)?;
+        size = MAGIC.len() as u64;
+    }
+    Ok((file, size))
+}
+
+/// `path` with a `.N` suffix, the name of the Nth most recent rotated file
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = OsString::from(path);
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+impl<T: Read + Write> EventLog<T> {
+    pub fn create(handle: T, path: &Path) -> io::Result<EventLog<T>> {
+        let (file, size) = open_log(path)?;
+        Ok(EventLog {
+            handle,
+            path: path.to_path_buf(),
+            file,
+            size,
+            rotation: None,
+            error: None,
+        })
+    }
+
+    /// Starts a new file once the log would grow past `max_size` bytes, keeping the previous
+    /// ones as `path.1` (the most recent) up to `path.<keep>`
+    pub fn with_rotation(mut self, max_size: u64, keep: usize) -> EventLog<T> {
+        self.rotation = Some((max_size, keep));
+        self
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.handle
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.handle
+    }
+
+    fn rotate(&mut self, keep: usize) -> io::Result<()> {
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..keep).rev() {
+                match fs::rename(rotated(&self.path, index), rotated(&self.path, index + 1)) {
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+        let (file, size) = open_log(&self.path)?;
+        self.file = file;
+        self.size = size;
+        Ok(())
+    }
+
+    fn log(&mut self, direction: Direction, event: &[u8]) -> io::Result<()> {
+        let length = event
+            .iter()
+            .rposition(|b| *b != 0)
+            .map_or(0, |last| last + 1);
+        let event = &event[..length];
+        let record_size = (RECORD_HEADER_SIZE + length) as u64;
+        if let Some((max_size, keep)) = self.rotation {
+            if self.size > MAGIC.len() as u64 && self.size + record_size > max_size {
+                self.rotate(keep)?;
+            }
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut record = Vec::with_capacity(record_size as usize);
+        record.push(direction as u8);
+        record.extend_from_slice(&time.to_le_bytes());
+        record.extend_from_slice(&(length as u32).to_le_bytes());
+        record.extend_from_slice(event);
+        self.file.write_all(&record)?;
+        self.size += record_size;
+        Ok(())
+    }
+
+    /// Logs an event that crossed the handle, keeping the first error for `flush`
+    fn record(&mut self, direction: Direction, event: &[u8]) {
+        if let Err(error) = self.log(direction, event) {
+            self.error.get_or_insert(error);
+        }
+    }
+}
+
+impl<T: Read + Write> Read for EventLog<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.handle.read(buf)?;
+        if size > 0 {
+            self.record(Direction::Output, &buf[..size]);
+        }
+        Ok(size)
+    }
+}
+
+impl<T: Read + Write> Write for EventLog<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.handle.write(buf)?;
+        if size > 0 {
+            self.record(Direction::Input, &buf[..size]);
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.handle.flush()?;
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.file.flush()
+    }
+}
+
+impl<T: Read + Write + AsRawFd> AsRawFd for EventLog<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.handle.as_raw_fd()
+    }
+}
+
+/// Iterates the records of one file written by an `EventLog`, oldest first. Rotated files are
+/// read separately, starting with the highest suffix.
+pub struct EventLogReader<R: Read> {
+    reader: R,
+}
+
+impl EventLogReader<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<EventLogReader<BufReader<File>>> {
+        EventLogReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> EventLogReader<R> {
+    pub fn new(mut reader: R) -> io::Result<EventLogReader<R>> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a UHID event log",
+            ));
+        }
+        Ok(EventLogReader { reader })
+    }
+
+    fn read_record(&mut self, direction: u8) -> io::Result<LogRecord> {
+        let direction = match direction {
+            0 => Direction::Input,
+            1 => Direction::Output,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid direction {}", direction),
+                ))
+            }
+        };
+        let mut header = [0; RECORD_HEADER_SIZE - 1];
+        self.reader.read_exact(&mut header)?;
+        let mut time = [0; 8];
+        time.copy_from_slice(&header[..8]);
+        let mut length = [0; 4];
+        length.copy_from_slice(&header[8..]);
+        let length = u32::from_le_bytes(length) as usize;
+        if length > UHID_EVENT_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record of {} bytes is larger than an event", length),
+            ));
+        }
+        let mut event = Box::new([0; UHID_EVENT_SIZE]);
+        self.reader.read_exact(&mut event[..length])?;
+        Ok(LogRecord {
+            time: UNIX_EPOCH + Duration::from_micros(u64::from_le_bytes(time)),
+            direction,
+            event,
+        })
+    }
+}
+
+impl<R: Read> Iterator for EventLogReader<R> {
+    type Item = io::Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut direction = [0];
+        loop {
+            match self.reader.read(&mut direction) {
+                Ok(0) => return None,
+                Ok(_) => return Some(self.read_record(direction[0])),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Some(Err(error)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Bus;
+    use crate::uhid_device::{CreateParams, UHIDDevice};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn logs_and_rotates_events() {
+        let root = std::env::temp_dir().join(format!("uhid-virt-log-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("uhid.log");
+        let (handle, mut kernel) = UnixStream::pair().unwrap();
+        let log = EventLog::create(handle, &path)
+            .unwrap()
+            .with_rotation(64, 1);
+        let params = CreateParams {
+            name: "test-device".to_string(),
+            phys: "".to_string(),
+            uniq: "".to_string(),
+            bus: Bus::USB,
+            vendor: 0x15d9,
+            product: 0x0a37,
+            version: 0,
+            country: 0,
+            rd_data: vec![0x05, 0x01],
+        };
+        let mut device = UHIDDevice::create_with_handle(params.clone(), log).unwrap();
+        device.write(&[1, 2, 3]).unwrap();
+        let mut start = [0; UHID_EVENT_SIZE];
+        start[0] = 2; /* UHID_START */
+        kernel.write_all(&start).unwrap();
+        device.read().ok().unwrap();
+
+        /* The create event fills the first file on its own */
+        let rotated: Vec<_> = EventLogReader::open(&rotated(&path, 1))
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        let records: Vec<_> = EventLogReader::open(&path)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(rotated.len(), 1);
+        assert!(
+            matches!(rotated[0].input_event(), Some(Ok(InputEvent::Create(ref logged))) if *logged == params)
+        );
+        assert_eq!(records.len(), 2);
+        assert!(matches!(
+            records[0].input_event(),
+            Some(Ok(InputEvent::Input { data: &[1, 2, 3] }))
+        ));
+        assert!(matches!(
+            records[1].output_event(),
+            Some(Ok(OutputEvent::Start { .. }))
+        ));
+        assert!(records[0].time <= records[1].time);
+
+        let mut oversized = MAGIC.to_vec();
+        oversized.push(Direction::Input as u8);
+        oversized.extend_from_slice(&0u64.to_le_bytes());
+        oversized.extend_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = EventLogReader::new(&oversized[..]).unwrap();
+        assert_eq!(
+            reader.next().unwrap().err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::os::raw::c_char;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+use crate::codec::{Bus, ReportType};
+use crate::uhid_device::CreateParams;
+
+/// HID_MAX_DESCRIPTOR_SIZE
+const MAX_DESCRIPTOR_SIZE: usize = 4096;
+/// UHID_DATA_MAX, so that any report read here fits an UHID event
+pub const MAX_REPORT_SIZE: usize = 4096;
+const MAX_STRING_SIZE: usize = 256;
+
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+/// Request number of a hidraw ioctl, using the asm-generic encoding
+const fn ioc(direction: u32, number: u32, size: usize) -> u32 {
+    direction << 30 | (size as u32) << 16 | (b'H' as u32) << 8 | number
+}
+
+const HIDIOCGRDESCSIZE: u32 = ioc(IOC_READ, 0x01, 4);
+const HIDIOCGRDESC: u32 = ioc(IOC_READ, 0x02, 4 + MAX_DESCRIPTOR_SIZE);
+const HIDIOCGRAWINFO: u32 = ioc(IOC_READ, 0x03, 8);
+const RAW_NAME: u32 = 0x04;
+const RAW_PHYS: u32 = 0x05;
+const SET_FEATURE: u32 = 0x06;
+const GET_FEATURE: u32 = 0x07;
+const RAW_UNIQ: u32 = 0x08;
+const SET_INPUT: u32 = 0x09;
+const GET_INPUT: u32 = 0x0a;
+const SET_OUTPUT: u32 = 0x0b;
+const GET_OUTPUT: u32 = 0x0c;
+
+#[repr(C)]
+struct ReportDescriptor {
+    size: u32,
+    value: [u8; MAX_DESCRIPTOR_SIZE],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DevInfo {
+    bustype: u32,
+    vendor: i16,
+    product: i16,
+}
+
+/// A real HID device, opened through its /dev/hidrawN node. Reading gives one input report at a
+/// time and writing sends an output report, both starting with the report number when the
+/// device uses numbered reports.
+pub struct Hidraw {
+    file: File,
+}
+
+impl Hidraw {
+    pub fn open(path: &Path) -> io::Result<Hidraw> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(path)?;
+        Ok(Hidraw { file })
+    }
+
+    /// Any file standing in for a hidraw node, whose ioctls then fail
+    #[cfg(test)]
+    pub(crate) fn from_file(file: File) -> Hidraw {
+        Hidraw { file }
+    }
+
+    fn ioctl<A>(&self, request: u32, argument: *mut A) -> io::Result<usize> {
+        let result = unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, argument) };
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result as usize)
+        }
+    }
+
+    fn string(&self, number: u32) -> io::Result<String> {
+        let mut buffer = [0 as c_char; MAX_STRING_SIZE];
+        self.ioctl(ioc(IOC_READ, number, MAX_STRING_SIZE), buffer.as_mut_ptr())?;
+        buffer[MAX_STRING_SIZE - 1] = 0;
+        let string = unsafe { CStr::from_ptr(buffer.as_ptr()) };
+        Ok(string.to_string_lossy().into_owned())
+    }
+
+    /// The report descriptor of the device
+    pub fn descriptor(&self) -> io::Result<Vec<u8>> {
+        let mut size: i32 = 0;
+        self.ioctl(HIDIOCGRDESCSIZE, &mut size)?;
+        let mut descriptor = ReportDescriptor {
+            size: size as u32,
+            value: [0; MAX_DESCRIPTOR_SIZE],
+        };
+        self.ioctl(HIDIOCGRDESC, &mut descriptor)?;
+        let size = (descriptor.size as usize).min(MAX_DESCRIPTOR_SIZE);
+        Ok(descriptor.value[..size].to_vec())
+    }
+
+    /// Parameters describing the device as the kernel knows it: name, physical path, unique
+    /// identifier, bus, IDs and descriptor. Version and country are not available through
+    /// hidraw and are left at 0.
+    pub fn create_params(&self) -> io::Result<CreateParams> {
+        let mut info = DevInfo::default();
+        self.ioctl(HIDIOCGRAWINFO, &mut info)?;
+        let bus = Bus::try_from(info.bustype).map_err(|bus| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown bus type {}", bus),
+            )
+        })?;
+        Ok(CreateParams {
+            name: self.string(RAW_NAME)?,
+            phys: self.string(RAW_PHYS)?,
+            /* Older kernels lack HIDIOCGRAWUNIQ */
+            uniq: self.string(RAW_UNIQ).unwrap_or_default(),
+            bus,
+            vendor: info.vendor as u16 as u32,
+            product: info.product as u16 as u32,
+            version: 0,
+            country: 0,
+            rd_data: self.descriptor()?,
+        })
+    }
+
+    /// Reads a report with GET_REPORT. The result starts with the report number.
+    pub fn get_report(&self, report_type: ReportType, report_number: u8) -> io::Result<Vec<u8>> {
+        let number = match report_type {
+            ReportType::Feature => GET_FEATURE,
+            ReportType::Output => GET_OUTPUT,
+            ReportType::Input => GET_INPUT,
+        };
+        let mut buffer = vec![0; MAX_REPORT_SIZE];
+        buffer[0] = report_number;
+        let size = self.ioctl(
+            ioc(IOC_READ | IOC_WRITE, number, buffer.len()),
+            buffer.as_mut_ptr(),
+        )?;
+        buffer.truncate(size);
+        Ok(buffer)
+    }
+
+    /// Sends a report with SET_REPORT. `data` starts with the report number, 0 when the device
+    /// does not use numbered reports.
+    pub fn set_report(&self, report_type: ReportType, data: &[u8]) -> io::Result<usize> {
+        let number = match report_type {
+            ReportType::Feature => SET_FEATURE,
+            ReportType::Output => SET_OUTPUT,
+            ReportType::Input => SET_INPUT,
+        };
+        let mut buffer = data.to_vec();
+        self.ioctl(
+            ioc(IOC_READ | IOC_WRITE, number, buffer.len()),
+            buffer.as_mut_ptr(),
+        )
+    }
+}
+
+impl Read for Hidraw {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for Hidraw {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl AsRawFd for Hidraw {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_ioctl_requests() {
+        assert_eq!(HIDIOCGRDESCSIZE, 0x8004_4801);
+        assert_eq!(HIDIOCGRDESC, 0x9004_4802);
+        assert_eq!(HIDIOCGRAWINFO, 0x8008_4803);
+        assert_eq!(ioc(IOC_READ | IOC_WRITE, GET_FEATURE, 64), 0xc040_4807);
+    }
+}
@@ -1,5 +1,26 @@
 mod codec;
+mod descriptor;
+pub mod devices;
+mod event_log;
+mod hidraw;
+mod proxy;
+mod recording;
+mod report_descriptor;
+#[cfg(feature = "serde")]
+mod serde_support;
+#[cfg(feature = "spec")]
+mod spec;
+mod sysfs;
 mod uhid_device;
 
 pub use codec::*;
+pub use descriptor::*;
+pub use event_log::*;
+pub use hidraw::*;
+pub use proxy::*;
+pub use recording::*;
+pub use report_descriptor::*;
+#[cfg(feature = "spec")]
+pub use spec::*;
+pub use sysfs::KernelNodes;
 pub use uhid_device::*;
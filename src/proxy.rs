@@ -0,0 +1,319 @@
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::codec::{OutputEvent, ReportType, StreamError};
+use crate::hidraw::{Hidraw, MAX_REPORT_SIZE};
+use crate::uhid_device::{CreateParams, UHIDDevice};
+
+/// Sees every report crossing a `Proxy` and may change or drop it. All methods default to
+/// forwarding reports untouched.
+pub trait ProxyHook {
+    /// An input report read from the real device, forwarded unless this returns false
+    fn input(&mut self, _report: &mut Vec<u8>) -> bool {
+        true
+    }
+
+    /// An output report written by the host, forwarded unless this returns false
+    fn output(&mut self, _report: &mut Vec<u8>) -> bool {
+        true
+    }
+
+    /// A SET_REPORT request on its way to the real device. Dropped requests fail with EPERM.
+    fn set_report(&mut self, _report_type: ReportType, _report: &mut Vec<u8>) -> bool {
+        true
+    }
+
+    /// The answer of the real device to a GET_REPORT request, or the errno it failed with
+    fn get_report(
+        &mut self,
+        _report_type: ReportType,
+        _report_number: u8,
+        _reply: &mut Result<Vec<u8>, u16>,
+    ) {
+    }
+}
+
+/// A hook forwarding everything as is
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Passthrough;
+
+impl ProxyHook for Passthrough {}
+
+fn errno(error: &io::Error) -> u16 {
+    error.raw_os_error().unwrap_or(libc::EIO) as u16
+}
+
+/// Interposes a virtual device on a real one: input reports of the hidraw node go up through
+/// the UHID device, while output reports and GET_REPORT/SET_REPORT requests go down with the
+/// hidraw ioctls, their replies coming back up.
+pub struct Proxy<H: ProxyHook, T: Read + Write = File> {
+    hidraw: Hidraw,
+    device: UHIDDevice<T>,
+    hook: H,
+}
+
+impl<H: ProxyHook> Proxy<H> {
+    /// Opens a hidraw node and creates a UHID device with its parameters, as changed by
+    /// `rewrite`
+    pub fn open<F: FnOnce(&mut CreateParams)>(
+        path: &Path,
+        rewrite: F,
+        hook: H,
+    ) -> io::Result<Proxy<H>> {
+        let hidraw = Hidraw::open(path)?;
+        let mut params = hidraw.create_params()?;
+        rewrite(&mut params);
+        let device = UHIDDevice::create(params)?;
+        Ok(Proxy::new(hidraw, device, hook))
+    }
+}
+
+impl<H: ProxyHook, T: Read + Write> Proxy<H, T> {
+    pub fn new(hidraw: Hidraw, device: UHIDDevice<T>, hook: H) -> Proxy<H, T> {
+        Proxy {
+            hidraw,
+            device,
+            hook,
+        }
+    }
+
+    pub fn hook(&self) -> &H {
+        &self.hook
+    }
+
+    pub fn hook_mut(&mut self) -> &mut H {
+        &mut self.hook
+    }
+
+    /// Reads one input report from the real device and sends it through the virtual one
+    pub fn forward_input(&mut self) -> io::Result<usize> {
+        let mut report = vec![0; MAX_REPORT_SIZE];
+        let size = self.hidraw.read(&mut report)?;
+        report.truncate(size);
+        if !self.hook.input(&mut report) {
+            return Ok(0);
+        }
+        self.device.write(&report)
+    }
+
+    /// Reads one event from the virtual device and carries it out on the real one
+    pub fn forward_output(&mut self) -> io::Result<()> {
+        let event = match self.device.read() {
+            Ok(event) => event,
+            Err(StreamError::Io(error)) => return Err(error),
+            Err(StreamError::UnknownEventType(_)) => return Ok(()),
+        };
+        match event {
+            OutputEvent::Output { mut data } => {
+                let forward = self.hook.output(&mut data);
+                if forward {
+                    self.hidraw.write_all(&data)?;
+                }
+            }
+            OutputEvent::GetReport {
+                id,
+                report_number,
+                report_type,
+            } => {
+                let mut reply = self
+                    .hidraw
+                    .get_report(report_type, report_number)
+                    .map_err(|error| errno(&error));
+                self.hook.get_report(report_type, report_number, &mut reply);
+                match reply {
+                    Ok(data) => self.device.write_get_report_reply(id, 0, data)?,
+                    Err(err) => self.device.write_get_report_reply(id, err, Vec::new())?,
+                };
+            }
+            OutputEvent::SetReport {
+                id,
+                report_type,
+                mut data,
+                ..
+            } => {
+                let err = if !self.hook.set_report(report_type, &mut data) {
+                    libc::EPERM as u16
+                } else {
+                    match self.hidraw.set_report(report_type, &data) {
+                        Ok(_) => 0,
+                        Err(error) => errno(&error),
+                    }
+                };
+                self.device.write_set_report_reply(id, err)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl<H: ProxyHook, T: Read + Write + AsRawFd> Proxy<H, T> {
+    /// Forwards reports both ways until the real device goes away, then destroys the virtual
+    /// device
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.hidraw.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.device.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        loop {
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, -1) } < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+            if fds[0].revents & (libc::POLLHUP | libc::POLLERR) != 0 {
+                self.device.destroy()?;
+                return Ok(());
+            }
+            if fds[0].revents & libc::POLLIN != 0 {
+                self.forward_input()?;
+            }
+            if fds[1].revents & libc::POLLIN != 0 {
+                self.forward_output()?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Bus, InputEvent, OwnedInputEvent, UHID_EVENT_SIZE};
+    use std::convert::TryFrom;
+    use std::os::unix::io::OwnedFd;
+    use std::os::unix::net::UnixStream;
+
+    /// Rewrites output reports, drops report 0xff and SET_REPORT 3, and answers failed
+    /// GET_REPORT requests itself
+    struct Rewrite;
+
+    impl ProxyHook for Rewrite {
+        fn output(&mut self, report: &mut Vec<u8>) -> bool {
+            report[1] = 0x42;
+            report[0] != 0xff
+        }
+
+        fn set_report(&mut self, _report_type: ReportType, report: &mut Vec<u8>) -> bool {
+            report[0] != 3
+        }
+
+        fn get_report(
+            &mut self,
+            _report_type: ReportType,
+            report_number: u8,
+            reply: &mut Result<Vec<u8>, u16>,
+        ) {
+            if reply.is_err() {
+                *reply = Ok(vec![report_number, 0x99]);
+            }
+        }
+    }
+
+    /// An event of the kernel, laid out like struct uhid_event
+    fn event(event_type: u8, id: u32, report_number: u8, data: &[u8]) -> Vec<u8> {
+        let mut event = vec![0; UHID_EVENT_SIZE];
+        event[0] = event_type;
+        if event_type == 6 {
+            /* UHID_OUTPUT of an output report */
+            event[4..4 + data.len()].copy_from_slice(data);
+            event[4100..4102].copy_from_slice(&(data.len() as u16).to_le_bytes());
+            event[4102] = 1;
+        } else {
+            event[4..8].copy_from_slice(&id.to_le_bytes());
+            event[8] = report_number;
+            event[10..12].copy_from_slice(&(data.len() as u16).to_le_bytes());
+            event[12..12 + data.len()].copy_from_slice(data);
+        }
+        event
+    }
+
+    fn reply(kernel: &mut UnixStream) -> OwnedInputEvent {
+        let mut event = [0; UHID_EVENT_SIZE];
+        kernel.read_exact(&mut event).unwrap();
+        match InputEvent::try_from(&event) {
+            Ok(event) => event.into(),
+            Err(_) => panic!("invalid event"),
+        }
+    }
+
+    #[test]
+    fn forwards_reports_through_hook() {
+        let (uhid, mut kernel) = UnixStream::pair().unwrap();
+        let params = CreateParams {
+            name: "test-device".to_string(),
+            phys: "".to_string(),
+            uniq: "".to_string(),
+            bus: Bus::USB,
+            vendor: 0x15d9,
+            product: 0x0a37,
+            version: 0,
+            country: 0,
+            rd_data: Vec::new(),
+        };
+        let device = UHIDDevice::create_with_handle(params, uhid).unwrap();
+        reply(&mut kernel);
+        let (raw, mut real) = UnixStream::pair().unwrap();
+        let hidraw = Hidraw::from_file(File::from(OwnedFd::from(raw)));
+        let mut proxy = Proxy::new(hidraw, device, Rewrite);
+
+        real.write_all(&[1, 2, 3]).unwrap();
+        proxy.forward_input().unwrap();
+        assert_eq!(
+            reply(&mut kernel),
+            OwnedInputEvent::Input {
+                data: vec![1, 2, 3],
+            }
+        );
+
+        kernel.write_all(&event(6, 0, 0, &[0xff, 0])).unwrap();
+        kernel.write_all(&event(6, 0, 0, &[1, 0])).unwrap();
+        proxy.forward_output().unwrap();
+        proxy.forward_output().unwrap();
+        let mut report = [0; 2];
+        real.read_exact(&mut report).unwrap();
+        assert_eq!(report, [1, 0x42]);
+
+        kernel.write_all(&event(9, 5, 2, &[])).unwrap(); /* UHID_GET_REPORT */
+        proxy.forward_output().unwrap();
+        assert_eq!(
+            reply(&mut kernel),
+            OwnedInputEvent::GetReportReply {
+                id: 5,
+                err: 0,
+                data: vec![2, 0x99],
+            }
+        );
+
+        kernel.write_all(&event(13, 6, 3, &[3, 1])).unwrap(); /* UHID_SET_REPORT */
+        kernel.write_all(&event(13, 7, 1, &[1, 5])).unwrap();
+        proxy.forward_output().unwrap();
+        proxy.forward_output().unwrap();
+        assert_eq!(
+            reply(&mut kernel),
+            OwnedInputEvent::SetReportReply {
+                id: 6,
+                err: libc::EPERM as u16,
+            }
+        );
+        /* The ioctl reaches the socket standing in for hidraw, which rejects it */
+        assert_eq!(
+            reply(&mut kernel),
+            OwnedInputEvent::SetReportReply {
+                id: 7,
+                err: libc::ENOTTY as u16,
+            }
+        );
+    }
+}
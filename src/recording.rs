@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::{self, prelude::*};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::codec::Bus;
+use crate::uhid_device::{CreateParams, UHIDDevice};
+
+/// An input report of a recording, timed from the start of the recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedEvent {
+    pub time: Duration,
+    pub data: Vec<u8>,
+}
+
+/// A device and its input reports in the hid-tools format written by hid-recorder and read
+/// by hid-replay: `N:` name, `P:` physical path, `I:` bus, vendor and product, `R:` report
+/// descriptor and one `E:` line per report. Version, country and uniq are not recorded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording {
+    pub params: CreateParams,
+    pub events: Vec<RecordedEvent>,
+}
+
+fn invalid(line: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line, message),
+    )
+}
+
+/// Parses a length followed by that many hex bytes
+fn parse_bytes<'a, I: Iterator<Item = &'a str>>(line: usize, mut words: I) -> io::Result<Vec<u8>> {
+    let length: usize = words
+        .next()
+        .and_then(|word| word.parse().ok())
+        .ok_or_else(|| invalid(line, "missing length"))?;
+    let bytes = words
+        .map(|word| u8::from_str_radix(word, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| invalid(line, "invalid byte"))?;
+    if bytes.len() != length {
+        return Err(invalid(line, "length does not match the bytes"));
+    }
+    Ok(bytes)
+}
+
+fn parse_hex(line: usize, word: Option<&str>) -> io::Result<u32> {
+    word.and_then(|word| u32::from_str_radix(word, 16).ok())
+        .ok_or_else(|| invalid(line, "invalid device info"))
+}
+
+fn parse_time(line: usize, word: Option<&str>) -> io::Result<Duration> {
+    let (seconds, micros) = word
+        .and_then(|word| word.split_once('.'))
+        .ok_or_else(|| invalid(line, "invalid timestamp"))?;
+    match (seconds.parse(), micros.parse()) {
+        (Ok(seconds), Ok(micros)) => Duration::from_secs(seconds)
+            .checked_add(Duration::from_micros(micros))
+            .ok_or_else(|| invalid(line, "invalid timestamp")),
+        _ => Err(invalid(line, "invalid timestamp")),
+    }
+}
+
+impl Recording {
+    /// An empty recording of a device created with `params`
+    pub fn new(params: CreateParams) -> Recording {
+        Recording {
+            params,
+            events: Vec::new(),
+        }
+    }
+
+    /// Parses a recording, one `Recording` per device of multi-device recordings in the order
+    /// of their `D:` index. A `D:` line switches the device the following lines belong to.
+    pub fn parse_all(text: &str) -> io::Result<Vec<Recording>> {
+        let mut recordings: BTreeMap<usize, Recording> = BTreeMap::new();
+        let mut current = 0;
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (tag, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid(number, "missing tag"))?;
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            if tag == "D" {
+                current = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid(number, "invalid device index"))?;
+                continue;
+            }
+            let recording = recordings.entry(current).or_insert_with(|| {
+                Recording::new(CreateParams {
+                    name: String::new(),
+                    phys: String::new(),
+                    uniq: String::new(),
+                    bus: Bus::USB,
+                    vendor: 0,
+                    product: 0,
+                    version: 0,
+                    country: 0,
+                    rd_data: Vec::new(),
+                })
+            });
+            let mut words = value.split_whitespace();
+            match tag {
+                "N" => recording.params.name = value.to_string(),
+                "P" => recording.params.phys = value.to_string(),
+                "R" => recording.params.rd_data = parse_bytes(number, words)?,
+                "I" => {
+                    let bus = parse_hex(number, words.next())?;
+                    recording.params.bus =
+                        Bus::try_from(bus).map_err(|_| invalid(number, "unknown bus"))?;
+                    recording.params.vendor = parse_hex(number, words.next())?;
+                    recording.params.product = parse_hex(number, words.next())?;
+                }
+                "E" => {
+                    let time = parse_time(number, words.next())?;
+                    let data = parse_bytes(number, words)?;
+                    recording.events.push(RecordedEvent { time, data });
+                }
+                /* Other tags, such as the evdev events of newer hid-recorder versions */
+                _ => {}
+            }
+        }
+        Ok(recordings.into_values().collect())
+    }
+
+    /// Parses a recording of a single device, or the first device of a multi-device recording
+    pub fn parse(text: &str) -> io::Result<Recording> {
+        Recording::parse_all(text)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| invalid(0, "no device in recording"))
+    }
+
+    pub fn load(path: &Path) -> io::Result<Recording> {
+        Recording::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    /// Appends an input report sent `time` after the start of the recording
+    pub fn record(&mut self, time: Duration, data: &[u8]) {
+        self.events.push(RecordedEvent {
+            time,
+            data: data.to_vec(),
+        });
+    }
+
+    /// Sends the recorded reports through `device`, spaced like in the recording with delays
+    /// multiplied by `scale`: 1.0 keeps the original timing, 0.5 plays twice as fast and 0.0
+    /// sends everything at once, while infinite or NaN scales are rejected. The device should be
+    /// opened first, see `wait_opened`.
+    pub fn replay<T: Read + Write>(
+        &self,
+        device: &mut UHIDDevice<T>,
+        scale: f64,
+    ) -> io::Result<()> {
+        if !scale.is_finite() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid replay scale {}", scale),
+            ));
+        }
+        let first = match self.events.first() {
+            Some(event) => event.time,
+            None => return Ok(()),
+        };
+        let start = Instant::now();
+        for event in &self.events {
+            let due = start + event.time.saturating_sub(first).mul_f64(scale.max(0.0));
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+            device.write(&event.data)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_bytes(f: &mut fmt::Formatter, data: &[u8]) -> fmt::Result {
+    write!(f, "{}", data.len())?;
+    for byte in data {
+        write!(f, " {:02x}", byte)?;
+    }
+    writeln!(f)
+}
+
+/// Writes the recording in the hid-tools format
+impl fmt::Display for Recording {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params = &self.params;
+        writeln!(f, "D: 0")?;
+        write!(f, "R: ")?;
+        write_bytes(f, &params.rd_data)?;
+        writeln!(f, "N: {}", params.name)?;
+        writeln!(f, "P: {}", params.phys)?;
+        writeln!(
+            f,
+            "I: {:x} {:04x} {:04x}",
+            params.bus as u32, params.vendor, params.product
+        )?;
+        for event in &self.events {
+            write!(
+                f,
+                "E: {}.{:06} ",
+                event.time.as_secs(),
+                event.time.subsec_micros()
+            )?;
+            write_bytes(f, &event.data)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORDING: &str = "\
+# Logitech USB Receiver
+# 0x05, 0x01,                    // Usage Page (Generic Desktop)        0
+D: 0
+R: 6 05 01 09 02 a1 01
+N: Logitech USB Receiver
+P: usb-0000:00:14.0-2/input1
+I: 3 046d c52b
+E: 0.000000 5 02 00 fe ff 00
+E: 0.008012 5 02 00 ff ff 00
+";
+
+    #[test]
+    fn parses_and_writes_hid_recorder_output() {
+        let recording = Recording::parse(RECORDING).unwrap();
+        assert_eq!(recording.params.name, "Logitech USB Receiver");
+        assert_eq!(recording.params.bus, Bus::USB);
+        assert_eq!(
+            (recording.params.vendor, recording.params.product),
+            (0x046d, 0xc52b)
+        );
+        assert_eq!(
+            recording.params.rd_data,
+            vec![0x05, 0x01, 0x09, 0x02, 0xa1, 0x01]
+        );
+        assert_eq!(
+            recording.events[1],
+            RecordedEvent {
+                time: Duration::from_micros(8012),
+                data: vec![0x02, 0x00, 0xff, 0xff, 0x00],
+            }
+        );
+
+        let (handle, _kernel) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut device = UHIDDevice::create_with_handle(recording.params.clone(), handle).unwrap();
+        let error = recording.replay(&mut device, f64::INFINITY).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(parse_time(1, Some("18446744073709551615.1000000")).is_err());
+
+        let written = recording.to_string();
+        assert!(RECORDING.ends_with(written.as_str()));
+        assert_eq!(Recording::parse(&written).unwrap(), recording);
+    }
+
+    #[test]
+    fn splits_interleaved_devices() {
+        let recordings = Recording::parse_all(
+            "\
+D: 0
+R: 2 05 01
+N: Keyboard
+I: 3 046d c52b
+D: 1
+R: 2 05 0d
+N: Touchpad
+I: 18 06cb 7e7e
+D: 0
+E: 0.000000 2 01 02
+D: 1
+E: 0.001000 2 03 04
+D: 0
+E: 0.002000 2 05 06
+",
+        )
+        .unwrap();
+        assert_eq!(recordings.len(), 2);
+        assert_eq!(recordings[0].params.name, "Keyboard");
+        assert_eq!(recordings[1].params.name, "Touchpad");
+        assert_eq!(recordings[1].params.bus, Bus::I2C);
+        assert_eq!(recordings[1].params.product, 0x7e7e);
+        assert_eq!(
+            recordings[0]
+                .events
+                .iter()
+                .map(|event| event.data.clone())
+                .collect::<Vec<_>>(),
+            vec![vec![0x01, 0x02], vec![0x05, 0x06]]
+        );
+        assert_eq!(
+            recordings[1].events,
+            vec![RecordedEvent {
+                time: Duration::from_millis(1),
+                data: vec![0x03, 0x04],
+            }]
+        );
+    }
+}
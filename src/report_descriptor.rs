@@ -0,0 +1,568 @@
+use std::error::Error;
+use std::fmt;
+
+use enumflags2::BitFlags;
+
+use crate::codec::ReportType;
+use crate::descriptor::{MainFlags, ReportDescriptorBuilder};
+
+const TYPE_MAIN: u8 = 0;
+const TYPE_GLOBAL: u8 = 1;
+const TYPE_LOCAL: u8 = 2;
+const LONG_ITEM: u8 = 0xfe;
+
+const MAIN_INPUT: u8 = 0x8;
+const MAIN_OUTPUT: u8 = 0x9;
+const MAIN_COLLECTION: u8 = 0xa;
+const MAIN_FEATURE: u8 = 0xb;
+const MAIN_END_COLLECTION: u8 = 0xc;
+
+const GLOBAL_USAGE_PAGE: u8 = 0x0;
+const GLOBAL_LOGICAL_MINIMUM: u8 = 0x1;
+const GLOBAL_LOGICAL_MAXIMUM: u8 = 0x2;
+const GLOBAL_REPORT_SIZE: u8 = 0x7;
+const GLOBAL_REPORT_ID: u8 = 0x8;
+const GLOBAL_REPORT_COUNT: u8 = 0x9;
+const GLOBAL_PUSH: u8 = 0xa;
+const GLOBAL_POP: u8 = 0xb;
+
+const LOCAL_USAGE: u8 = 0x0;
+const LOCAL_USAGE_MINIMUM: u8 = 0x1;
+const LOCAL_USAGE_MAXIMUM: u8 = 0x2;
+
+/// Why a descriptor could not be parsed or rewritten
+#[derive(Debug, Clone, PartialEq)]
+pub enum DescriptorError {
+    /// An item runs past the end of the descriptor
+    Truncated {
+        offset: usize,
+    },
+    /// An End Collection without a Collection, or a Collection never closed
+    UnbalancedCollection {
+        offset: usize,
+    },
+    /// A Pop without a Push
+    UnbalancedPop {
+        offset: usize,
+    },
+    /// A main item whose report would grow past u32::MAX bits
+    ReportOverflow {
+        offset: usize,
+    },
+    NoSuchField(usize),
+    NoSuchCollection(usize),
+}
+
+impl fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DescriptorError::Truncated { offset } => {
+                write!(f, "item at offset {} is truncated", offset)
+            }
+            DescriptorError::UnbalancedCollection { offset } => {
+                write!(f, "unbalanced collection at offset {}", offset)
+            }
+            DescriptorError::UnbalancedPop { offset } => {
+                write!(f, "pop without push at offset {}", offset)
+            }
+            DescriptorError::ReportOverflow { offset } => {
+                write!(f, "report size overflows at offset {}", offset)
+            }
+            DescriptorError::NoSuchField(index) => write!(f, "no field {}", index),
+            DescriptorError::NoSuchCollection(index) => write!(f, "no collection {}", index),
+        }
+    }
+}
+
+impl Error for DescriptorError {}
+
+/// One item of a report descriptor, kept with its original encoding
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    /// First byte, holding tag, type and size; 0xFE for long items
+    prefix: u8,
+    data: Vec<u8>,
+}
+
+impl Item {
+    /// Item type: 0 for main, 1 for global, 2 for local items
+    pub fn item_type(&self) -> u8 {
+        (self.prefix >> 2) & 0x3
+    }
+
+    pub fn tag(&self) -> u8 {
+        self.prefix >> 4
+    }
+
+    pub fn is_long(&self) -> bool {
+        self.prefix == LONG_ITEM
+    }
+
+    fn is(&self, item_type: u8, tag: u8) -> bool {
+        !self.is_long() && self.item_type() == item_type && self.tag() == tag
+    }
+
+    pub fn unsigned(&self) -> u32 {
+        let mut bytes = [0; 4];
+        for (i, byte) in self.data.iter().take(4).enumerate() {
+            bytes[i] = *byte;
+        }
+        u32::from_le_bytes(bytes)
+    }
+
+    pub fn signed(&self) -> i32 {
+        match self.data.len() {
+            0 => 0,
+            1 => self.data[0] as i8 as i32,
+            2 => i16::from_le_bytes([self.data[0], self.data[1]]) as i32,
+            _ => self.unsigned() as i32,
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.prefix);
+        out.extend_from_slice(&self.data);
+    }
+}
+
+fn parse_items(data: &[u8]) -> Result<Vec<Item>, DescriptorError> {
+    let mut items = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let prefix = data[offset];
+        let (start, size) = if prefix == LONG_ITEM {
+            /* Data size and long item tag follow the prefix; the tag is kept in the data */
+            let size = *data
+                .get(offset + 1)
+                .ok_or(DescriptorError::Truncated { offset })?;
+            (offset + 1, size as usize + 2)
+        } else {
+            let size = match prefix & 0x3 {
+                3 => 4,
+                size => size as usize,
+            };
+            (offset + 1, size)
+        };
+        let item_data = data
+            .get(start..start + size)
+            .ok_or(DescriptorError::Truncated { offset })?;
+        items.push(Item {
+            prefix,
+            data: item_data.to_vec(),
+        });
+        offset = start + size;
+    }
+    Ok(items)
+}
+
+/// An Input, Output or Feature main item with the state it was declared in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub report_type: ReportType,
+    pub report_id: u8,
+    pub flags: BitFlags<MainFlags>,
+    /// Usages as extended usages, with their usage page in the high 16 bits
+    pub usages: Vec<u32>,
+    /// Usage Minimum and Usage Maximum, as extended usages
+    pub usage_range: Option<(u32, u32)>,
+    pub logical_minimum: i32,
+    pub logical_maximum: i32,
+    pub report_size: u32,
+    pub report_count: u32,
+    /// Position of the first bit in the report, not counting the report ID
+    pub bit_offset: u32,
+    /// Index of the main item in the descriptor
+    item: usize,
+}
+
+/// A collection of the descriptor, in order of appearance
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectionInfo {
+    /// Collection type, see `Collection`; vendor-defined types are 0x80 and above
+    pub kind: u8,
+    pub usage: Option<u32>,
+    /// 0 for top-level collections
+    pub depth: usize,
+    item: usize,
+}
+
+/// A report that a rewrite moved, resized or removed fields of
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LayoutShift {
+    pub report_type: ReportType,
+    pub report_id: u8,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Globals {
+    usage_page: u32,
+    logical_minimum: Option<Item>,
+    logical_maximum: Option<Item>,
+    report_size: u32,
+    report_id: u8,
+    report_count: u32,
+}
+
+/// Slots of each report: type, ID and the offset, size and count of its fields
+type Layout = Vec<(ReportType, u8, Vec<(u32, u32, u32)>)>;
+
+/// A parsed report descriptor that can be rewritten, like kernel `report_fixup` hooks do, and
+/// encoded back for `CreateParams::rd_data`. Untouched items keep their original encoding, so
+/// parsing and building gives the same bytes back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportDescriptor {
+    items: Vec<Item>,
+    original: Layout,
+}
+
+impl ReportDescriptor {
+    pub fn parse(data: &[u8]) -> Result<ReportDescriptor, DescriptorError> {
+        let mut descriptor = ReportDescriptor {
+            items: parse_items(data)?,
+            original: Vec::new(),
+        };
+        descriptor.original = descriptor.layout()?;
+        Ok(descriptor)
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for item in &self.items {
+            item.encode(&mut data);
+        }
+        data
+    }
+
+    /// Walks the items, keeping track of the state like the kernel parser
+    fn walk(&self) -> Result<(Vec<Field>, Vec<CollectionInfo>), DescriptorError> {
+        let mut globals = Globals::default();
+        let mut stack = Vec::new();
+        let mut usages = Vec::new();
+        let mut usage_minimum = None;
+        let mut usage_maximum = None;
+        let mut fields = Vec::new();
+        let mut collections = Vec::new();
+        let mut depth = 0;
+        let mut offsets: Vec<(ReportType, u8, u32)> = Vec::new();
+
+        let mut position = 0;
+        for (index, item) in self.items.iter().enumerate() {
+            let offset = position;
+            position += 1 + item.data.len();
+            if item.is_long() {
+                continue;
+            }
+            let extended = |item: &Item, page: u32| match item.data.len() {
+                4 => item.unsigned(),
+                _ => page << 16 | item.unsigned(),
+            };
+            match (item.item_type(), item.tag()) {
+                (TYPE_GLOBAL, GLOBAL_USAGE_PAGE) => globals.usage_page = item.unsigned(),
+                (TYPE_GLOBAL, GLOBAL_LOGICAL_MINIMUM) => {
+                    globals.logical_minimum = Some(item.clone())
+                }
+                (TYPE_GLOBAL, GLOBAL_LOGICAL_MAXIMUM) => {
+                    globals.logical_maximum = Some(item.clone())
+                }
+                (TYPE_GLOBAL, GLOBAL_REPORT_SIZE) => globals.report_size = item.unsigned(),
+                (TYPE_GLOBAL, GLOBAL_REPORT_ID) => globals.report_id = item.unsigned() as u8,
+                (TYPE_GLOBAL, GLOBAL_REPORT_COUNT) => globals.report_count = item.unsigned(),
+                (TYPE_GLOBAL, GLOBAL_PUSH) => stack.push(globals.clone()),
+                (TYPE_GLOBAL, GLOBAL_POP) => {
+                    globals = stack
+                        .pop()
+                        .ok_or(DescriptorError::UnbalancedPop { offset })?
+                }
+                (TYPE_LOCAL, LOCAL_USAGE) => usages.push(extended(item, globals.usage_page)),
+                (TYPE_LOCAL, LOCAL_USAGE_MINIMUM) => {
+                    usage_minimum = Some(extended(item, globals.usage_page))
+                }
+                (TYPE_LOCAL, LOCAL_USAGE_MAXIMUM) => {
+                    usage_maximum = Some(extended(item, globals.usage_page))
+                }
+                (TYPE_MAIN, tag) => {
+                    match tag {
+                        MAIN_COLLECTION => {
+                            collections.push(CollectionInfo {
+                                kind: item.unsigned() as u8,
+                                usage: usages.first().copied(),
+                                depth,
+                                item: index,
+                            });
+                            depth += 1;
+                        }
+                        MAIN_END_COLLECTION => {
+                            depth = depth
+                                .checked_sub(1)
+                                .ok_or(DescriptorError::UnbalancedCollection { offset })?;
+                        }
+                        MAIN_INPUT | MAIN_OUTPUT | MAIN_FEATURE => {
+                            let report_type = match tag {
+                                MAIN_INPUT => ReportType::Input,
+                                MAIN_OUTPUT => ReportType::Output,
+                                _ => ReportType::Feature,
+                            };
+                            let logical_minimum =
+                                globals.logical_minimum.as_ref().map_or(0, Item::signed);
+                            /* Like the kernel, maxima are unsigned when the minimum is positive */
+                            let logical_maximum = match &globals.logical_maximum {
+                                Some(maximum) if logical_minimum < 0 => maximum.signed(),
+                                Some(maximum) => maximum.unsigned() as i32,
+                                None => 0,
+                            };
+                            let bits = globals
+                                .report_size
+                                .checked_mul(globals.report_count)
+                                .ok_or(DescriptorError::ReportOverflow { offset })?;
+                            let bit_offset = match offsets.iter_mut().find(|(kind, id, _)| {
+                                *kind == report_type && *id == globals.report_id
+                            }) {
+                                Some((_, _, next)) => {
+                                    let bit_offset = *next;
+                                    *next = next
+                                        .checked_add(bits)
+                                        .ok_or(DescriptorError::ReportOverflow { offset })?;
+                                    bit_offset
+                                }
+                                None => {
+                                    offsets.push((report_type, globals.report_id, bits));
+                                    0
+                                }
+                            };
+                            fields.push(Field {
+                                report_type,
+                                report_id: globals.report_id,
+                                flags: BitFlags::from_bits_truncate(item.unsigned() as u16),
+                                usages: usages.clone(),
+                                usage_range: usage_minimum.zip(usage_maximum),
+                                logical_minimum,
+                                logical_maximum,
+                                report_size: globals.report_size,
+                                report_count: globals.report_count,
+                                bit_offset,
+                                item: index,
+                            });
+                        }
+                        _ => {}
+                    }
+                    usages.clear();
+                    usage_minimum = None;
+                    usage_maximum = None;
+                }
+                _ => {}
+            }
+        }
+        if depth != 0 {
+            return Err(DescriptorError::UnbalancedCollection { offset: position });
+        }
+        Ok((fields, collections))
+    }
+
+    pub fn fields(&self) -> Result<Vec<Field>, DescriptorError> {
+        Ok(self.walk()?.0)
+    }
+
+    pub fn collections(&self) -> Result<Vec<CollectionInfo>, DescriptorError> {
+        Ok(self.walk()?.1)
+    }
+
+    /// Index in `fields` of the first field with `usage`, given as an extended usage
+    pub fn find_field(&self, usage: u32) -> Result<Option<usize>, DescriptorError> {
+        Ok(self.fields()?.iter().position(|field| {
+            field.usages.contains(&usage)
+                || field
+                    .usage_range
+                    .is_some_and(|(min, max)| (min..=max).contains(&usage))
+        }))
+    }
+
+    fn layout(&self) -> Result<Layout, DescriptorError> {
+        let mut layout: Layout = Vec::new();
+        for field in self.fields()? {
+            let slot = (field.bit_offset, field.report_size, field.report_count);
+            match layout
+                .iter_mut()
+                .find(|(kind, id, _)| *kind == field.report_type && *id == field.report_id)
+            {
+                Some((_, _, slots)) => slots.push(slot),
+                None => layout.push((field.report_type, field.report_id, vec![slot])),
+            }
+        }
+        Ok(layout)
+    }
+
+    /// Reports that existed in the parsed descriptor and whose fields no longer sit at the same
+    /// place, meaning reports sent for the original layout would be misread
+    pub fn layout_shifts(&self) -> Result<Vec<LayoutShift>, DescriptorError> {
+        let layout = self.layout()?;
+        Ok(self
+            .original
+            .iter()
+            .filter(|original| !layout.contains(original))
+            .map(|(report_type, report_id, _)| LayoutShift {
+                report_type: *report_type,
+                report_id: *report_id,
+            })
+            .collect())
+    }
+
+    fn field_item(&self, field: usize) -> Result<usize, DescriptorError> {
+        self.fields()?
+            .get(field)
+            .map(|field| field.item)
+            .ok_or(DescriptorError::NoSuchField(field))
+    }
+
+    /// Changes the logical range of one field, by index in `fields`. The new range is set
+    /// between Push and Pop items so the following fields keep theirs.
+    pub fn set_logical_range(
+        &mut self,
+        field: usize,
+        minimum: i32,
+        maximum: i32,
+    ) -> Result<(), DescriptorError> {
+        let item = self.field_item(field)?;
+        let before = parse_items(
+            &ReportDescriptorBuilder::new()
+                .push()
+                .logical_minimum(minimum)
+                .logical_maximum(maximum)
+                .build(),
+        )?;
+        let after = parse_items(&ReportDescriptorBuilder::new().pop().build())?;
+        self.items.splice(item + 1..item + 1, after);
+        self.items.splice(item..item, before);
+        Ok(())
+    }
+
+    /// Replaces every Usage item resolving to `old` with `new`, both extended usages. Usage
+    /// ranges are left alone. Returns how many items were replaced.
+    pub fn replace_usage(&mut self, old: u32, new: u32) -> usize {
+        let mut usage_page = 0;
+        let mut stack = Vec::new();
+        let mut replaced = 0;
+        for item in self.items.iter_mut() {
+            if item.is(TYPE_GLOBAL, GLOBAL_USAGE_PAGE) {
+                usage_page = item.unsigned();
+            } else if item.is(TYPE_GLOBAL, GLOBAL_PUSH) {
+                stack.push(usage_page);
+            } else if item.is(TYPE_GLOBAL, GLOBAL_POP) {
+                usage_page = stack.pop().unwrap_or(usage_page);
+            } else if item.is(TYPE_LOCAL, LOCAL_USAGE) {
+                let usage = match item.data.len() {
+                    4 => item.unsigned(),
+                    _ => usage_page << 16 | item.unsigned(),
+                };
+                if usage == old {
+                    /* Always four bytes, so the usage page of the new usage is explicit */
+                    *item = Item {
+                        prefix: LOCAL_USAGE << 4 | TYPE_LOCAL << 2 | 3,
+                        data: new.to_le_bytes().to_vec(),
+                    };
+                    replaced += 1;
+                }
+            }
+        }
+        replaced
+    }
+
+    /// Removes a collection, by index in `collections`, with its usages and everything inside.
+    /// Global items inside are kept so the state seen by later items stays the same.
+    pub fn remove_collection(&mut self, collection: usize) -> Result<(), DescriptorError> {
+        let start = self
+            .collections()?
+            .get(collection)
+            .map(|collection| collection.item)
+            .ok_or(DescriptorError::NoSuchCollection(collection))?;
+        let mut end = start;
+        let mut depth = 0;
+        for (index, item) in self.items.iter().enumerate().skip(start) {
+            if item.is(TYPE_MAIN, MAIN_COLLECTION) {
+                depth += 1;
+            } else if item.is(TYPE_MAIN, MAIN_END_COLLECTION) {
+                depth -= 1;
+                if depth == 0 {
+                    end = index;
+                    break;
+                }
+            }
+        }
+        /* Local items right before the collection belong to it */
+        let mut first = start;
+        while first > 0 && self.items[first - 1].item_type() == TYPE_LOCAL {
+            first -= 1;
+        }
+        let mut index = 0;
+        self.items.retain(|item| {
+            let inside = (first..=end).contains(&index);
+            index += 1;
+            !inside || (item.item_type() == TYPE_GLOBAL && !item.is_long())
+        });
+        Ok(())
+    }
+
+    /// Appends encoded items, typically a collection declaring a new report built with a
+    /// `ReportDescriptorBuilder`. They start from the global state left by the last item.
+    pub fn append(&mut self, items: &[u8]) -> Result<(), DescriptorError> {
+        self.items.extend(parse_items(items)?);
+        self.walk()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::descriptor::Collection;
+    use crate::devices::{Mouse, Preset};
+
+    #[test]
+    fn rewrites_and_detects_layout_shifts() {
+        let original = Mouse::new(1).descriptor();
+        let mut rd = ReportDescriptor::parse(&original).unwrap();
+        assert_eq!(rd.build(), original);
+
+        let x = rd.find_field(0x01_0030).unwrap().unwrap();
+        rd.set_logical_range(x, -100, 100).unwrap();
+        let fields = rd.fields().unwrap();
+        assert_eq!(
+            (fields[x].logical_minimum, fields[x].logical_maximum),
+            (-100, 100)
+        );
+        assert_eq!(rd.replace_usage(0x01_0030, 0x01_0033), 1);
+        assert_eq!(rd.find_field(0x01_0033).unwrap(), Some(x));
+        assert!(rd.layout_shifts().unwrap().is_empty());
+
+        rd.append(
+            &ReportDescriptorBuilder::new()
+                .usage_page(0xff00)
+                .usage(0x01)
+                .collection(Collection::Application)
+                .report_id(9)
+                .report_size(8)
+                .report_count(4)
+                .usage(0x01)
+                .feature(MainFlags::Variable.into())
+                .end_collection()
+                .build(),
+        )
+        .unwrap();
+        assert!(rd.layout_shifts().unwrap().is_empty());
+        rd.remove_collection(0).unwrap();
+        let shifts = rd.layout_shifts().unwrap();
+        assert_eq!(shifts.len(), 2);
+        assert!(shifts.iter().all(|shift| shift.report_id == 1));
+        assert_eq!(rd.fields().unwrap().len(), 1);
+        assert_eq!(rd.fields().unwrap()[0].report_id, 9);
+
+        /* Report Size 0xffffffff, Report Count 2, Input */
+        assert_eq!(
+            ReportDescriptor::parse(&[0x77, 0xff, 0xff, 0xff, 0xff, 0x95, 0x02, 0x81, 0x02]),
+            Err(DescriptorError::ReportOverflow { offset: 7 })
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Serialize, Serializer};
+
+use crate::codec::Bus;
+
+/// Hex-string encoding of descriptors and reports, such as "05010902". Whitespace between the
+/// bytes is accepted when reading, so that long descriptors can be split up in config files.
+pub(crate) mod hex {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let text: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+        serializer.serialize_str(&text)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        parse(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+
+    pub fn parse(text: &str) -> Result<Vec<u8>, String> {
+        let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+        if !digits.len().is_multiple_of(2) {
+            return Err("odd number of hex digits".to_string());
+        }
+        digits
+            .chunks(2)
+            .map(|pair| {
+                let byte: String = pair.iter().collect();
+                u8::from_str_radix(&byte, 16).map_err(|_| format!("invalid hex byte {:?}", byte))
+            })
+            .collect()
+    }
+}
+
+/// Written by name, such as "USB"
+impl Serialize for Bus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:?}", self))
+    }
+}
+
+struct BusVisitor;
+
+impl<'de> Visitor<'de> for BusVisitor {
+    type Value = Bus;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a bus name such as \"USB\" or a BUS_* number")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Bus, E> {
+        u32::try_from(value)
+            .ok()
+            .and_then(|value| Bus::try_from(value).ok())
+            .ok_or_else(|| E::custom(format!("unknown bus type {}", value)))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Bus, E> {
+        match u64::try_from(value) {
+            Ok(value) => self.visit_u64(value),
+            Err(_) => Err(E::custom(format!("unknown bus type {}", value))),
+        }
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Bus, E> {
+        (0..=31)
+            .filter_map(|bus| Bus::try_from(bus).ok())
+            .find(|bus| format!("{:?}", bus).eq_ignore_ascii_case(value))
+            .ok_or_else(|| E::custom(format!("unknown bus {:?}", value)))
+    }
+}
+
+/// Read from a name, in any case, or from the numeric value of the kernel
+impl<'de> serde::Deserialize<'de> for Bus {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Bus, D::Error> {
+        deserializer.deserialize_any(BusVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::{DevFlags, OutputEvent, OwnedInputEvent};
+    use crate::uhid_device::CreateParams;
+    use crate::Bus;
+
+    #[test]
+    fn round_trips_params_and_events() {
+        let params: CreateParams = serde_json::from_str(
+            r#"{"name": "test-device", "bus": 5, "vendor": 5593, "product": 2615,
+                "rd_data": "05 01 09 02"}"#,
+        )
+        .unwrap();
+        assert_eq!(params.bus, Bus::BLUETOOTH);
+        assert_eq!(params.rd_data, vec![0x05, 0x01, 0x09, 0x02]);
+        let json = serde_json::to_string(&params).unwrap();
+        assert!(json.contains(r#""bus":"BLUETOOTH""#));
+        assert!(json.contains(r#""rd_data":"05010902""#));
+        assert_eq!(serde_json::from_str::<CreateParams>(&json).unwrap(), params);
+        assert!(serde_json::from_str::<Bus>(r#""usb""#).unwrap() == Bus::USB);
+        assert!(serde_json::from_str::<Bus>("7").is_err());
+
+        let event = OwnedInputEvent::Input {
+            data: vec![0x01, 0xff],
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(json, r#"{"type":"input","data":"01ff"}"#);
+        assert_eq!(
+            serde_json::from_str::<OwnedInputEvent>(&json).unwrap(),
+            event
+        );
+
+        let event: OutputEvent =
+            serde_json::from_str(r#"{"type": "start", "dev_flags": ["FeatureReportsNumbered"]}"#)
+                .unwrap();
+        assert!(matches!(
+            event,
+            OutputEvent::Start { ref dev_flags } if dev_flags[..] == [DevFlags::FeatureReportsNumbered]
+        ));
+    }
+}
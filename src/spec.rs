@@ -0,0 +1,424 @@
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+use enumflags2::BitFlags;
+use serde::Deserialize;
+
+use crate::codec::{Bus, OutputEvent, ReportType, StreamError};
+use crate::descriptor::{Collection, MainFlags, ReportDescriptorBuilder};
+use crate::uhid_device::{CreateParams, UHIDDevice};
+
+/// One call of `ReportDescriptorBuilder`, such as `{ usage_page = 1 }` or `"end_collection"`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpecItem {
+    UsagePage(u16),
+    Usage(u32),
+    UsageMinimum(u32),
+    UsageMaximum(u32),
+    LogicalMinimum(i32),
+    LogicalMaximum(i32),
+    PhysicalMinimum(i32),
+    PhysicalMaximum(i32),
+    UnitExponent(i8),
+    Unit(u32),
+    ReportSize(u32),
+    ReportId(u8),
+    ReportCount(u32),
+    Push,
+    Pop,
+    Input(Vec<MainFlags>),
+    Output(Vec<MainFlags>),
+    Feature(Vec<MainFlags>),
+    Collection(Collection),
+    EndCollection,
+    Raw(#[serde(with = "crate::serde_support::hex")] Vec<u8>),
+}
+
+fn flags(flags: &[MainFlags]) -> BitFlags<MainFlags> {
+    flags
+        .iter()
+        .fold(BitFlags::empty(), |all, flag| all | *flag)
+}
+
+impl SpecItem {
+    fn describe(&self, rd: &mut ReportDescriptorBuilder) {
+        match self {
+            SpecItem::UsagePage(page) => rd.usage_page(*page),
+            SpecItem::Usage(usage) => rd.usage(*usage),
+            SpecItem::UsageMinimum(usage) => rd.usage_minimum(*usage),
+            SpecItem::UsageMaximum(usage) => rd.usage_maximum(*usage),
+            SpecItem::LogicalMinimum(value) => rd.logical_minimum(*value),
+            SpecItem::LogicalMaximum(value) => rd.logical_maximum(*value),
+            SpecItem::PhysicalMinimum(value) => rd.physical_minimum(*value),
+            SpecItem::PhysicalMaximum(value) => rd.physical_maximum(*value),
+            SpecItem::UnitExponent(exponent) => rd.unit_exponent(*exponent),
+            SpecItem::Unit(unit) => rd.unit(*unit),
+            SpecItem::ReportSize(bits) => rd.report_size(*bits),
+            SpecItem::ReportId(id) => rd.report_id(*id),
+            SpecItem::ReportCount(count) => rd.report_count(*count),
+            SpecItem::Push => rd.push(),
+            SpecItem::Pop => rd.pop(),
+            SpecItem::Input(main) => rd.input(flags(main)),
+            SpecItem::Output(main) => rd.output(flags(main)),
+            SpecItem::Feature(main) => rd.feature(flags(main)),
+            SpecItem::Collection(kind) => rd.collection(*kind),
+            SpecItem::EndCollection => rd.end_collection(),
+            SpecItem::Raw(items) => rd.raw(items),
+        };
+    }
+}
+
+/// Where the report descriptor of a spec comes from
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DescriptorSource {
+    /// Hex bytes, such as "05 01 09 02 a1 01 ..."
+    Hex(#[serde(with = "crate::serde_support::hex")] Vec<u8>),
+    /// A binary file, relative to the spec file when loaded with `DeviceSpec::load` or
+    /// `DeviceSpec::load_with`
+    Path(PathBuf),
+    Items(Vec<SpecItem>),
+}
+
+/// A report the device answers GET_REPORT requests with. `data` is the whole report, starting
+/// with the report number when the device uses numbered reports. SET_REPORT requests replace it
+/// unless it is `fixed`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SpecReport {
+    #[serde(default = "feature_report")]
+    pub report_type: ReportType,
+    #[serde(default)]
+    pub id: u8,
+    #[serde(with = "crate::serde_support::hex")]
+    pub data: Vec<u8>,
+    #[serde(default)]
+    pub fixed: bool,
+}
+
+fn feature_report() -> ReportType {
+    ReportType::Feature
+}
+
+fn invalid_spec<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// A whole virtual device defined in a TOML file, or a YAML one with the `yaml` feature:
+///
+/// ```toml
+/// name = "Test device"
+/// bus = "USB"
+/// vendor = 0x15d9
+/// product = 0x0a37
+/// descriptor.path = "device.bin"
+///
+/// [[reports]]
+/// id = 1
+/// data = "01 64"
+/// ```
+///
+/// Other formats can be read with `load_with`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DeviceSpec {
+    pub name: String,
+    #[serde(default)]
+    pub phys: String,
+    #[serde(default)]
+    pub uniq: String,
+    pub bus: Bus,
+    pub vendor: u32,
+    pub product: u32,
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub country: u32,
+    pub descriptor: DescriptorSource,
+    #[serde(default)]
+    pub reports: Vec<SpecReport>,
+    /// Directory descriptor paths are relative to
+    #[serde(skip)]
+    pub base: Option<PathBuf>,
+}
+
+impl DeviceSpec {
+    /// Parses a spec in TOML
+    pub fn parse(text: &str) -> io::Result<DeviceSpec> {
+        toml::from_str(text).map_err(invalid_spec)
+    }
+
+    /// Parses a spec in YAML, written like the TOML one: `descriptor: { path: device.bin }`
+    /// rather than with `!path` tags
+    #[cfg(feature = "yaml")]
+    pub fn parse_yaml(text: &str) -> io::Result<DeviceSpec> {
+        serde_yaml::with::singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_str(
+            text,
+        ))
+        .map_err(invalid_spec)
+    }
+
+    /// Loads a spec in YAML when its file name ends in `.yaml` or `.yml`, in TOML otherwise
+    pub fn load(path: &Path) -> io::Result<DeviceSpec> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            #[cfg(feature = "yaml")]
+            Some("yaml") | Some("yml") => DeviceSpec::load_with(path, DeviceSpec::parse_yaml),
+            #[cfg(not(feature = "yaml"))]
+            Some("yaml") | Some("yml") => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "YAML specs need the yaml feature",
+            )),
+            _ => DeviceSpec::load_with(path, DeviceSpec::parse),
+        }
+    }
+
+    /// Loads a spec with any parser, descriptor paths being relative to the spec file
+    pub fn load_with<F: FnOnce(&str) -> io::Result<DeviceSpec>>(
+        path: &Path,
+        parse: F,
+    ) -> io::Result<DeviceSpec> {
+        let mut spec = parse(&fs::read_to_string(path)?)?;
+        spec.base = path.parent().map(Path::to_path_buf);
+        Ok(spec)
+    }
+
+    /// The report descriptor, read from its file or built from its items as needed
+    pub fn descriptor(&self) -> io::Result<Vec<u8>> {
+        match &self.descriptor {
+            DescriptorSource::Hex(data) => Ok(data.clone()),
+            DescriptorSource::Path(path) => match &self.base {
+                Some(base) => fs::read(base.join(path)),
+                None => fs::read(path),
+            },
+            DescriptorSource::Items(items) => {
+                let mut rd = ReportDescriptorBuilder::new();
+                for item in items {
+                    item.describe(&mut rd);
+                }
+                Ok(rd.build())
+            }
+        }
+    }
+
+    pub fn create_params(&self) -> io::Result<CreateParams> {
+        Ok(CreateParams {
+            name: self.name.clone(),
+            phys: self.phys.clone(),
+            uniq: self.uniq.clone(),
+            bus: self.bus,
+            vendor: self.vendor,
+            product: self.product,
+            version: self.version,
+            country: self.country,
+            rd_data: self.descriptor()?,
+        })
+    }
+
+    /// Creates the device through /dev/uhid
+    pub fn create(&self) -> io::Result<SpecDevice<File>> {
+        Ok(SpecDevice {
+            device: UHIDDevice::create(self.create_params()?)?,
+            reports: self.reports.clone(),
+        })
+    }
+
+    /// Creates the device through an already opened /dev/uhid handle
+    pub fn create_with_handle<T: Read + Write>(&self, handle: T) -> io::Result<SpecDevice<T>> {
+        Ok(SpecDevice {
+            device: UHIDDevice::create_with_handle(self.create_params()?, handle)?,
+            reports: self.reports.clone(),
+        })
+    }
+}
+
+/// A device created from a `DeviceSpec`, answering GET_REPORT and SET_REPORT requests from
+/// the reports of the spec. Requests for other reports fail with EIO.
+pub struct SpecDevice<T: Read + Write = File> {
+    device: UHIDDevice<T>,
+    reports: Vec<SpecReport>,
+}
+
+impl<T: Read + Write> SpecDevice<T> {
+    pub fn device(&self) -> &UHIDDevice<T> {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut UHIDDevice<T> {
+        &mut self.device
+    }
+
+    /// Current value of a report, as last set by the host or the spec
+    pub fn report(&self, report_type: ReportType, id: u8) -> Option<&[u8]> {
+        self.reports
+            .iter()
+            .find(|report| report.report_type == report_type && report.id == id)
+            .map(|report| &report.data[..])
+    }
+
+    /// Reads the next event, after answering it when it is a request for a report
+    pub fn read(&mut self) -> Result<OutputEvent, StreamError> {
+        let event = self.device.read()?;
+        self.handle(&event).map_err(StreamError::Io)?;
+        Ok(event)
+    }
+
+    fn handle(&mut self, event: &OutputEvent) -> io::Result<()> {
+        match event {
+            OutputEvent::GetReport {
+                id,
+                report_number,
+                report_type,
+            } => {
+                let reply = self
+                    .report(*report_type, *report_number)
+                    .map(|data| data.to_vec());
+                match reply {
+                    Some(data) => self.device.write_get_report_reply(*id, 0, data)?,
+                    None => {
+                        self.device
+                            .write_get_report_reply(*id, libc::EIO as u16, Vec::new())?
+                    }
+                };
+            }
+            OutputEvent::SetReport {
+                id,
+                report_number,
+                report_type,
+                data,
+            } => {
+                let report = self.reports.iter_mut().find(|report| {
+                    report.report_type == *report_type && report.id == *report_number
+                });
+                let err = match report {
+                    Some(report) if report.fixed => libc::EPERM,
+                    Some(report) => {
+                        report.data = data.clone();
+                        0
+                    }
+                    None => libc::EIO,
+                };
+                self.device.write_set_report_reply(*id, err as u16)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{InputEvent, UHID_EVENT_SIZE};
+    use std::convert::TryFrom;
+    use std::os::unix::net::UnixStream;
+
+    const SPEC: &str = r#"
+name = "test-device"
+bus = "usb"
+vendor = 0x15d9
+product = 0x0a37
+descriptor.items = [
+    { usage_page = 0xff00 },
+    { usage = 1 },
+    { collection = "Application" },
+    { report_id = 1 },
+    { report_size = 8 },
+    { report_count = 1 },
+    { feature = ["Variable"] },
+    "end_collection",
+]
+
+[[reports]]
+id = 1
+data = "01 2a"
+"#;
+
+    fn reply(kernel: &mut UnixStream) -> [u8; UHID_EVENT_SIZE] {
+        let mut event = [0; UHID_EVENT_SIZE];
+        kernel.read_exact(&mut event).unwrap();
+        event
+    }
+
+    #[test]
+    fn creates_device_answering_reports() {
+        let spec = DeviceSpec::parse(SPEC).unwrap();
+        let params = spec.create_params().unwrap();
+        assert_eq!(params.bus, Bus::USB);
+        assert_eq!(
+            params.rd_data,
+            vec![
+                0x06, 0x00, 0xff, 0x09, 0x01, 0xa1, 0x01, 0x85, 0x01, 0x75, 0x08, 0x95, 0x01, 0xb1,
+                0x02, 0xc0
+            ]
+        );
+
+        let (handle, mut kernel) = UnixStream::pair().unwrap();
+        let mut device = spec.create_with_handle(handle).unwrap();
+        let create = reply(&mut kernel);
+        assert!(matches!(
+            InputEvent::try_from(&create),
+            Ok(InputEvent::Create(ref created)) if *created == params
+        ));
+
+        /* UHID_SET_REPORT then UHID_GET_REPORT of feature report 1 */
+        let mut set_report = [0; UHID_EVENT_SIZE];
+        set_report[0] = 13;
+        set_report[4] = 7;
+        set_report[8] = 1;
+        set_report[10..12].copy_from_slice(&2u16.to_le_bytes());
+        set_report[12..14].copy_from_slice(&[0x01, 0x63]);
+        kernel.write_all(&set_report).unwrap();
+        let mut get_report = [0; UHID_EVENT_SIZE];
+        get_report[0] = 9;
+        get_report[4] = 8;
+        get_report[8] = 1;
+        kernel.write_all(&get_report).unwrap();
+
+        device.read().ok().unwrap();
+        assert!(matches!(
+            InputEvent::try_from(&reply(&mut kernel)),
+            Ok(InputEvent::SetReportReply { id: 7, err: 0 })
+        ));
+        device.read().ok().unwrap();
+        assert!(matches!(
+            InputEvent::try_from(&reply(&mut kernel)),
+            Ok(InputEvent::GetReportReply { id: 8, err: 0, ref data }) if data[..] == [0x01, 0x63]
+        ));
+        assert_eq!(
+            device.report(ReportType::Feature, 1),
+            Some(&[0x01, 0x63][..])
+        );
+    }
+
+    #[test]
+    fn loads_specs_by_extension() {
+        let root = std::env::temp_dir().join(format!("uhid-virt-spec-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("device.bin"), [0x05, 0x01, 0x09, 0x02]).unwrap();
+        fs::write(
+            root.join("device.toml"),
+            "name = \"test-device\"\nbus = \"usb\"\nvendor = 0x15d9\nproduct = 0x0a37\n\
+             descriptor.path = \"device.bin\"\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("device.yaml"),
+            "name: test-device\nbus: usb\nvendor: 0x15d9\nproduct: 0x0a37\n\
+             descriptor:\n  path: device.bin\n",
+        )
+        .unwrap();
+
+        let toml =
+            DeviceSpec::load(&root.join("device.toml")).and_then(|spec| spec.create_params());
+        let yaml =
+            DeviceSpec::load(&root.join("device.yaml")).and_then(|spec| spec.create_params());
+        fs::remove_dir_all(&root).unwrap();
+        let toml = toml.unwrap();
+        assert_eq!(toml.rd_data, vec![0x05, 0x01, 0x09, 0x02]);
+        if cfg!(feature = "yaml") {
+            assert_eq!(yaml.unwrap(), toml);
+        } else {
+            assert_eq!(yaml.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
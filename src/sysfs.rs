@@ -0,0 +1,182 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::uhid_device::CreateParams;
+
+/// Longest name, phys and uniq strings kept by the kernel, without the terminating nul
+const MAX_NAME: usize = 127;
+const MAX_PHYS: usize = 63;
+const MAX_UNIQ: usize = 63;
+
+/// The nodes the kernel created for a HID device
+#[derive(Debug, Clone, PartialEq)]
+pub struct KernelNodes {
+    /// Name of the device on the HID bus, such as "0003:046D:C52B.0007"
+    pub hid_id: String,
+    /// The device directory under `/sys/bus/hid/devices`
+    pub sysfs_path: PathBuf,
+    /// `/dev/hidrawN` nodes, usually one unless the driver opted out of hidraw
+    pub hidraw: Vec<PathBuf>,
+    /// `/dev/input/eventN` nodes of all input devices set up by the driver
+    pub input_events: Vec<PathBuf>,
+    /// Name of the bound driver, such as "hid-generic"
+    pub driver: Option<String>,
+}
+
+fn truncated(value: &str, max: usize) -> &[u8] {
+    &value.as_bytes()[..value.len().min(max)]
+}
+
+/// Whether the `uevent` file of a HID device describes a device created with `params`
+fn uevent_matches(uevent: &str, params: &CreateParams) -> bool {
+    let mut id = None;
+    let (mut name, mut phys, mut uniq) = ("", "", "");
+    for line in uevent.lines() {
+        match line.split_once('=') {
+            Some(("HID_ID", value)) => id = Some(value),
+            Some(("HID_NAME", value)) => name = value,
+            Some(("HID_PHYS", value)) => phys = value,
+            Some(("HID_UNIQ", value)) => uniq = value,
+            _ => {}
+        }
+    }
+    let expected_id = format!(
+        "{:04X}:{:08X}:{:08X}",
+        params.bus as u32, params.vendor, params.product
+    );
+    id.is_some_and(|id| id.eq_ignore_ascii_case(&expected_id))
+        && name.as_bytes() == truncated(&params.name, MAX_NAME)
+        && phys.as_bytes() == truncated(&params.phys, MAX_PHYS)
+        && uniq.as_bytes() == truncated(&params.uniq, MAX_UNIQ)
+}
+
+/// Names of the entries of a directory, sorted, or none if it does not exist
+fn entries(path: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    match fs::read_dir(path) {
+        Ok(dir) => {
+            for entry in dir {
+                names.push(entry?.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error),
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Looks for the HID device created with `params` under a sysfs mount. When several devices
+/// match, the most recently created one wins.
+pub(crate) fn find_nodes(sysfs: &Path, params: &CreateParams) -> io::Result<Option<KernelNodes>> {
+    let devices = sysfs.join("bus/hid/devices");
+    let prefix = format!(
+        "{:04X}:{:04X}:{:04X}.",
+        params.bus as u32, params.vendor, params.product
+    );
+    let mut found = None;
+    for hid_id in entries(&devices)? {
+        if !hid_id.to_uppercase().starts_with(&prefix) {
+            continue;
+        }
+        let path = devices.join(&hid_id);
+        let uevent = match fs::read_to_string(path.join("uevent")) {
+            Ok(uevent) => uevent,
+            /* The device went away while looking */
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(error),
+        };
+        if uevent_matches(&uevent, params) {
+            found = Some((hid_id, path));
+        }
+    }
+    let (hid_id, sysfs_path) = match found {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    let hidraw = entries(&sysfs_path.join("hidraw"))?
+        .into_iter()
+        .map(|node| Path::new("/dev").join(node))
+        .collect();
+    let mut input_events = Vec::new();
+    for input in entries(&sysfs_path.join("input"))? {
+        for node in entries(&sysfs_path.join("input").join(input))? {
+            if node.starts_with("event") {
+                input_events.push(Path::new("/dev/input").join(node));
+            }
+        }
+    }
+    let driver = fs::read_link(sysfs_path.join("driver"))
+        .ok()
+        .and_then(|target| {
+            target
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        });
+    Ok(Some(KernelNodes {
+        hid_id,
+        sysfs_path,
+        hidraw,
+        input_events,
+        driver,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Bus;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn finds_nodes_in_fixture_tree() {
+        let root = std::env::temp_dir().join(format!("uhid-virt-sysfs-{}", std::process::id()));
+        let devices = root.join("bus/hid/devices");
+        let uevent = |uniq: &str| {
+            format!(
+                "DRIVER=hid-generic\nHID_ID=0003:000015D9:00000A37\nHID_NAME=test-device\n\
+                 HID_PHYS=\nHID_UNIQ={}\nMODALIAS=hid:b0003g0001v000015D9p00000A37\n",
+                uniq
+            )
+        };
+        for (hid_id, uniq) in [
+            ("0003:15D9:0A37.0001", "other"),
+            ("0003:15D9:0A37.0002", "ours"),
+        ] {
+            fs::create_dir_all(devices.join(hid_id)).unwrap();
+            fs::write(devices.join(hid_id).join("uevent"), uevent(uniq)).unwrap();
+        }
+        let ours = devices.join("0003:15D9:0A37.0002");
+        fs::create_dir_all(ours.join("hidraw/hidraw3")).unwrap();
+        fs::create_dir_all(ours.join("input/input12/event7")).unwrap();
+        fs::create_dir_all(ours.join("input/input12/mouse1")).unwrap();
+        fs::create_dir_all(root.join("bus/hid/drivers/hid-generic")).unwrap();
+        symlink("../../../bus/hid/drivers/hid-generic", ours.join("driver")).unwrap();
+
+        let params = CreateParams {
+            name: "test-device".to_string(),
+            phys: "".to_string(),
+            uniq: "ours".to_string(),
+            bus: Bus::USB,
+            vendor: 0x15d9,
+            product: 0x0a37,
+            version: 0,
+            country: 0,
+            rd_data: Vec::new(),
+        };
+        let nodes = find_nodes(&root, &params).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            nodes,
+            Some(KernelNodes {
+                hid_id: "0003:15D9:0A37.0002".to_string(),
+                sysfs_path: ours,
+                hidraw: vec![PathBuf::from("/dev/hidraw3")],
+                input_events: vec![PathBuf::from("/dev/input/event7")],
+                driver: Some("hid-generic".to_string()),
+            })
+        );
+    }
+}
@@ -1,44 +1,135 @@
+use std::collections::VecDeque;
 use std::convert::TryFrom;
 use std::fs::{File, OpenOptions};
 use std::io::{self, prelude::*};
 use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::codec::*;
+use crate::sysfs::{self, KernelNodes};
+
+/// How long `kernel_nodes` waits for the driver to set up hidraw after the Start event
+const NODES_TIMEOUT: Duration = Duration::from_secs(2);
+const NODES_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 pub struct UHIDDevice<T: Read + Write> {
     handle: T,
+    params: CreateParams,
+    started: bool,
+    opened: bool,
+    /// Events read while waiting, handed out by `read` first
+    pending: VecDeque<OutputEvent>,
 }
 
 /// Contains information about your HID device, sent when UHIDDevice is created
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CreateParams {
     pub name: String,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub phys: String,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub uniq: String,
     pub bus: Bus,
     pub vendor: u32,
     pub product: u32,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub version: u32,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub country: u32,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hex"))]
     pub rd_data: Vec<u8>,
 }
 
 /// Character misc-device handle for a specific HID device
 impl<T: Read + Write> UHIDDevice<T> {
+    /// Creates the device through an already opened /dev/uhid handle, such as an `EventLog`
+    /// wrapping it
+    pub fn create_with_handle(params: CreateParams, mut handle: T) -> io::Result<UHIDDevice<T>> {
+        let event: [u8; UHID_EVENT_SIZE] = InputEvent::Create(params.clone()).into();
+        handle.write_all(&event)?;
+        Ok(UHIDDevice {
+            handle,
+            params,
+            started: false,
+            opened: false,
+            pending: VecDeque::new(),
+        })
+    }
+
     /// The data parameter should contain a data-payload. This is the raw data that you read from your device. The kernel will parse the HID reports.
     pub fn write(&mut self, data: &[u8]) -> io::Result<usize> {
         let event: [u8; UHID_EVENT_SIZE] = InputEvent::Input { data }.into();
         self.handle.write(&event)
     }
 
-    /// Reads a queued output event. No reaction is required to an output event, but you should handle them according to your needs.
-    pub fn read(&mut self) -> Result<OutputEvent, StreamError> {
+    /// Reads the next event from the handle, keeping track of the device state
+    fn read_event(&mut self) -> Result<OutputEvent, StreamError> {
         let mut event = [0u8; UHID_EVENT_SIZE];
         self.handle
             .read_exact(&mut event)
             .map_err(StreamError::Io)?;
-        OutputEvent::try_from(event)
+        let event = OutputEvent::try_from(event)?;
+        match event {
+            OutputEvent::Start { .. } => self.started = true,
+            OutputEvent::Stop => {
+                self.started = false;
+                self.opened = false;
+            }
+            OutputEvent::Open => self.opened = true,
+            OutputEvent::Close => self.opened = false,
+            _ => {}
+        }
+        Ok(event)
+    }
+
+    /// Reads an event into the pending queue, giving up on unknown event types
+    fn buffer_event(&mut self) -> io::Result<()> {
+        match self.read_event() {
+            Ok(event) => self.pending.push_back(event),
+            Err(StreamError::UnknownEventType(_)) => {}
+            Err(StreamError::Io(error)) => return Err(error),
+        }
+        Ok(())
+    }
+
+    /// Reads a queued output event. No reaction is required to an output event, but you should handle them according to your needs.
+    /// Events read by `wait_started`, `wait_opened` or `kernel_nodes` come first.
+    pub fn read(&mut self) -> Result<OutputEvent, StreamError> {
+        match self.pending.pop_front() {
+            Some(event) => Ok(event),
+            None => self.read_event(),
+        }
+    }
+
+    /// Whether the kernel started the device, so that input reports are no longer dropped
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    /// Whether some process has the device open, such as an evdev reader
+    pub fn is_opened(&self) -> bool {
+        self.opened
+    }
+
+    /// Answers an `OutputEvent::GetReport` with the same id. An `err` of 0 means success, anything else is an errno value and `data` is ignored by the kernel.
+    pub fn write_get_report_reply(
+        &mut self,
+        id: u32,
+        err: u16,
+        data: Vec<u8>,
+    ) -> io::Result<usize> {
+        let event: [u8; UHID_EVENT_SIZE] = InputEvent::GetReportReply { id, err, data }.into();
+        self.handle.write(&event)
+    }
+
+    /// Answers an `OutputEvent::SetReport` with the same id. An `err` of 0 means success, anything else is an errno value.
+    pub fn write_set_report_reply(&mut self, id: u32, err: u16) -> io::Result<usize> {
+        let event: [u8; UHID_EVENT_SIZE] = InputEvent::SetReportReply { id, err }.into();
+        self.handle.write(&event)
     }
 
     /// This destroys the internal HID device. No further I/O will be accepted. There may still be pending output events that you can receive but no further input events can be sent to the kernel.
@@ -48,6 +139,87 @@ impl<T: Read + Write> UHIDDevice<T> {
     }
 }
 
+/// Lets the device be polled along with other file descriptors
+impl<T: Read + Write + AsRawFd> AsRawFd for UHIDDevice<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.handle.as_raw_fd()
+    }
+}
+
+impl<T: Read + Write + AsRawFd> UHIDDevice<T> {
+    /// Reads output events until `ready` holds, keeping them for `read`
+    fn wait_for(&mut self, timeout: Duration, ready: fn(&Self) -> bool) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        while !ready(self) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let mut fd = libc::pollfd {
+                fd: self.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            /* Rounded up, so that a sub-millisecond remainder still waits */
+            let millis = remaining.as_micros().div_ceil(1000);
+            match unsafe { libc::poll(&mut fd, 1, millis.min(i32::MAX as u128) as i32) } {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "timed out waiting for the device",
+                    ))
+                }
+                result if result < 0 => {
+                    let error = io::Error::last_os_error();
+                    if error.kind() != io::ErrorKind::Interrupted {
+                        return Err(error);
+                    }
+                }
+                _ => self.buffer_event()?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the kernel to start the device. Input reports written before are dropped.
+    /// Events read meanwhile are kept for `read`.
+    pub fn wait_started(&mut self, timeout: Duration) -> io::Result<()> {
+        self.wait_for(timeout, UHIDDevice::is_started)
+    }
+
+    /// Waits for some process to open the device, which is when drivers such as hid-input
+    /// start passing input reports on. Events read meanwhile are kept for `read`.
+    pub fn wait_opened(&mut self, timeout: Duration) -> io::Result<()> {
+        self.wait_for(timeout, UHIDDevice::is_opened)
+    }
+
+    /// Finds the hidraw and input event nodes, sysfs directory and driver of this device.
+    /// Output events are read until the kernel starts the device, and kept for `read`. Fails
+    /// with `TimedOut` if it does not within two seconds.
+    pub fn kernel_nodes(&mut self) -> io::Result<KernelNodes> {
+        self.kernel_nodes_in(Path::new("/sys"))
+    }
+
+    /// Same as `kernel_nodes`, with sysfs mounted at `sysfs`
+    pub fn kernel_nodes_in(&mut self, sysfs: &Path) -> io::Result<KernelNodes> {
+        self.wait_for(NODES_TIMEOUT, UHIDDevice::is_started)?;
+        /* hidraw is the last node set up when the driver connects the device */
+        let deadline = Instant::now() + NODES_TIMEOUT;
+        loop {
+            let nodes = sysfs::find_nodes(sysfs, &self.params)?;
+            match nodes {
+                Some(nodes) if !nodes.hidraw.is_empty() || Instant::now() >= deadline => {
+                    return Ok(nodes)
+                }
+                None if Instant::now() >= deadline => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no HID device named {:?} in sysfs", self.params.name),
+                    ))
+                }
+                _ => thread::sleep(NODES_POLL_INTERVAL),
+            }
+        }
+    }
+}
+
 impl UHIDDevice<File> {
     /// Opens the character misc-device at /dev/uhid
     pub fn create(params: CreateParams) -> io::Result<UHIDDevice<File>> {
@@ -60,9 +232,48 @@ impl UHIDDevice<File> {
         if cfg!(unix) {
             options.custom_flags(libc::O_RDWR | libc::O_CLOEXEC);
         }
-        let mut handle = options.open(path)?;
-        let event: [u8; UHID_EVENT_SIZE] = InputEvent::Create(params).into();
-        handle.write_all(&event)?;
-        Ok(UHIDDevice { handle })
+        UHIDDevice::create_with_handle(params, options.open(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    fn event(event_type: u8) -> [u8; UHID_EVENT_SIZE] {
+        let mut event = [0; UHID_EVENT_SIZE];
+        event[0] = event_type;
+        event
+    }
+
+    #[test]
+    fn buffers_events_while_waiting() {
+        let (handle, mut kernel) = UnixStream::pair().unwrap();
+        let mut device = UHIDDevice {
+            handle,
+            params: CreateParams {
+                name: "test-device".to_string(),
+                phys: "".to_string(),
+                uniq: "".to_string(),
+                bus: Bus::USB,
+                vendor: 0x15d9,
+                product: 0x0a37,
+                version: 0,
+                country: 0,
+                rd_data: Vec::new(),
+            },
+            started: false,
+            opened: false,
+            pending: VecDeque::new(),
+        };
+        kernel.write_all(&event(5)).unwrap(); /* UHID_CLOSE */
+        kernel.write_all(&event(2)).unwrap(); /* UHID_START */
+
+        device.wait_started(Duration::from_secs(1)).unwrap();
+        let error = device.wait_opened(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(matches!(device.read(), Ok(OutputEvent::Close)));
+        assert!(matches!(device.read(), Ok(OutputEvent::Start { .. })));
     }
 }